@@ -0,0 +1,149 @@
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Component, Path};
+
+use flate2::read::GzDecoder;
+use tar::EntryType;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ArchiveFormat {
+    Zip,
+    Tar,
+    TarGz
+}
+
+impl ArchiveFormat {
+    pub fn from_path(path: &Path) -> Option<ArchiveFormat> {
+        let file_name = path.file_name()?.to_str()?.to_lowercase();
+
+        if file_name.ends_with(".zip") {
+            Some(ArchiveFormat::Zip)
+        } else if file_name.ends_with(".tar.gz") || file_name.ends_with(".tgz") {
+            Some(ArchiveFormat::TarGz)
+        } else if file_name.ends_with(".tar") {
+            Some(ArchiveFormat::Tar)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ArchiveEntry {
+    pub path: String,
+    pub is_directory: bool
+}
+
+/// Visits the entries of the given archive in order, streaming the content of each file entry.
+pub fn visit_archive<F: FnMut(ArchiveEntry, &mut dyn Read) -> std::io::Result<()>>(path: &Path, mut on_entry: F) -> std::io::Result<()> {
+    let format = ArchiveFormat::from_path(path)
+        .ok_or_else(|| std::io::Error::other(format!("Unsupported archive format: {}", path.display())))?;
+
+    match format {
+        ArchiveFormat::Zip => {
+            let mut archive = zip::ZipArchive::new(BufReader::new(File::open(path)?)).map_err(std::io::Error::other)?;
+
+            for index in 0..archive.len() {
+                let mut file = archive.by_index(index).map_err(std::io::Error::other)?;
+                let entry = ArchiveEntry {
+                    path: normalize_entry_path(file.name())?,
+                    is_directory: file.is_dir()
+                };
+
+                on_entry(entry, &mut file)?;
+            }
+        }
+        ArchiveFormat::Tar => {
+            visit_tar(BufReader::new(File::open(path)?), on_entry)?;
+        }
+        ArchiveFormat::TarGz => {
+            visit_tar(GzDecoder::new(BufReader::new(File::open(path)?)), on_entry)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn visit_tar<R: Read, F: FnMut(ArchiveEntry, &mut dyn Read) -> std::io::Result<()>>(reader: R, mut on_entry: F) -> std::io::Result<()> {
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let is_directory = match entry.header().entry_type() {
+            EntryType::Regular | EntryType::Continuous => false,
+            EntryType::Directory => true,
+            // Links, devices and global headers are not supported and skipped
+            _ => continue
+        };
+
+        let path = normalize_entry_path(&entry.path()?.to_string_lossy())?;
+        on_entry(ArchiveEntry { path, is_directory }, &mut entry)?;
+    }
+
+    Ok(())
+}
+
+/// Normalizes the path of an archive entry, rejecting paths that would escape the archive root.
+fn normalize_entry_path(path: &str) -> std::io::Result<String> {
+    let mut parts = Vec::new();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(part) => {
+                parts.push(part.to_str().unwrap().to_owned());
+            }
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                return Err(std::io::Error::other(format!("Invalid path in archive: {}", path)));
+            }
+        }
+    }
+
+    Ok(parts.join("/"))
+}
+
+#[test]
+fn test_visit_zip() {
+    let mut entries = Vec::new();
+    let result = visit_archive(
+        Path::new("testdata/archives/rawdata2.zip"),
+        |entry, reader| {
+            let mut content = String::new();
+            reader.read_to_string(&mut content)?;
+            entries.push((entry, content.len()));
+            Ok(())
+        }
+    );
+    assert!(result.is_ok());
+
+    assert_eq!(6, entries.len());
+    assert_eq!(ArchiveEntry { path: "dir1".to_owned(), is_directory: true }, entries[0].0);
+    assert_eq!(ArchiveEntry { path: "dir1/file1.txt".to_owned(), is_directory: false }, entries[1].0);
+    assert_eq!(std::fs::metadata("testdata/rawdata2/dir1/file1.txt").unwrap().len() as usize, entries[1].1);
+}
+
+#[test]
+fn test_visit_tar_gz() {
+    let mut entries = Vec::new();
+    let result = visit_archive(
+        Path::new("testdata/archives/rawdata2.tar.gz"),
+        |entry, reader| {
+            let mut content = String::new();
+            reader.read_to_string(&mut content)?;
+            entries.push((entry, content));
+            Ok(())
+        }
+    );
+    assert!(result.is_ok());
+
+    assert_eq!(6, entries.len());
+    assert_eq!(ArchiveEntry { path: "dir1".to_owned(), is_directory: true }, entries[0].0);
+    assert_eq!(ArchiveEntry { path: "file2.txt".to_owned(), is_directory: false }, entries[5].0);
+    assert_eq!(std::fs::read_to_string("testdata/rawdata2/file2.txt").unwrap(), entries[5].1);
+}
+
+#[test]
+fn test_normalize_entry_path() {
+    assert_eq!("dir1/file1.txt", normalize_entry_path("./dir1/file1.txt").unwrap());
+    assert_eq!("dir1", normalize_entry_path("dir1/").unwrap());
+    assert!(normalize_entry_path("../file1.txt").is_err());
+    assert!(normalize_entry_path("/etc/passwd").is_err());
+}
@@ -1,5 +1,5 @@
 use std::fs::File;
-use std::io::{BufReader, Read};
+use std::io::{BufReader, Read, Write};
 use std::path::Path;
 
 use sha2::{Digest, Sha256};
@@ -42,6 +42,26 @@ pub fn compute_content_hash(path: &Path) -> std::io::Result<String> {
     Ok(hasher.finalize())
 }
 
+/// Copies the content of the reader to the writer, computing the content hash at the same time.
+/// Returns the content hash and the number of bytes copied.
+pub fn copy_with_content_hash<W: Write>(reader: &mut dyn Read, writer: &mut W) -> std::io::Result<(String, u64)> {
+    let mut buffer = [0; 4096];
+    let mut hasher = ContentHash::new();
+    let mut total_count = 0;
+    loop {
+        let count = reader.read(&mut buffer)?;
+        if count == 0 {
+            break;
+        }
+
+        hasher.add(&buffer[..count]);
+        writer.write_all(&buffer[..count])?;
+        total_count += count as u64;
+    }
+
+    Ok((hasher.finalize(), total_count))
+}
+
 pub async fn compute_content_hash_async(path: &Path) -> std::io::Result<String> {
     let mut file = tokio::fs::File::open(path).await?;
 
@@ -3,6 +3,7 @@ use std::fs::File;
 use std::io::{BufReader, BufWriter};
 use std::ops::{Add, AddAssign, Deref, DerefMut};
 use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 use flate2::Compression;
 use flate2::read::GzDecoder;
@@ -158,6 +159,37 @@ impl Display for DataSize {
     }
 }
 
+impl FromStr for DataSize {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let text = text.trim();
+        let unit_start = text.find(|char: char| !char.is_ascii_digit() && char != '.').unwrap_or(text.len());
+        let (value, unit) = text.split_at(unit_start);
+
+        let value = f64::from_str(value).map_err(|_| format!("Invalid data size: {}", text))?;
+        let multiplier = match unit.trim().to_uppercase().as_str() {
+            "" | "B" => 1.0,
+            "KB" | "K" => 1024.0,
+            "MB" | "M" => 1024.0 * 1024.0,
+            "GB" | "G" => 1024.0 * 1024.0 * 1024.0,
+            _ => { return Err(format!("Invalid data size unit: {}", unit.trim())); }
+        };
+
+        Ok(DataSize((value * multiplier) as usize))
+    }
+}
+
+#[test]
+fn test_parse_data_size() {
+    assert_eq!(Ok(DataSize(4096)), DataSize::from_str("4096"));
+    assert_eq!(Ok(DataSize(2048)), DataSize::from_str("2KB"));
+    assert_eq!(Ok(DataSize(1536 * 1024)), DataSize::from_str("1.5 MB"));
+    assert_eq!(Ok(DataSize(1024 * 1024 * 1024)), DataSize::from_str("1g"));
+    assert!(DataSize::from_str("10 XB").is_err());
+    assert!(DataSize::from_str("MB").is_err());
+}
+
 impl Add for DataSize {
     type Output = DataSize;
 
@@ -2,7 +2,7 @@ use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
 use std::path::Path;
 
-use chrono::{DateTime, Local};
+use chrono::{DateTime, FixedOffset, Local};
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 
@@ -38,7 +38,10 @@ pub enum LayerOperation {
         original_source_path: String,
         content_hash: String,
         link_type: LinkType,
-        writable: bool
+        writable: bool,
+        /// Rendered into a real file when unpacked. Only serialized when set, so that older versions can still read other layers.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        template: bool
     },
     CompressedFile {
         path: String,
@@ -47,6 +50,8 @@ pub enum LayerOperation {
         content_hash: String,
         link_type: LinkType,
         writable: bool,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        template: bool,
         compressed_content_hash: String
     },
     Label {
@@ -106,7 +111,7 @@ pub struct Layer {
     pub hash: ImageId,
     pub operations: Vec<LayerOperation>,
     pub storage_size: DataSize,
-    pub created: DateTime<Local>,
+    pub created: DateTime<FixedOffset>,
     #[serde(skip)]
     file_operation_mapping: HashMap<usize, usize>
 }
@@ -121,7 +126,7 @@ impl Layer {
             hash,
             operations,
             storage_size,
-            created: Local::now(),
+            created: Local::now().fixed_offset(),
             file_operation_mapping: HashMap::new()
         }
     }
@@ -337,5 +342,9 @@ impl Image {
 pub struct ImageMetadata {
     pub image: Image,
     pub created: DateTime<Local>,
+    #[serde(default)]
+    pub last_used: Option<DateTime<Local>>,
+    #[serde(default)]
+    pub pinned: bool,
     pub size: DataSize
 }
\ No newline at end of file
@@ -1,22 +1,31 @@
-use std::path::{Path};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
+use crate::helpers::{split_parts, DataSize};
 use crate::image::LinkType;
 use crate::image_parser::{ImageParserContext, ImageParseError, ImageParser};
-use crate::reference::Reference;
+use crate::reference::{ImageId, Reference};
 
 pub type ImageParseResult<T> = Result<T, ImageParseError>;
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct ImageDefinition {
     pub base_image: Option<Reference>,
-    pub layers: Vec<LayerDefinition>
+    pub layers: Vec<LayerDefinition>,
+    /// The name of the stage, only for multi-stage definitions.
+    pub name: Option<String>,
+    /// The other stages of a multi-stage definition.
+    pub stages: Vec<ImageDefinition>
 }
 
 impl ImageDefinition {
     pub fn new(base_image: Option<Reference>, layers: Vec<LayerDefinition>) -> ImageDefinition {
         ImageDefinition {
             base_image,
-            layers
+            layers,
+            name: None,
+            stages: Vec::new()
         }
     }
 
@@ -29,10 +38,170 @@ impl ImageDefinition {
         Ok(
             ImageDefinition {
                 base_image: self.base_image,
-                layers: expanded_layers
+                layers: expanded_layers,
+                name: self.name,
+                stages: self.stages
             }
         )
     }
+
+    /// Returns the definition with the given stage as the image to build.
+    pub fn with_target(mut self, target: &str) -> ImageParseResult<ImageDefinition> {
+        if self.name.as_deref() == Some(target) {
+            return Ok(self);
+        }
+
+        let index = self.stages
+            .iter()
+            .position(|stage| stage.name.as_deref() == Some(target))
+            .ok_or_else(|| ImageParseError::StageNotFound(target.to_owned()))?;
+
+        let mut target_stage = self.stages.remove(index);
+        target_stage.stages = std::mem::take(&mut self.stages);
+        target_stage.stages.push(self);
+        Ok(target_stage)
+    }
+
+    /// Returns the indices of the stages required to build the image (and the given additional stages), in the order they must be built.
+    pub fn stage_build_order(&self, additional_stages: &[&str]) -> ImageParseResult<Vec<usize>> {
+        let mut order = Vec::new();
+        let mut visiting = Vec::new();
+
+        for name in self.stage_dependencies() {
+            self.visit_stage(name, &mut visiting, &mut order)?;
+        }
+
+        for name in additional_stages {
+            if self.name.as_deref() != Some(name) {
+                self.visit_stage(name, &mut visiting, &mut order)?;
+            }
+        }
+
+        Ok(order)
+    }
+
+    fn visit_stage<'a>(&'a self, name: &'a str, visiting: &mut Vec<&'a str>, order: &mut Vec<usize>) -> ImageParseResult<()> {
+        if self.name.as_deref() == Some(name) {
+            // Only a reference from the final stage itself is a cycle, other stages can not use the final stage
+            return match visiting.last() {
+                Some(stage) => Err(ImageParseError::FinalStageReferenced { stage: stage.to_string(), final_stage: name.to_owned() }),
+                None => Err(ImageParseError::CyclicStages(name.to_owned()))
+            };
+        }
+
+        if visiting.contains(&name) {
+            return Err(ImageParseError::CyclicStages(name.to_owned()));
+        }
+
+        let index = self.stages
+            .iter()
+            .position(|stage| stage.name.as_deref() == Some(name))
+            .ok_or_else(|| ImageParseError::StageNotFound(name.to_owned()))?;
+
+        if order.contains(&index) {
+            return Ok(());
+        }
+
+        visiting.push(name);
+        for dependency in self.stages[index].stage_dependencies() {
+            self.visit_stage(dependency, visiting, order)?;
+        }
+        visiting.pop();
+
+        order.push(index);
+        Ok(())
+    }
+
+    /// Returns the names of the stages directly referred to by this definition.
+    pub fn stage_dependencies(&self) -> Vec<&str> {
+        let mut dependencies = Vec::new();
+        for reference in self.references() {
+            if let Some(name) = stage_name(reference) {
+                if !dependencies.contains(&name) {
+                    dependencies.push(name);
+                }
+            }
+        }
+
+        dependencies
+    }
+
+    /// Returns the references to images outside the definition, including the ones of the other stages.
+    pub fn external_references(&self) -> Vec<Reference> {
+        let mut references = Vec::new();
+        for definition in std::iter::once(self).chain(self.stages.iter()) {
+            for reference in definition.references() {
+                if stage_name(reference).is_none() {
+                    references.push(reference.clone());
+                }
+            }
+        }
+
+        references
+    }
+
+    fn references(&self) -> Vec<&Reference> {
+        let mut references = self.base_image.iter().collect::<Vec<_>>();
+        for layer in &self.layers {
+            for operation in &layer.operations {
+                match operation {
+                    LayerOperationDefinition::Image { reference }
+                    | LayerOperationDefinition::ImageAlias { reference }
+                    | LayerOperationDefinition::ImageFile { reference, .. } => {
+                        references.push(reference);
+                    }
+                    LayerOperationDefinition::Directory { .. } => {}
+                    LayerOperationDefinition::File { .. } => {}
+                    LayerOperationDefinition::Archive { .. } => {}
+                    LayerOperationDefinition::Label { .. } => {}
+                }
+            }
+        }
+
+        references
+    }
+
+    /// Replaces the references to stages with the ids of the built stages.
+    pub fn resolve_stages(&mut self, stage_hashes: &HashMap<String, ImageId>) -> ImageParseResult<()> {
+        let resolve = |reference: &mut Reference| -> ImageParseResult<()> {
+            if let Some(name) = stage_name(reference) {
+                let hash = stage_hashes.get(name).ok_or_else(|| ImageParseError::StageNotFound(name.to_owned()))?;
+                *reference = Reference::ImageId(hash.clone());
+            }
+
+            Ok(())
+        };
+
+        if let Some(base_image) = self.base_image.as_mut() {
+            resolve(base_image)?;
+        }
+
+        for layer in &mut self.layers {
+            for operation in &mut layer.operations {
+                match operation {
+                    LayerOperationDefinition::Image { reference }
+                    | LayerOperationDefinition::ImageAlias { reference }
+                    | LayerOperationDefinition::ImageFile { reference, .. } => {
+                        resolve(reference)?;
+                    }
+                    LayerOperationDefinition::Directory { .. } => {}
+                    LayerOperationDefinition::File { .. } => {}
+                    LayerOperationDefinition::Archive { .. } => {}
+                    LayerOperationDefinition::Label { .. } => {}
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the name of the stage if the reference refers to a stage.
+pub fn stage_name(reference: &Reference) -> Option<&str> {
+    match reference {
+        Reference::Stage(name) => Some(name),
+        _ => None
+    }
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -64,7 +233,9 @@ pub enum LayerOperationDefinition {
     Image { reference: Reference },
     ImageAlias { reference: Reference },
     Directory { path: String },
-    File { path: String, source_path: String, link_type: LinkType, writable: bool },
+    File { path: String, source_path: String, link_type: LinkType, writable: bool, template: bool },
+    ImageFile { reference: Reference, path: String, source_path: String, link_type: LinkType, writable: bool },
+    Archive { path: String, source_path: String },
     Label { key_values: Vec<(String, String)> }
 }
 
@@ -87,6 +258,92 @@ impl ImageDefinition {
     }
 
     pub fn create_from_directory(directory: &Path) -> ImageParseResult<ImageDefinition> {
+        ImageDefinition::create_from_directory_with_grouping(directory, &LayerGrouping::TopLevel)
+    }
+
+    pub fn create_from_directory_with_grouping(directory: &Path, grouping: &LayerGrouping) -> ImageParseResult<ImageDefinition> {
+        let layers = match grouping {
+            LayerGrouping::TopLevel => ImageDefinition::create_top_level_layers(directory)?,
+            LayerGrouping::Single => {
+                let (directories, files) = collect_directory_entries(directory)?;
+                let groups = vec![(format!("directory: {}", directory.display()), files.into_iter().map(|(path, _)| path).collect())];
+                create_grouped_layers(directory, directories, groups)
+            }
+            LayerGrouping::Depth(depth) => {
+                let (directories, files) = collect_directory_entries(directory)?;
+
+                let mut groups = BTreeMap::<String, Vec<String>>::new();
+                for (path, _) in files {
+                    let parts = path.split('/').collect::<Vec<_>>();
+                    let key = parts[..(parts.len() - 1).min(*depth)].join("/");
+                    groups.entry(key).or_default().push(path);
+                }
+
+                let groups = groups
+                    .into_iter()
+                    .map(|(key, paths)| (format!("directory: {}", if key.is_empty() { "." } else { &key }), paths))
+                    .collect();
+                create_grouped_layers(directory, directories, groups)
+            }
+            LayerGrouping::TargetSize(target_size) => {
+                let (directories, files) = collect_directory_entries(directory)?;
+
+                let mut groups = Vec::new();
+                let mut current_paths = Vec::new();
+                let mut current_size = 0;
+                for (path, size) in files {
+                    current_paths.push(path);
+                    current_size += size;
+
+                    if current_size >= target_size.0 as u64 {
+                        groups.push((format!("files: {} ({})", groups.len() + 1, DataSize(current_size as usize)), std::mem::take(&mut current_paths)));
+                        current_size = 0;
+                    }
+                }
+
+                if !current_paths.is_empty() {
+                    groups.push((format!("files: {} ({})", groups.len() + 1, DataSize(current_size as usize)), current_paths));
+                }
+
+                create_grouped_layers(directory, directories, groups)
+            }
+            LayerGrouping::Manifest(manifest_path) => {
+                let (directories, files) = collect_directory_entries(directory)?;
+
+                let content = std::fs::read_to_string(manifest_path)
+                    .map_err(|err| ImageParseError::Other(format!("Failed to read manifest {}: {}", manifest_path.display(), err)))?;
+                let manifest_groups = content
+                    .lines()
+                    .map(|line| line.trim())
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(|line| (line.to_owned(), split_parts(line).into_iter().map(|part| part.trim_end_matches('/').to_owned()).collect::<Vec<_>>()))
+                    .collect::<Vec<_>>();
+
+                let mut groups = manifest_groups.iter().map(|(line, _)| (format!("manifest: {}", line), Vec::new())).collect::<Vec<_>>();
+                let mut remaining = Vec::new();
+                for (path, _) in files {
+                    let group_index = manifest_groups.iter().position(|(_, patterns)| {
+                        patterns.iter().any(|pattern| &path == pattern || path.starts_with(&format!("{}/", pattern)))
+                    });
+
+                    match group_index {
+                        Some(group_index) => groups[group_index].1.push(path),
+                        None => remaining.push(path)
+                    }
+                }
+
+                if !remaining.is_empty() {
+                    groups.push(("manifest: remaining files".to_owned(), remaining));
+                }
+
+                create_grouped_layers(directory, directories, groups)
+            }
+        };
+
+        Ok(ImageDefinition::new(None, layers))
+    }
+
+    fn create_top_level_layers(directory: &Path) -> ImageParseResult<Vec<LayerDefinition>> {
         let mut read_dir = std::fs::read_dir(directory)?;
         let mut root_files = Vec::new();
         let mut directories = Vec::new();
@@ -116,6 +373,7 @@ impl ImageDefinition {
                             source_path: current_directory.to_str().unwrap().to_owned(),
                             link_type: LinkType::Hard,
                             writable: false,
+                            template: false,
                         }
                     ]
                 }
@@ -133,19 +391,15 @@ impl ImageDefinition {
                             path: file_relative.to_str().unwrap().to_owned(),
                             source_path: file.to_str().unwrap().to_owned(),
                             link_type: LinkType::Hard,
-                            writable: false
+                            writable: false,
+                            template: false
                         }
                     ]
                 }
             );
         }
 
-        Ok(
-            ImageDefinition {
-                base_image: None,
-                layers
-            }
-        )
+        Ok(layers)
     }
 }
 
@@ -160,7 +414,7 @@ fn expand_operations(build_context: &Path, operations: Vec<LayerOperationDefinit
             LayerOperationDefinition::ImageAlias { reference } => {
                 expanded_operations.push(LayerOperationDefinition::ImageAlias { reference });
             }
-            LayerOperationDefinition::File { path, source_path, link_type, writable } => {
+            LayerOperationDefinition::File { path, source_path, link_type, writable, template } => {
                 let source_path_obj = Path::new(&source_path);
                 if source_path_obj.is_absolute() {
                     return Err(ImageParseError::IsAbsolutePath(source_path.clone()));
@@ -176,7 +430,8 @@ fn expand_operations(build_context: &Path, operations: Vec<LayerOperationDefinit
                                 path: destination_path.join(source_path_obj.file_name().unwrap()).to_str().unwrap().to_owned(),
                                 source_path: source_path_obj.to_str().unwrap().to_owned(),
                                 link_type,
-                                writable
+                                writable,
+                                template
                             }
                         );
                     } else if path == "." {
@@ -185,7 +440,8 @@ fn expand_operations(build_context: &Path, operations: Vec<LayerOperationDefinit
                                 path: source_path_obj.file_name().unwrap().to_str().unwrap().to_owned(),
                                 source_path: source_path_obj.to_str().unwrap().to_owned(),
                                 link_type,
-                                writable
+                                writable,
+                                template
                             }
                         );
                     } else {
@@ -194,7 +450,8 @@ fn expand_operations(build_context: &Path, operations: Vec<LayerOperationDefinit
                                 path,
                                 source_path: source_path_obj.to_str().unwrap().to_owned(),
                                 link_type,
-                                writable
+                                writable,
+                                template
                             }
                         );
                     }
@@ -203,10 +460,24 @@ fn expand_operations(build_context: &Path, operations: Vec<LayerOperationDefinit
                         &source_path_obj,
                         destination_path,
                         link_type,
-                        writable
+                        writable,
+                        template
                     )?);
                 }
             },
+            LayerOperationDefinition::ImageFile { reference, path, source_path, link_type, writable } => {
+                // The files are only known when building, as the image might not exist yet
+                expanded_operations.push(LayerOperationDefinition::ImageFile { reference, path, source_path, link_type, writable });
+            }
+            LayerOperationDefinition::Archive { path, source_path } => {
+                if Path::new(&source_path).is_absolute() {
+                    return Err(ImageParseError::IsAbsolutePath(source_path.clone()));
+                }
+
+                // The entries are only known when building, as the archive needs to be read
+                let source_path = build_context.join(source_path).to_str().unwrap().to_owned();
+                expanded_operations.push(LayerOperationDefinition::Archive { path, source_path });
+            }
             LayerOperationDefinition::Directory { path } => {
                 expanded_operations.push(LayerOperationDefinition::Directory { path });
             },
@@ -222,7 +493,8 @@ fn expand_operations(build_context: &Path, operations: Vec<LayerOperationDefinit
 fn recursive_copy_operations(source_path: &Path,
                              base_destination_path: &Path,
                              link_type: LinkType,
-                             writable: bool) -> ImageParseResult<Vec<LayerOperationDefinition>> {
+                             writable: bool,
+                             template: bool) -> ImageParseResult<Vec<LayerOperationDefinition>> {
     let mut stack = Vec::new();
     stack.push(source_path.to_owned());
 
@@ -253,7 +525,8 @@ fn recursive_copy_operations(source_path: &Path,
                     path: relative_entry_path.to_str().unwrap().to_owned(),
                     source_path: entry_path.to_str().unwrap().to_owned(),
                     link_type,
-                    writable
+                    writable,
+                    template
                 });
             }
         }
@@ -264,6 +537,133 @@ fn recursive_copy_operations(source_path: &Path,
     Ok(results)
 }
 
+/// How the layers are grouped when creating an image from a directory.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum LayerGrouping {
+    /// One layer per top-level directory and one per root file
+    #[default]
+    TopLevel,
+    /// All files in a single layer
+    Single,
+    /// One layer per directory at the given depth, files above that depth are grouped by their directory
+    Depth(usize),
+    /// Files are packed into layers of at least the given size, in path order
+    TargetSize(DataSize),
+    /// Each line in the manifest file lists the paths of one layer, remaining files are put in a last layer
+    Manifest(PathBuf)
+}
+
+impl FromStr for LayerGrouping {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let (name, argument) = match text.split_once('=') {
+            Some((name, argument)) => (name, Some(argument)),
+            None => (text, None)
+        };
+
+        match (name, argument) {
+            ("top-level", None) => Ok(LayerGrouping::TopLevel),
+            ("single", None) => Ok(LayerGrouping::Single),
+            ("depth", Some(depth)) => {
+                let depth = usize::from_str(depth).map_err(|_| format!("Invalid depth: {}", depth))?;
+                if depth == 0 {
+                    return Err("The depth must be at least 1".to_owned());
+                }
+
+                Ok(LayerGrouping::Depth(depth))
+            }
+            ("size", Some(size)) => {
+                let size = DataSize::from_str(size)?;
+                if size.0 == 0 {
+                    return Err("The target size must be larger than 0".to_owned());
+                }
+
+                Ok(LayerGrouping::TargetSize(size))
+            }
+            ("manifest", Some(path)) => Ok(LayerGrouping::Manifest(PathBuf::from(path))),
+            _ => Err(format!("Invalid grouping '{}', expected top-level, single, depth=N, size=SIZE or manifest=FILE", text))
+        }
+    }
+}
+
+type DirectoryEntries = (Vec<String>, Vec<(String, u64)>);
+
+/// Returns all directories and files (with size) below the given directory as relative paths, sorted by path.
+fn collect_directory_entries(directory: &Path) -> ImageParseResult<DirectoryEntries> {
+    let mut directories = Vec::new();
+    let mut files = Vec::new();
+
+    let mut stack = vec![directory.to_owned()];
+    while let Some(current) = stack.pop() {
+        for entry in std::fs::read_dir(&current)? {
+            let entry_path = entry?.path();
+            let relative_path = entry_path.strip_prefix(directory)?.to_str().unwrap().to_owned();
+
+            if entry_path.is_dir() {
+                directories.push(relative_path);
+                stack.push(entry_path);
+            } else if entry_path.is_file() {
+                files.push((relative_path, std::fs::metadata(&entry_path)?.len()));
+            }
+        }
+    }
+
+    directories.sort();
+    files.sort();
+    Ok((directories, files))
+}
+
+/// Creates a layer for each group of files. The directories are created in the first layer that needs them,
+/// directories without any files are created in the last layer.
+fn create_grouped_layers(directory: &Path, directories: Vec<String>, groups: Vec<(String, Vec<String>)>) -> Vec<LayerDefinition> {
+    let mut created_directories = BTreeSet::new();
+    let mut layers = Vec::new();
+
+    for (input_line, paths) in groups.into_iter().filter(|(_, paths)| !paths.is_empty()) {
+        let mut operations = Vec::new();
+        for path in paths {
+            let mut current = Path::new(&path).parent();
+            let mut parents = Vec::new();
+            while let Some(parent) = current.filter(|parent| parent != &Path::new("")) {
+                parents.push(parent.to_str().unwrap().to_owned());
+                current = parent.parent();
+            }
+
+            for parent in parents.into_iter().rev() {
+                if created_directories.insert(parent.clone()) {
+                    operations.push(LayerOperationDefinition::Directory { path: parent });
+                }
+            }
+
+            operations.push(LayerOperationDefinition::File {
+                source_path: directory.join(&path).to_str().unwrap().to_owned(),
+                path,
+                link_type: LinkType::Hard,
+                writable: false,
+                template: false
+            });
+        }
+
+        layers.push(LayerDefinition::new(input_line, operations));
+    }
+
+    let empty_directories = directories
+        .into_iter()
+        .filter(|path| !created_directories.contains(path))
+        .map(|path| LayerOperationDefinition::Directory { path })
+        .collect::<Vec<_>>();
+
+    if !empty_directories.is_empty() {
+        match layers.last_mut() {
+            Some(layer) => layer.operations.extend(empty_directories),
+            None => layers.push(LayerDefinition::new("directories".to_owned(), empty_directories))
+        }
+    }
+
+    layers
+}
+
 #[cfg(test)]
 fn image_definition_from_file(path: &str, context: &ImageParserContext) -> ImageParseResult<ImageDefinition> {
     ImageDefinition::parse(&std::fs::read_to_string(path)?, context)
@@ -286,7 +686,7 @@ fn test_parse_copy1() {
         vec![
             LayerOperationDefinition::File {
                 path: "file1.txt".to_owned(), source_path: "testdata/rawdata/file1.txt".to_owned(),
-                link_type: LinkType::Hard, writable: false
+                link_type: LinkType::Hard, writable: false, template: false
             }
         ],
     );
@@ -304,7 +704,7 @@ fn test_parse_copy2() {
         vec![
             LayerOperationDefinition::File {
                 path: "sub/file1.txt".to_owned(),source_path: "testdata/rawdata/file1.txt".to_owned(),
-                link_type: LinkType::Hard, writable: false
+                link_type: LinkType::Hard, writable: false, template: false
             }
         ],
     );
@@ -326,13 +726,13 @@ fn test_parse_copy3() {
         vec![
             LayerOperationDefinition::Directory { path: "dir2".to_owned() },
             LayerOperationDefinition::File {
-                path: "dir2/file1.txt".to_owned(), source_path: "testdata/dir1/dir2/file1.txt".to_owned(), link_type: LinkType::Hard , writable: false
+                path: "dir2/file1.txt".to_owned(), source_path: "testdata/dir1/dir2/file1.txt".to_owned(), link_type: LinkType::Hard , writable: false, template: false
             },
             LayerOperationDefinition::File {
-                path: "dir2/file2.txt".to_owned(), source_path: "testdata/dir1/dir2/file2.txt".to_owned(), link_type: LinkType::Hard, writable: false
+                path: "dir2/file2.txt".to_owned(), source_path: "testdata/dir1/dir2/file2.txt".to_owned(), link_type: LinkType::Hard, writable: false, template: false
             },
             LayerOperationDefinition::File {
-                path: "file1.txt".to_owned(), source_path: "testdata/dir1/file1.txt".to_owned(), link_type: LinkType::Hard, writable: false
+                path: "file1.txt".to_owned(), source_path: "testdata/dir1/file1.txt".to_owned(), link_type: LinkType::Hard, writable: false, template: false
             },
         ],
     );
@@ -353,9 +753,9 @@ fn test_parse_copy4() {
         result.layers[0].operations,
         vec![
             LayerOperationDefinition::Directory { path: "test/dir2".to_owned() },
-            LayerOperationDefinition::File { path: "test/dir2/file1.txt".to_owned(), source_path: "testdata/dir1/dir2/file1.txt".to_owned(), link_type: LinkType::Hard, writable: false },
-            LayerOperationDefinition::File { path: "test/dir2/file2.txt".to_owned(), source_path: "testdata/dir1/dir2/file2.txt".to_owned(), link_type: LinkType::Hard, writable: false },
-            LayerOperationDefinition::File { path: "test/file1.txt".to_owned(), source_path: "testdata/dir1/file1.txt".to_owned(), link_type: LinkType::Hard, writable: false },
+            LayerOperationDefinition::File { path: "test/dir2/file1.txt".to_owned(), source_path: "testdata/dir1/dir2/file1.txt".to_owned(), link_type: LinkType::Hard, writable: false, template: false },
+            LayerOperationDefinition::File { path: "test/dir2/file2.txt".to_owned(), source_path: "testdata/dir1/dir2/file2.txt".to_owned(), link_type: LinkType::Hard, writable: false, template: false },
+            LayerOperationDefinition::File { path: "test/file1.txt".to_owned(), source_path: "testdata/dir1/file1.txt".to_owned(), link_type: LinkType::Hard, writable: false, template: false },
         ],
     );
 }
@@ -376,7 +776,7 @@ fn test_parse_copy5() {
         vec![
             LayerOperationDefinition::File {
                 path: "sub/file1.txt".to_owned(), source_path: "testdata/rawdata/file1.txt".to_owned(),
-                link_type: LinkType::Hard, writable: false
+                link_type: LinkType::Hard, writable: false, template: false
             }
         ]
     );
@@ -399,7 +799,7 @@ fn test_parse_copy6() {
         vec![
             LayerOperationDefinition::File {
                 path: "file1.txt".to_owned(), source_path: "testdata/rawdata/file1.txt".to_owned(),
-                link_type: LinkType::Hard, writable: false
+                link_type: LinkType::Hard, writable: false, template: false
             }
         ]
     );
@@ -417,7 +817,7 @@ fn test_parse_copy7() {
         vec![
             LayerOperationDefinition::File {
                 path: "file1.txt".to_owned(), source_path: "testdata/rawdata/file1.txt".to_owned(),
-                link_type: LinkType::Hard, writable: false
+                link_type: LinkType::Hard, writable: false, template: false
             }
         ],
     );
@@ -435,7 +835,7 @@ fn test_parse_copy8() {
         vec![
             LayerOperationDefinition::File {
                 path: "file1.txt".to_owned(), source_path: "testdata/rawdata/file1.txt".to_owned(),
-                link_type: LinkType::Soft, writable: false
+                link_type: LinkType::Soft, writable: false, template: false
             }
         ],
     );
@@ -453,7 +853,7 @@ fn test_parse_copy9() {
         vec![
             LayerOperationDefinition::File {
                 path: "file1.txt".to_owned(), source_path: "testdata/rawdata/file1.txt".to_owned(),
-                link_type: LinkType::Hard, writable: true
+                link_type: LinkType::Hard, writable: true, template: false
             }
         ],
     );
@@ -471,7 +871,64 @@ fn test_parse_copy10() {
         vec![
             LayerOperationDefinition::File {
                 path: "file 1.txt".to_owned(), source_path: "testdata/rawdata/file1.txt".to_owned(),
-                link_type: LinkType::Hard, writable: false
+                link_type: LinkType::Hard, writable: false, template: false
+            }
+        ],
+    );
+}
+
+#[test]
+fn test_parse_copy11() {
+    use std::str::FromStr;
+
+    let result = image_definition_from_file2("testdata/parsing/success/copy11.labarfile");
+    assert!(result.is_ok(), "{}", result.unwrap_err());
+    let result = result.unwrap();
+
+    assert_eq!(1, result.layers.len());
+    assert_eq!(
+        result.layers[0].operations,
+        vec![
+            LayerOperationDefinition::ImageFile {
+                reference: Reference::from_str("test:this").unwrap(),
+                path: "file1.txt".to_owned(), source_path: "sub/file1.txt".to_owned(),
+                link_type: LinkType::Hard, writable: true
+            }
+        ],
+    );
+    assert_eq!(result.external_references(), vec![Reference::from_str("test:this").unwrap()]);
+}
+
+#[test]
+fn test_parse_unpack1() {
+    let result = image_definition_from_file2("testdata/parsing/success/unpack1.labarfile");
+    assert!(result.is_ok(), "{}", result.unwrap_err());
+    let result = result.unwrap();
+
+    assert_eq!(1, result.layers.len());
+    assert_eq!(
+        result.layers[0].operations,
+        vec![
+            LayerOperationDefinition::Archive {
+                path: "vendor/".to_owned(), source_path: "testdata/archives/rawdata2.tar.gz".to_owned()
+            }
+        ],
+    );
+}
+
+#[test]
+fn test_parse_template1() {
+    let result = image_definition_from_file2("testdata/parsing/success/template1.labarfile");
+    assert!(result.is_ok(), "{}", result.unwrap_err());
+    let result = result.unwrap();
+
+    assert_eq!(2, result.layers.len());
+    assert_eq!(
+        result.layers[1].operations,
+        vec![
+            LayerOperationDefinition::File {
+                path: "config/server.conf".to_owned(), source_path: "testdata/templates/server.conf".to_owned(),
+                link_type: LinkType::Hard, writable: false, template: true
             }
         ],
     );
@@ -549,7 +1006,7 @@ fn test_parse_multi1() {
         vec![
             LayerOperationDefinition::File {
                 path: "file1.txt".to_owned(), source_path: "testdata/rawdata/file1.txt".to_owned(),
-                link_type: LinkType::Hard, writable: false
+                link_type: LinkType::Hard, writable: false, template: false
             }
         ]
     );
@@ -581,7 +1038,7 @@ fn test_parse_multi2() {
         vec![
             LayerOperationDefinition::File {
                 path: "file1.txt".to_owned(), source_path: "testdata/rawdata/file1.txt".to_owned(),
-                link_type: LinkType::Hard, writable: false
+                link_type: LinkType::Hard, writable: false, template: false
             }
         ]
     );
@@ -607,7 +1064,7 @@ fn test_parse_variables1() {
         vec![
             LayerOperationDefinition::File {
                 path: "file1.txt".to_owned(), source_path: "testdata/rawdata/file1.txt".to_owned(),
-                link_type: LinkType::Hard, writable: false
+                link_type: LinkType::Hard, writable: false, template: false
             }
         ],
     );
@@ -628,7 +1085,7 @@ fn test_parse_variables2() {
         vec![
             LayerOperationDefinition::File {
                 path: "file1.txt".to_owned(), source_path: "testdata/rawdata/file1.txt".to_owned(),
-                link_type: LinkType::Hard, writable: false
+                link_type: LinkType::Hard, writable: false, template: false
             }
         ],
     );
@@ -649,7 +1106,7 @@ fn test_parse_variables3() {
         vec![
             LayerOperationDefinition::File {
                 path: "file1.txt".to_owned(), source_path: "testdata/rawdata/file1.txt".to_owned(),
-                link_type: LinkType::Hard, writable: false
+                link_type: LinkType::Hard, writable: false, template: false
             }
         ],
     );
@@ -672,7 +1129,7 @@ fn test_parse_variables4() {
         vec![
             LayerOperationDefinition::File {
                 path: "file_1.txt".to_owned(), source_path: "testdata/rawdata/file1.txt".to_owned(),
-                link_type: LinkType::Hard, writable: false
+                link_type: LinkType::Hard, writable: false, template: false
             }
         ],
     );
@@ -709,11 +1166,11 @@ fn test_parse_sublayer1() {
         vec![
             LayerOperationDefinition::File {
                 path: "file1.txt".to_owned(), source_path: "testdata/rawdata/file1.txt".to_owned(),
-                link_type: LinkType::Hard, writable: false
+                link_type: LinkType::Hard, writable: false, template: false
             },
             LayerOperationDefinition::File {
                 path: "file2.txt".to_owned(), source_path: "testdata/rawdata/file2.txt".to_owned(),
-                link_type: LinkType::Hard, writable: false
+                link_type: LinkType::Hard, writable: false, template: false
             }
         ],
     );
@@ -731,11 +1188,11 @@ fn test_parse_sublayer2() {
         vec![
             LayerOperationDefinition::File {
                 path: "file1.txt".to_owned(), source_path: "testdata/rawdata/file1.txt".to_owned(),
-                link_type: LinkType::Hard, writable: false
+                link_type: LinkType::Hard, writable: false, template: false
             },
             LayerOperationDefinition::File {
                 path: "file2.txt".to_owned(), source_path: "testdata/rawdata/file2.txt".to_owned(),
-                link_type: LinkType::Hard, writable: false
+                link_type: LinkType::Hard, writable: false, template: false
             }
         ],
     );
@@ -762,7 +1219,7 @@ fn test_parse_label1() {
         vec![
             LayerOperationDefinition::File {
                 path: "file1.txt".to_owned(), source_path: "testdata/rawdata/file1.txt".to_owned(),
-                link_type: LinkType::Hard, writable: false
+                link_type: LinkType::Hard, writable: false, template: false
             }
         ],
     );
@@ -791,7 +1248,7 @@ fn test_parse_label2() {
         vec![
             LayerOperationDefinition::File {
                 path: "file1.txt".to_owned(), source_path: "testdata/rawdata/file1.txt".to_owned(),
-                link_type: LinkType::Hard, writable: false
+                link_type: LinkType::Hard, writable: false, template: false
             }
         ],
     );
@@ -825,7 +1282,7 @@ fn test_parse_label3() {
         vec![
             LayerOperationDefinition::File {
                 path: "file1.txt".to_owned(), source_path: "testdata/rawdata/file1.txt".to_owned(),
-                link_type: LinkType::Hard, writable: false
+                link_type: LinkType::Hard, writable: false, template: false
             }
         ],
     );
@@ -842,6 +1299,31 @@ fn test_parse_label3() {
     );
 }
 
+#[test]
+fn test_parse_stages1() {
+    use std::str::FromStr;
+
+    let result = image_definition_from_file2("testdata/parsing/success/stages1.labarfile");
+    assert!(result.is_ok(), "{}", result.unwrap_err());
+    let result = result.unwrap();
+
+    assert_eq!(result.name, Some("final".to_owned()));
+    assert_eq!(result.base_image, Some(Reference::from_str("test:this").unwrap()));
+    assert_eq!(result.layers.len(), 2);
+    assert_eq!(result.stages.len(), 2);
+    assert_eq!(result.stages[0].name, Some("files".to_owned()));
+    assert_eq!(result.stages[1].name, Some("base".to_owned()));
+    assert_eq!(result.stages[1].base_image, Some(Reference::from_str("stage:files").unwrap()));
+
+    assert_eq!(result.stage_build_order(&[]).unwrap(), vec![0, 1]);
+    assert_eq!(result.external_references(), vec![Reference::from_str("test:this").unwrap()]);
+
+    let result = result.with_target("files").unwrap();
+    assert_eq!(result.name, Some("files".to_owned()));
+    assert_eq!(result.stages.len(), 2);
+    assert_eq!(result.stage_build_order(&[]).unwrap(), Vec::<usize>::new());
+}
+
 
 #[test]
 fn test_failed_parse_mkdir1() {
@@ -872,3 +1354,123 @@ fn test_failed_parse_sublayer2() {
     let result = image_definition_from_file2("testdata/parsing/failed/sublayer2.labarfile");
     assert!(result.is_err());
 }
+
+#[test]
+fn test_failed_parse_label1() {
+    let result = image_definition_from_file2("testdata/parsing/failed/label1.labarfile");
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_failed_parse_stages1() {
+    let result = image_definition_from_file2("testdata/parsing/failed/stages1.labarfile");
+    assert!(matches!(result, Err(ImageParseError::OutsideStage)));
+}
+
+#[test]
+fn test_failed_parse_stages2() {
+    let result = image_definition_from_file2("testdata/parsing/failed/stages2.labarfile");
+    assert!(matches!(result, Err(ImageParseError::StageNotFound(_))));
+}
+
+#[test]
+fn test_failed_parse_stages3() {
+    let result = image_definition_from_file2("testdata/parsing/failed/stages3.labarfile");
+    assert!(matches!(result, Err(ImageParseError::CyclicStages(_))));
+}
+
+#[test]
+fn test_failed_parse_stages4() {
+    let result = image_definition_from_file2("testdata/parsing/failed/stages4.labarfile");
+    assert!(matches!(result, Err(ImageParseError::DuplicateStage(_))));
+}
+
+#[test]
+fn test_failed_parse_stages5() {
+    let result = image_definition_from_file2("testdata/parsing/failed/stages5.labarfile");
+    match result {
+        Err(ImageParseError::FinalStageReferenced { stage, final_stage }) => {
+            assert_eq!("first", stage);
+            assert_eq!("final", final_stage);
+        }
+        _ => panic!("Expected final stage referenced")
+    }
+}
+
+#[test]
+fn test_failed_parse_stages6() {
+    let result = image_definition_from_file2("testdata/parsing/failed/stages6.labarfile");
+    assert!(matches!(result, Err(ImageParseError::EmptyStage(name)) if name == "first"));
+}
+
+#[test]
+fn test_failed_parse_unpack1() {
+    let result = image_definition_from_file2("testdata/parsing/failed/unpack1.labarfile");
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_failed_parse_template1() {
+    let result = image_definition_from_file2("testdata/parsing/failed/template1.labarfile");
+    assert!(matches!(result, Err(ImageParseError::ExpectedArguments { expected: 2, actual: 1 })));
+}
+
+#[test]
+fn test_create_from_directory_with_grouping() {
+    let directory = Path::new("testdata/rawdata2");
+    let file = |path: &str| LayerOperationDefinition::File {
+        path: path.to_owned(),
+        source_path: directory.join(path).to_str().unwrap().to_owned(),
+        link_type: LinkType::Hard,
+        writable: false,
+        template: false
+    };
+    let directory_operation = |path: &str| LayerOperationDefinition::Directory { path: path.to_owned() };
+
+    let result = ImageDefinition::create_from_directory_with_grouping(directory, &LayerGrouping::Single).unwrap();
+    assert_eq!(1, result.layers.len());
+    assert_eq!(
+        vec![
+            directory_operation("dir1"), file("dir1/file1.txt"),
+            directory_operation("dir2"), file("dir2/file2.txt"),
+            file("file1.txt"), file("file2.txt")
+        ],
+        result.layers[0].operations
+    );
+
+    let result = ImageDefinition::create_from_directory_with_grouping(directory, &LayerGrouping::Depth(1)).unwrap();
+    assert_eq!(3, result.layers.len());
+    assert_eq!(vec![file("file1.txt"), file("file2.txt")], result.layers[0].operations);
+    assert_eq!(vec![directory_operation("dir1"), file("dir1/file1.txt")], result.layers[1].operations);
+    assert_eq!(vec![directory_operation("dir2"), file("dir2/file2.txt")], result.layers[2].operations);
+
+    let result = ImageDefinition::create_from_directory_with_grouping(directory, &LayerGrouping::TargetSize(DataSize(1000))).unwrap();
+    assert_eq!(2, result.layers.len());
+    assert_eq!(
+        vec![directory_operation("dir1"), file("dir1/file1.txt"), directory_operation("dir2"), file("dir2/file2.txt")],
+        result.layers[0].operations
+    );
+    assert_eq!(vec![file("file1.txt"), file("file2.txt")], result.layers[1].operations);
+
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+    std::fs::create_dir_all(tmp_folder.owned()).unwrap();
+    let manifest_path = tmp_folder.join("layers.txt");
+    std::fs::write(&manifest_path, "# Changes often\nfile2.txt dir2/\n").unwrap();
+
+    let result = ImageDefinition::create_from_directory_with_grouping(directory, &LayerGrouping::Manifest(manifest_path)).unwrap();
+    assert_eq!(2, result.layers.len());
+    assert_eq!(vec![directory_operation("dir2"), file("dir2/file2.txt"), file("file2.txt")], result.layers[0].operations);
+    assert_eq!(vec![directory_operation("dir1"), file("dir1/file1.txt"), file("file1.txt")], result.layers[1].operations);
+}
+
+#[test]
+fn test_parse_layer_grouping() {
+    assert_eq!(Ok(LayerGrouping::TopLevel), LayerGrouping::from_str("top-level"));
+    assert_eq!(Ok(LayerGrouping::Single), LayerGrouping::from_str("single"));
+    assert_eq!(Ok(LayerGrouping::Depth(2)), LayerGrouping::from_str("depth=2"));
+    assert_eq!(Ok(LayerGrouping::TargetSize(DataSize(64 * 1024 * 1024))), LayerGrouping::from_str("size=64MB"));
+    assert_eq!(Ok(LayerGrouping::Manifest(PathBuf::from("layers.txt"))), LayerGrouping::from_str("manifest=layers.txt"));
+    assert!(LayerGrouping::from_str("depth=0").is_err());
+    assert!(LayerGrouping::from_str("depth").is_err());
+    assert!(LayerGrouping::from_str("other").is_err());
+}
@@ -1,17 +1,25 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io::{BufWriter, Write};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::time::Instant;
+use chrono::{DateTime, FixedOffset, TimeZone, Utc};
+use itertools::Itertools;
+use serde::Serialize;
 use sha2::{Digest, Sha256};
+use tempfile::TempDir;
 
-use crate::content::compute_content_hash;
+use crate::archive::visit_archive;
+use crate::content::{compute_content_hash, copy_with_content_hash};
 use crate::helpers::DataSize;
-use crate::image_manager::details::layer::LayerManager;
+use crate::image_manager::details::layer::{link_stored_file, LayerManager};
 use crate::image_definition::{ImageDefinition, LayerDefinition, LayerOperationDefinition};
-use crate::image_manager::{ImageManagerConfig, ImageManagerError, ImageManagerResult};
-use crate::image::{Image, Layer, LayerOperation};
-use crate::image_manager::printing::PrinterRef;
+use crate::image_manager::{ImageManagerConfig, ImageManagerError, ImageManagerResult, Quota};
+use crate::image::{Image, Layer, LayerOperation, LinkType};
+use crate::image_manager::printing::{BuildEvent, PrinterRef, StepResult};
 use crate::image_manager::details::state::StateSession;
-use crate::reference::{ImageId, ImageTag};
+use crate::image_parser::{ImageParseError, RESERVED_LABEL_PREFIX};
+use crate::reference::{ImageId, ImageTag, Reference};
 
 pub struct BuildManager {
     config: ImageManagerConfig,
@@ -29,10 +37,104 @@ impl BuildManager {
     pub fn build_image(&self,
                        session: &mut StateSession,
                        layer_manager: &LayerManager,
-                       request: BuildRequest) -> ImageManagerResult<BuildResult> {
+                       mut request: BuildRequest) -> ImageManagerResult<BuildResult> {
+        let build_start_time = Instant::now();
+
+        if !request.labels.is_empty() {
+            let input_line = format!("LABEL {}", request.labels.iter().map(|(key, value)| format!("{}={}", key, value)).join(" "));
+            let label_operation = LayerOperationDefinition::Label { key_values: std::mem::take(&mut request.labels) };
+            match request.image_definition.layers.last_mut() {
+                Some(top_layer) => {
+                    top_layer.operations.push(label_operation);
+                }
+                None => {
+                    request.image_definition.layers.push(LayerDefinition::new(input_line, vec![label_operation]));
+                }
+            }
+        }
+
+        let tag_latest = request.tag_latest.unwrap_or(self.config.tag_latest);
+        let tagged_stages = request.stage_tags.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>();
+        let stage_order = request.image_definition.stage_build_order(&tagged_stages)?;
+
+        let mut built_layers = Vec::new();
+        let mut steps = Vec::new();
+        let mut stage_hashes = HashMap::new();
+        let mut stages = Vec::new();
+
+        for stage_index in stage_order {
+            let mut stage = request.image_definition.stages[stage_index].clone();
+            let stage_name = stage.name.clone().unwrap_or_default();
+            stage.resolve_stages(&stage_hashes)?;
+
+            if request.print {
+                self.printer.println(&format!("Stage {}", stage_name));
+            }
+
+            let (hash, _) = self.build_layers(session, layer_manager, &request, stage, &mut built_layers, &mut steps)?;
+            let hash = hash.ok_or_else(|| ImageParseError::EmptyStage(stage_name.clone()))?;
+
+            for (_, tag) in request.stage_tags.iter().filter(|(name, _)| name == &stage_name) {
+                self.insert_image(session, layer_manager, &Image::new(hash.clone(), tag.clone()), &[], tag_latest, request.force)?;
+            }
+
+            stage_hashes.insert(stage_name.clone(), hash.clone());
+            stages.push((stage_name, hash));
+        }
+
+        let mut image_definition = std::mem::replace(&mut request.image_definition, ImageDefinition::new(None, Vec::new()));
+        image_definition.resolve_stages(&stage_hashes)?;
+        if request.print && !stages.is_empty() {
+            self.printer.println(&format!("Stage {}", image_definition.name.as_deref().unwrap_or_default()));
+        }
+
+        let final_stage_name = image_definition.name.clone();
+        let (parent_hash, image_layers) = self.build_layers(session, layer_manager, &request, image_definition, &mut built_layers, &mut steps)?;
+        let parent_hash = parent_hash.ok_or_else(|| match final_stage_name {
+            Some(name) => ImageParseError::EmptyStage(name),
+            None => ImageParseError::Other("The image definition is empty".to_owned())
+        })?;
+
+        let image = Image::new(parent_hash, request.tag.to_owned());
+        self.insert_image(
+            session,
+            layer_manager,
+            &image,
+            &request.additional_tags,
+            tag_latest,
+            request.force
+        )?;
+
+        if request.print {
+            self.printer.event(&BuildEvent::BuildFinished {
+                image: image.clone(),
+                built_layers: built_layers.len(),
+                duration_seconds: build_start_time.elapsed().as_secs_f64()
+            });
+        }
+
+        Ok(
+            BuildResult {
+                image,
+                built_layers,
+                layers: image_layers,
+                steps,
+                stages
+            }
+        )
+    }
+
+    /// Builds the layers of a single stage, returning the top layer and all layers of the stage.
+    fn build_layers(&self,
+                    session: &mut StateSession,
+                    layer_manager: &LayerManager,
+                    request: &BuildRequest,
+                    image_definition: ImageDefinition,
+                    built_layers: &mut Vec<ImageId>,
+                    steps: &mut Vec<BuildStep>) -> ImageManagerResult<(Option<ImageId>, Vec<ImageId>)> {
         let mut parent_hash: Option<ImageId> = None;
 
-        if let Some(base_image_reference) = request.image_definition.base_image {
+        if let Some(base_image_reference) = image_definition.base_image {
             let hash = layer_manager.fully_qualify_reference(session, &base_image_reference)?;
             if !layer_manager.layer_exist(session, &hash)? {
                 return Err(ImageManagerError::ReferenceNotFound { reference: base_image_reference.clone() });
@@ -41,96 +143,320 @@ impl BuildManager {
             parent_hash = Some(hash);
         }
 
-        let num_layers = request.image_definition.layers.len();
-        let mut built_layers = Vec::new();
+        let num_layers = image_definition.layers.len();
         let mut image_layers = Vec::new();
 
-        for (layer_index, layer_definition) in request.image_definition.layers.into_iter().enumerate() {
+        let options = request.options();
+        let mut staging_folder = None;
+
+        for (layer_index, layer_definition) in image_definition.layers.into_iter().enumerate() {
             if request.print {
                 self.printer.println(&format!("Step {}/{}: {}", layer_index + 1, num_layers, layer_definition.input_line));
+                self.printer.event(&BuildEvent::StepStarted {
+                    step: layer_index + 1,
+                    num_steps: num_layers,
+                    input_line: layer_definition.input_line.clone()
+                });
             }
 
             let start_time = Instant::now();
+            let input_line = layer_definition.input_line.clone();
             let layer_definition = layer_definition.expand(&request.build_context)?;
             let layer = self.create_layer(
                 session,
                 layer_manager,
-                &request.build_context,
+                &mut staging_folder,
                 layer_definition,
                 &parent_hash,
-                request.force
+                &options
             )?;
             let hash = layer.hash.clone();
 
             image_layers.push(hash.clone());
-            let layer_built = self.build_layer(
+            let bytes_copied = self.build_layer(
                 session,
                 layer_manager,
-                &request.build_context,
                 start_time,
                 layer,
-                request.force,
-                request.verbose_output,
-                request.print
+                &options
             )?;
-            if layer_built {
+            if bytes_copied.is_some() {
                 built_layers.push(hash.clone());
             }
 
+            let step = BuildStep {
+                input_line,
+                hash: hash.clone(),
+                cached: bytes_copied.is_none(),
+                bytes_copied: bytes_copied.unwrap_or(DataSize(0)),
+                duration_seconds: start_time.elapsed().as_secs_f64()
+            };
+
+            if request.print {
+                self.printer.event(&BuildEvent::StepFinished { step: layer_index + 1, num_steps: num_layers, result: StepResult::from(&step) });
+            }
+
+            steps.push(step);
+
             parent_hash = Some(hash);
         }
 
-        let image = Image::new(parent_hash.unwrap(), request.tag.to_owned());
-        layer_manager.insert_or_replace_image(session, image.clone())?;
+        Ok((parent_hash, image_layers))
+    }
+
+    /// Builds an image from a zip or tar archive, streaming the entries directly into the layer storage.
+    /// The layers are grouped in the same way as `ImageDefinition::create_from_directory`.
+    pub fn build_image_from_archive(&self,
+                                    session: &mut StateSession,
+                                    layer_manager: &LayerManager,
+                                    archive_path: &Path,
+                                    tag: ImageTag,
+                                    options: &BuildOptions) -> ImageManagerResult<BuildResult> {
+        let build_start_time = Instant::now();
+
+        // The archive is first only hashed, so that nothing is extracted if a quota would be exceeded
+        let content = read_archive(archive_path, None, None)?;
+        let archive_layers = archive_layer_entries(&content);
+        if archive_layers.is_empty() {
+            return Err(ImageManagerError::OtherError { message: format!("The archive {} is empty", archive_path.display()) });
+        }
+
+        let mut parent_hash: Option<ImageId> = None;
+        let mut layers = Vec::new();
+        for (input_line, entries) in archive_layers {
+            let mut layer = archive_layer(parent_hash.clone(), &entries);
+            if let Some(build_time) = options.build_time {
+                layer.created = build_time;
+            }
+
+            parent_hash = Some(layer.hash.clone());
+
+            let build = options.force || !layer_manager.layer_exist(session, &layer.hash)?;
+            layers.push((input_line, layer, entries, build));
+        }
+
+        self.check_archive_quotas(session, layer_manager, &layers)?;
+
+        let mut stage_paths = HashSet::new();
+        for (_, _, entries, build) in &layers {
+            if *build {
+                for entry in entries {
+                    if let ArchiveLayerEntry::File { path, .. } = entry {
+                        stage_paths.insert(path.clone());
+                    }
+                }
+            }
+        }
+
+        let mut staging_folder = None;
+        if !stage_paths.is_empty() {
+            let staging_folder = self.staging_folder(&mut staging_folder)?;
+            if read_archive(archive_path, Some(staging_folder), Some(&stage_paths))? != content {
+                return Err(ImageManagerError::FileIOError { message: format!("The archive {} changed while building", archive_path.display()) });
+            }
+        }
+
+        let num_layers = layers.len();
+        let mut built_layers = Vec::new();
+        let mut image_layers = Vec::new();
+        let mut steps = Vec::new();
+
+        for (layer_index, (input_line, mut layer, _, build)) in layers.into_iter().enumerate() {
+            if options.print {
+                self.printer.println(&format!("Step {}/{}: {}", layer_index + 1, num_layers, input_line));
+                self.printer.event(&BuildEvent::StepStarted { step: layer_index + 1, num_steps: num_layers, input_line: input_line.clone() });
+            }
+
+            let start_time = Instant::now();
+
+            let hash = layer.hash.clone();
+            image_layers.push(hash.clone());
+
+            let mut step = BuildStep {
+                input_line,
+                hash: hash.clone(),
+                cached: true,
+                bytes_copied: DataSize(0),
+                duration_seconds: 0.0
+            };
+
+            if !build {
+                step.duration_seconds = start_time.elapsed().as_secs_f64();
+                if options.print {
+                    self.printer.println(&format!("\t* Layer already built: {}", layer.hash));
+                    self.printer.event(&BuildEvent::StepFinished { step: layer_index + 1, num_steps: num_layers, result: StepResult::from(&step) });
+                }
+
+                steps.push(step);
+                continue;
+            }
+
+            if options.print {
+                self.printer.println(&format!("\t* Building layer: {}...", layer.hash));
+            }
+
+            let destination_base_path = self.config.get_layer_folder(&layer.hash);
+            std::fs::create_dir_all(&destination_base_path)?;
+
+            for operation in &mut layer.operations {
+                if options.verbose_output {
+                    self.printer.println(&format!("\t* {}", operation));
+                }
+
+                if let LayerOperation::File { path, source_path, original_source_path, content_hash, .. } = operation {
+                    let destination_path = destination_base_path.join(create_hash(path));
+                    link_stored_file(&staging_folder.as_ref().unwrap().path().join(content_hash.as_str()), &destination_path)?;
+
+                    *source_path = destination_path.strip_prefix(self.config.base_folder()).unwrap().to_str().unwrap().to_owned();
+                    *original_source_path = create_hash(original_source_path);
+                }
+            }
+
+            if options.print {
+                self.printer.println(&format!(
+                    "\t* Layer built in {:.2} seconds ({} operations).",
+                    start_time.elapsed().as_secs_f64(),
+                    layer.operations.len()
+                ));
+            }
+
+            if options.force {
+                layer_manager.insert_or_replace_layer(session, &layer)?;
+            } else {
+                layer_manager.insert_layer(session, &layer)?;
+            }
+
+            // Like for UNPACK, the staged files are linked and not copied, so no bytes are copied
+            step.cached = false;
+            step.duration_seconds = start_time.elapsed().as_secs_f64();
+            if options.print {
+                self.printer.event(&BuildEvent::StepFinished { step: layer_index + 1, num_steps: num_layers, result: StepResult::from(&step) });
+            }
+
+            steps.push(step);
+
+            built_layers.push(hash);
+        }
+
+        let image = Image::new(parent_hash.unwrap(), tag);
+        self.insert_image(session, layer_manager, &image, &[], self.config.tag_latest, options.force)?;
 
-        if image.tag.tag() != "latest" {
-            let mut latest_image = image.clone();
-            latest_image.tag = latest_image.tag.set_tag("latest");
-            layer_manager.insert_or_replace_image(session, latest_image)?;
+        if options.print {
+            self.printer.event(&BuildEvent::BuildFinished {
+                image: image.clone(),
+                built_layers: built_layers.len(),
+                duration_seconds: build_start_time.elapsed().as_secs_f64()
+            });
         }
 
         Ok(
             BuildResult {
                 image,
                 built_layers,
-                layers: image_layers
+                layers: image_layers,
+                steps,
+                stages: Vec::new()
             }
         )
     }
 
+    /// Returns the folder where extracted archive files are staged until linked into their layer, creating it on first use.
+    fn staging_folder<'a>(&self, staging_folder: &'a mut Option<TempDir>) -> ImageManagerResult<&'a Path> {
+        if staging_folder.is_none() {
+            std::fs::create_dir_all(self.config.base_folder())?;
+            *staging_folder = Some(
+                tempfile::Builder::new()
+                    .prefix("tmp-staging")
+                    .tempdir_in(self.config.base_folder())?
+            );
+        }
+
+        Ok(staging_folder.as_ref().unwrap().path())
+    }
+
+    fn check_archive_quotas(&self,
+                            session: &StateSession,
+                            layer_manager: &LayerManager,
+                            layers: &[(String, Layer, Vec<ArchiveLayerEntry>, bool)]) -> ImageManagerResult<()> {
+        let mut image_size = DataSize(0);
+        let mut new_size = DataSize(0);
+        for (_, layer, _, build) in layers {
+            self.config.check_quota(Quota::LayerSize, layer.storage_size)?;
+
+            image_size += layer.storage_size;
+            if *build && !layer_manager.layer_exist(session, &layer.hash)? {
+                new_size += layer.storage_size;
+            }
+        }
+
+        self.config.check_quota(Quota::ImageSize, image_size)?;
+
+        if self.config.max_store_size.is_some() {
+            self.config.check_quota(Quota::StoreSize, layer_manager.total_storage_size(session)? + new_size)?;
+        }
+
+        Ok(())
+    }
+
+    fn insert_image(&self,
+                    session: &mut StateSession,
+                    layer_manager: &LayerManager,
+                    image: &Image,
+                    additional_tags: &[ImageTag],
+                    tag_latest: bool,
+                    force: bool) -> ImageManagerResult<()> {
+        let mut images = Vec::new();
+        for tag in std::iter::once(&image.tag).chain(additional_tags.iter()) {
+            images.push(Image::new(image.hash.clone(), tag.clone()));
+
+            if tag_latest && tag.tag() != "latest" {
+                images.push(Image::new(image.hash.clone(), tag.clone().set_tag("latest")));
+            }
+        }
+
+        // None of the tags are set if any of them is pinned to another image
+        for image in &images {
+            layer_manager.check_replace_pinned(session, image, force)?;
+        }
+
+        for image in images {
+            layer_manager.insert_or_replace_image(session, image)?;
+        }
+
+        Ok(())
+    }
+
     fn build_layer(&self,
                    session: &mut StateSession,
                    layer_manager: &LayerManager,
-                   build_context: &Path,
                    start_time: Instant,
                    mut layer: Layer,
-                   force: bool,
-                   verbose_output: bool,
-                   print: bool) -> ImageManagerResult<bool> {
-        if !force && layer_manager.layer_exist(session, &layer.hash)? {
-            if print {
+                   options: &BuildOptions) -> ImageManagerResult<Option<DataSize>> {
+        if !options.force && layer_manager.layer_exist(session, &layer.hash)? {
+            if options.print {
                 self.printer.println(&format!("\t* Layer already built: {}", layer.hash));
             }
-            return Ok(false);
+            return Ok(None);
         }
 
-        if print {
+        if options.print {
             self.printer.println(&format!("\t* Building layer: {}...", layer.hash));
         }
 
         let destination_base_path = self.config.get_layer_folder(&layer.hash);
         std::fs::create_dir_all(&destination_base_path)?;
 
+        let mut bytes_copied = DataSize(0);
         for operation in &mut layer.operations {
-            self.build_operation(
-                verbose_output,
-                build_context,
+            bytes_copied += self.build_operation(
+                options.verbose_output,
+                &options.build_context,
                 &destination_base_path,
                 operation
             )?;
         }
 
-        if print {
+        if options.print {
             self.printer.println(&format!(
                 "\t* Layer built in {:.2} seconds ({} operations).",
                 start_time.elapsed().as_secs_f64(),
@@ -138,30 +464,35 @@ impl BuildManager {
             ));
         }
 
-        if force {
+        if options.force {
             layer_manager.insert_or_replace_layer(session, &layer)?;
         } else {
             layer_manager.insert_layer(session, &layer)?;
         }
 
-        Ok(true)
+        Ok(Some(bytes_copied))
     }
 
     fn build_operation(&self,
                        verbose_output: bool,
                        build_context: &Path,
                        destination_base_path: &Path,
-                       operation: &mut LayerOperation) -> ImageManagerResult<()> {
+                       operation: &mut LayerOperation) -> ImageManagerResult<DataSize> {
         if verbose_output {
             self.printer.println(&format!("\t* {}", operation));
         }
 
+        let mut bytes_copied = DataSize(0);
         match operation {
+            LayerOperation::File { path, source_path, .. } if !source_path.is_empty() => {
+                // Already in the store (from another image or an extracted archive), so it is linked instead of copied
+                *source_path = self.link_into_layer_folder(destination_base_path, path, source_path)?;
+            }
             LayerOperation::File { path, source_path, original_source_path, .. } => {
                 let destination_path = destination_base_path.join(Path::new(&create_hash(path)));
                 let relative_destination_path = destination_path.strip_prefix(&self.config.base_folder).unwrap();
 
-                std::fs::copy(&build_context.join(&original_source_path), &destination_path)
+                let copied = std::fs::copy(&build_context.join(&original_source_path), &destination_path)
                     .map_err(|err|
                         ImageManagerError::FileIOError {
                             message: format!(
@@ -173,22 +504,32 @@ impl BuildManager {
                         }
                     )?;
 
+                bytes_copied += DataSize(copied as usize);
                 *source_path = relative_destination_path.to_str().unwrap().to_owned();
                 *original_source_path = create_hash(&original_source_path);
             },
+            LayerOperation::CompressedFile { path, source_path, .. } if !source_path.is_empty() => {
+                *source_path = self.link_into_layer_folder(destination_base_path, path, source_path)?;
+            }
             _ => {}
         }
 
-        Ok(())
+        Ok(bytes_copied)
+    }
+
+    fn link_into_layer_folder(&self, destination_base_path: &Path, path: &str, source_path: &str) -> ImageManagerResult<String> {
+        let destination_path = destination_base_path.join(create_hash(path));
+        link_stored_file(&self.config.base_folder().join(source_path), &destination_path)?;
+        Ok(destination_path.strip_prefix(self.config.base_folder()).unwrap().to_str().unwrap().to_owned())
     }
 
     fn create_layer(&self,
                     session: &mut StateSession,
                     layer_manager: &LayerManager,
-                    build_context: &Path,
+                    staging_folder: &mut Option<TempDir>,
                     layer_definition: LayerDefinition,
                     parent_hash: &Option<ImageId>,
-                    force: bool) -> ImageManagerResult<Layer> {
+                    options: &BuildOptions) -> ImageManagerResult<Layer> {
         let mut layer_operations = Vec::new();
         let mut layer_hash = LayerHash::new();
         layer_hash.add_parent_hash(parent_hash.as_ref());
@@ -196,6 +537,7 @@ impl BuildManager {
         let mut added_content_hashes = Vec::new();
         let mut storage_size = DataSize(0);
         let mut num_alias = 0;
+        let mut archives = Vec::new();
 
         for operation_definition in &layer_definition.operations {
             match operation_definition {
@@ -222,7 +564,7 @@ impl BuildManager {
                     layer_operations.push(LayerOperation::ImageAlias { hash });
                     num_alias += 1;
                 }
-                LayerOperationDefinition::File { path, source_path, link_type, writable } => {
+                LayerOperationDefinition::File { path, source_path, link_type, writable, template } => {
                     let source_path_entry = Path::new(&source_path);
                     if !source_path_entry.exists() {
                         return Err(
@@ -239,7 +581,7 @@ impl BuildManager {
                     let modified_time_ms = modified_time.duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64;
 
                     let content_hash = match session.get_content_hash(source_path, modified_time_ms)? {
-                        Some(content_hash) if !force => content_hash,
+                        Some(content_hash) if !options.force => content_hash,
                         _ => {
                             let content_hash = compute_content_hash(source_path_entry)?;
                             added_content_hashes.push((source_path.clone(), modified_time_ms, content_hash.clone()));
@@ -247,7 +589,7 @@ impl BuildManager {
                         }
                     };
 
-                    let relative_source_path = Path::new(source_path).strip_prefix(build_context)
+                    let relative_source_path = Path::new(source_path).strip_prefix(&options.build_context)
                         .map_err(|_| ImageManagerError::FileNotInBuildContext { path: source_path.clone() })?;
                     let relative_source_path = relative_source_path.to_str().unwrap();
 
@@ -257,12 +599,31 @@ impl BuildManager {
                         original_source_path: relative_source_path.to_owned(),
                         content_hash: content_hash.clone(),
                         link_type: *link_type,
-                        writable: *writable
+                        writable: *writable,
+                        template: *template
                     };
 
                     layer_hash.add_file(&operation, false);
                     layer_operations.push(operation);
                 },
+                LayerOperationDefinition::ImageFile { reference, path, source_path, link_type, writable } => {
+                    let operations = image_file_operations(session, layer_manager, reference, source_path, path, *link_type, *writable)?;
+                    self.add_stored_operations(operations, &mut layer_hash, &mut storage_size, &mut layer_operations);
+                }
+                LayerOperationDefinition::Archive { path, source_path } => {
+                    let (content, operations) = self.archive_operations(&options.build_context, staging_folder, source_path, path)?;
+                    for operation in operations {
+                        match &operation {
+                            LayerOperation::Directory { path } => layer_hash.add_directory(path),
+                            _ => layer_hash.add_file(&operation, true)
+                        }
+
+                        layer_operations.push(operation);
+                    }
+
+                    storage_size += content.storage_size();
+                    archives.push((source_path, content));
+                }
                 LayerOperationDefinition::Directory { path } => {
                     layer_operations.push(LayerOperation::Directory { path: path.clone() });
                     layer_hash.add_directory(path);
@@ -279,14 +640,113 @@ impl BuildManager {
 
         session.insert_content_hashes(added_content_hashes)?;
 
-        Ok(
-            Layer::new(
-                parent_hash.clone(),
-                layer_hash.finalize(),
-                layer_operations,
-                storage_size
-            )
-        )
+        let mut layer = Layer::new(
+            parent_hash.clone(),
+            layer_hash.finalize(),
+            layer_operations,
+            storage_size
+        );
+
+        if let Some(build_time) = options.build_time {
+            layer.created = build_time;
+        }
+
+        self.check_quotas(session, layer_manager, &layer)?;
+
+        // The archives are only extracted once the quotas are known to hold, and when the layer is not already built
+        if !options.force && layer_manager.layer_exist(session, &layer.hash)? {
+            archives.clear();
+        }
+
+        for (archive_path, content) in archives {
+            let staging_folder = self.staging_folder(staging_folder)?;
+            if read_archive(Path::new(archive_path), Some(staging_folder), None)? != content {
+                return Err(ImageManagerError::FileIOError { message: format!("The archive {} changed while building", archive_path) });
+            }
+        }
+
+        Ok(layer)
+    }
+
+    /// Adds operations whose files are already in the store, such as the ones copied from other images.
+    fn add_stored_operations(&self,
+                             operations: Vec<LayerOperation>,
+                             layer_hash: &mut LayerHash,
+                             storage_size: &mut DataSize,
+                             layer_operations: &mut Vec<LayerOperation>) {
+        for operation in operations {
+            match &operation {
+                LayerOperation::Directory { path } => {
+                    layer_hash.add_directory(path);
+                }
+                LayerOperation::File { source_path, .. } => {
+                    *storage_size += DataSize::from_file(&self.config.base_folder().join(source_path));
+                    layer_hash.add_file(&operation, true);
+                }
+                LayerOperation::CompressedFile { source_path, .. } => {
+                    *storage_size += DataSize::from_file(&self.config.base_folder().join(source_path));
+                    layer_hash.add_compressed_file(&operation, true);
+                }
+                LayerOperation::Image { .. } => {}
+                LayerOperation::ImageAlias { .. } => {}
+                LayerOperation::Label { .. } => {}
+            }
+
+            layer_operations.push(operation);
+        }
+    }
+
+    /// Hashes the entries of the archive, creating an operation for each entry.
+    /// The files are not extracted, but refer to where they will be staged.
+    fn archive_operations(&self,
+                          build_context: &Path,
+                          staging_folder: &mut Option<TempDir>,
+                          archive_path: &str,
+                          destination_path: &str) -> ImageManagerResult<(ArchiveContent, Vec<LayerOperation>)> {
+        if Path::new(archive_path).strip_prefix(build_context).is_err() || !Path::new(archive_path).is_file() {
+            return Err(ImageManagerError::FileNotInBuildContext { path: archive_path.to_owned() });
+        }
+
+        let staging_folder = self.staging_folder(staging_folder)?;
+        let content = read_archive(Path::new(archive_path), None, None)?;
+
+        let mut operations = Vec::new();
+        for directory in &content.directories {
+            operations.push(LayerOperation::Directory { path: copied_path("", destination_path, directory, false).unwrap() });
+        }
+
+        for (path, file) in &content.files {
+            let staged_path = staging_folder.join(&file.content_hash);
+            operations.push(
+                LayerOperation::File {
+                    path: copied_path("", destination_path, path, true).unwrap(),
+                    source_path: staged_path.strip_prefix(self.config.base_folder()).unwrap().to_str().unwrap().to_owned(),
+                    // Only the entries are part of the layer hash, so the same content in another archive gives the same layer
+                    original_source_path: create_hash(path),
+                    content_hash: file.content_hash.clone(),
+                    link_type: LinkType::Hard,
+                    writable: false,
+                    template: false
+                }
+            );
+        }
+
+        Ok((content, operations))
+    }
+
+    /// Checks that the layer does not exceed any of the configured quotas, before its files are copied into the store.
+    fn check_quotas(&self, session: &StateSession, layer_manager: &LayerManager, layer: &Layer) -> ImageManagerResult<()> {
+        self.config.check_quota(Quota::LayerSize, layer.storage_size)?;
+
+        if self.config.max_image_size.is_some() {
+            self.config.check_quota(Quota::ImageSize, layer_manager.size_of_layer(session, layer, true)?)?;
+        }
+
+        if self.config.max_store_size.is_some() && !layer_manager.layer_exist(session, &layer.hash)? {
+            self.config.check_quota(Quota::StoreSize, layer_manager.total_storage_size(session)? + layer.storage_size)?;
+        }
+
+        Ok(())
     }
 }
 
@@ -302,10 +762,14 @@ impl LayerHash {
     }
 
     pub fn from_layer(layer: &Layer) -> ImageId {
+        LayerHash::from_operations(layer.parent_hash.as_ref(), &layer.operations)
+    }
+
+    pub fn from_operations(parent_hash: Option<&ImageId>, operations: &[LayerOperation]) -> ImageId {
         let mut layer_hash = LayerHash::new();
-        layer_hash.add_parent_hash(layer.parent_hash.as_ref());
+        layer_hash.add_parent_hash(parent_hash);
 
-        for operation in &layer.operations {
+        for operation in operations {
             match operation {
                 LayerOperation::Image { hash } => {
                     layer_hash.add_image_ref(&hash);
@@ -355,7 +819,7 @@ impl LayerHash {
     }
 
     pub fn add_file(&mut self, operation: &LayerOperation, hashed: bool) {
-        if let LayerOperation::File { path, original_source_path, content_hash, link_type, writable, .. } = operation {
+        if let LayerOperation::File { path, original_source_path, content_hash, link_type, writable, template, .. } = operation {
             let original_source_path = if hashed {
                 original_source_path.clone()
             } else {
@@ -371,11 +835,16 @@ impl LayerHash {
                 link_type,
                 writable
             );
+
+            // Only added for templates, so that the hashes of other layers are unchanged
+            if *template {
+                self.hash_input += "template";
+            }
         }
     }
 
     pub fn add_compressed_file(&mut self, operation: &LayerOperation, hashed: bool) {
-        if let LayerOperation::CompressedFile { path, original_source_path, content_hash, link_type, writable, .. } = operation {
+        if let LayerOperation::CompressedFile { path, original_source_path, content_hash, link_type, writable, template, .. } = operation {
             let original_source_path = if hashed {
                 original_source_path.clone()
             } else {
@@ -391,6 +860,11 @@ impl LayerHash {
                 link_type,
                 writable
             );
+
+            // Only added for templates, so that the hashes of other layers are unchanged
+            if *template {
+                self.hash_input += "template";
+            }
         }
     }
     
@@ -405,79 +879,441 @@ impl LayerHash {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct BuildRequest {
     pub build_context: PathBuf,
     pub image_definition: ImageDefinition,
     pub tag: ImageTag,
     pub force: bool,
     pub verbose_output: bool,
-    pub print: bool
+    pub print: bool,
+    /// Fixed creation time of the built layers, used for reproducible builds.
+    pub build_time: Option<DateTime<FixedOffset>>,
+    /// Additional labels added to the top layer of the image.
+    pub labels: Vec<(String, String)>,
+    /// Additional tags of the built image.
+    pub additional_tags: Vec<ImageTag>,
+    /// If the latest tag should also be set, uses the configured value if not specified.
+    pub tag_latest: Option<bool>,
+    /// Tags of the other stages of a multi-stage definition, these stages are built as well.
+    pub stage_tags: Vec<(String, ImageTag)>
+}
+
+impl BuildRequest {
+    fn options(&self) -> BuildOptions {
+        BuildOptions {
+            build_context: self.build_context.clone(),
+            force: self.force,
+            verbose_output: self.verbose_output,
+            print: self.print,
+            build_time: self.build_time
+        }
+    }
+}
+
+/// The options that apply to every layer of a build.
+#[derive(Debug, Clone, Default)]
+pub struct BuildOptions {
+    pub build_context: PathBuf,
+    pub force: bool,
+    pub verbose_output: bool,
+    pub print: bool,
+    /// Fixed creation time of the built layers, used for reproducible builds.
+    pub build_time: Option<DateTime<FixedOffset>>
+}
+
+/// Returns the provenance labels (in the reserved namespace) describing the inputs of a build.
+pub fn provenance_labels(definition_file: &Path,
+                         build_context: &Path,
+                         arguments: &[(String, String)]) -> ImageManagerResult<Vec<(String, String)>> {
+    let label_key = |name: &str| format!("{}{}", RESERVED_LABEL_PREFIX, name);
+
+    let mut labels = vec![
+        (label_key("labarfile.hash"), compute_content_hash(definition_file)?),
+        (label_key("build.arguments"), arguments.iter().map(|(key, value)| format!("{}={}", key, value)).sorted().join(",")),
+        (label_key("version"), env!("CARGO_PKG_VERSION").to_owned())
+    ];
+
+    let git_output = std::process::Command::new("git")
+        .arg("-C")
+        .arg(build_context)
+        .args(["rev-parse", "HEAD"])
+        .output();
+
+    if let Ok(git_output) = git_output {
+        if git_output.status.success() {
+            labels.push((label_key("git.commit"), String::from_utf8_lossy(&git_output.stdout).trim().to_owned()));
+        }
+    }
+
+    Ok(labels)
+}
+
+/// Returns the build time to use for reproducible builds.
+///
+/// `SOURCE_DATE_EPOCH` is always honored, otherwise the Unix epoch is used if `reproducible` is set.
+pub fn reproducible_build_time(reproducible: bool) -> ImageManagerResult<Option<DateTime<FixedOffset>>> {
+    if let Ok(source_date_epoch) = std::env::var("SOURCE_DATE_EPOCH") {
+        let timestamp = source_date_epoch.trim().parse::<i64>()
+            .map_err(|_| ImageManagerError::OtherError { message: format!("Invalid SOURCE_DATE_EPOCH: {}", source_date_epoch) })?;
+
+        return utc_build_time(timestamp)
+            .map(Some)
+            .ok_or_else(|| ImageManagerError::OtherError { message: format!("Invalid SOURCE_DATE_EPOCH: {}", source_date_epoch) });
+    }
+
+    if reproducible {
+        Ok(Some(utc_build_time(0).unwrap()))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Pinned build times always use the UTC offset, so that the serialized layer does not depend on the timezone of the machine.
+fn utc_build_time(timestamp: i64) -> Option<DateTime<FixedOffset>> {
+    Utc.timestamp_opt(timestamp, 0).single().map(|time| time.fixed_offset())
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct BuildResult {
     pub image: Image,
     #[allow(dead_code)]
     pub built_layers: Vec<ImageId>,
     #[allow(dead_code)]
     pub layers: Vec<ImageId>,
+    pub steps: Vec<BuildStep>,
+    /// The other stages that were built, for multi-stage definitions.
+    pub stages: Vec<(String, ImageId)>
 }
 
-fn create_hash(input: &str) -> String {
-    base16ct::lower::encode_string(&Sha256::digest(input.as_bytes()))
+#[derive(Debug, Clone, Serialize)]
+pub struct BuildStep {
+    pub input_line: String,
+    pub hash: ImageId,
+    pub cached: bool,
+    pub bytes_copied: DataSize,
+    pub duration_seconds: f64
 }
 
-#[test]
-fn test_build() {
-    use crate::image_manager::{test_helpers, ConsolePrinter};
-    use crate::reference::Reference;
-    use crate::image_manager::details::state::StateManager;
+impl From<&BuildStep> for StepResult {
+    fn from(step: &BuildStep) -> StepResult {
+        StepResult {
+            input_line: step.input_line.clone(),
+            hash: step.hash.clone(),
+            cached: step.cached,
+            bytes_copied: step.bytes_copied,
+            duration_seconds: step.duration_seconds
+        }
+    }
+}
 
-    let tmp_folder = crate::test_helpers::TempFolder::new();
-    let config = ImageManagerConfig::with_base_folder(tmp_folder.owned());
+/// The entries of an archive, where later entries with the same path replace earlier ones.
+#[derive(Debug, PartialEq)]
+struct ArchiveContent {
+    /// Includes the parent directories of all entries, even when not part of the archive.
+    directories: BTreeSet<String>,
+    files: BTreeMap<String, ArchiveFile>
+}
 
-    let printer = ConsolePrinter::new();
-    let state_manager = StateManager::new(&config.base_folder()).unwrap();
-    let mut layer_manager = LayerManager::new(config.clone());
-    let build_manager = BuildManager::new(config, printer);
-    let mut session = state_manager.session().unwrap();
+impl ArchiveContent {
+    fn storage_size(&self) -> DataSize {
+        DataSize(self.files.values().map(|file| file.size as usize).sum())
+    }
+}
 
-    let result = test_helpers::build_image2(
-        &mut session,
-        &mut layer_manager,
-        &build_manager,
-        Path::new("testdata/definitions/simple1.labarfile"),
-        ImageTag::from_str("test").unwrap(),
-        false
-    );
-    assert!(result.is_ok());
-    let result = result.unwrap().image;
+#[derive(Debug, PartialEq)]
+struct ArchiveFile {
+    content_hash: String,
+    size: u64
+}
 
-    assert_eq!(ImageTag::from_str("test").unwrap(), result.tag);
-    assert_eq!(ImageId::from_str("1f2bc97496b012cfe7969d0a7672818695727b3cfa341748a01616bc5f3ca15a").unwrap(), result.hash);
+/// Reads the entries of the archive, hashing the content of the files.
+/// If a staging folder is given, the files (or only the given paths) are also extracted into it, named by their content hash.
+fn read_archive(archive_path: &Path,
+                staging_folder: Option<&Path>,
+                stage_paths: Option<&HashSet<String>>) -> ImageManagerResult<ArchiveContent> {
+    let mut content = ArchiveContent {
+        directories: BTreeSet::new(),
+        files: BTreeMap::new()
+    };
 
-    let session = state_manager.session().unwrap();
-    let image = layer_manager.get_layer(&session, &Reference::from_str("test").unwrap());
-    assert!(image.is_ok());
-    let image = image.unwrap();
-    assert_eq!(image.hash, result.hash);
+    visit_archive(
+        archive_path,
+        |entry, reader| {
+            if entry.path.is_empty() {
+                return Ok(());
+            }
 
-    assert_eq!(layer_manager.get_image_hash(&session, &ImageTag::from_str("test").unwrap()).unwrap(), Some(result.hash));
-}
+            let entry_directory = if entry.is_directory {
+                Some(entry.path.as_str())
+            } else {
+                Path::new(&entry.path).parent().and_then(|parent| parent.to_str())
+            };
 
-#[test]
-fn test_build_with_cache1() {
-    use crate::image_manager::{test_helpers, ConsolePrinter};
-    use crate::reference::Reference;
-    use crate::image_manager::details::state::StateManager;
+            let mut current = String::new();
+            for part in entry_directory.unwrap_or("").split('/').filter(|part| !part.is_empty()) {
+                if !current.is_empty() {
+                    current.push('/');
+                }
 
-    let tmp_folder = crate::test_helpers::TempFolder::new();
-    let config = ImageManagerConfig::with_base_folder(tmp_folder.owned());
+                current.push_str(part);
+                content.directories.insert(current.clone());
+            }
 
-    let printer = ConsolePrinter::new();
-    let state_manager = StateManager::new(&config.base_folder()).unwrap();
-    let mut layer_manager = LayerManager::new(config.clone());
+            if entry.is_directory {
+                return Ok(());
+            }
+
+            let staging_folder = staging_folder.filter(|_| stage_paths.map(|paths| paths.contains(&entry.path)).unwrap_or(true));
+            let (content_hash, size) = match staging_folder {
+                Some(staging_folder) => {
+                    // Written to a temporary file first, as the same content can already be staged
+                    let mut staged_file = tempfile::NamedTempFile::new_in(staging_folder)?;
+                    let (content_hash, size) = {
+                        let mut writer = BufWriter::new(staged_file.as_file_mut());
+                        let result = copy_with_content_hash(reader, &mut writer)?;
+                        writer.flush()?;
+                        result
+                    };
+
+                    staged_file.persist(staging_folder.join(&content_hash)).map_err(|err| err.error)?;
+                    (content_hash, size)
+                }
+                None => copy_with_content_hash(reader, &mut std::io::sink())?
+            };
+
+            content.files.insert(entry.path, ArchiveFile { content_hash, size });
+            Ok(())
+        }
+    ).map_err(|err| ImageManagerError::FileIOError { message: format!("Failed to read archive {} due to: {}", archive_path.display(), err) })?;
+
+    Ok(content)
+}
+
+enum ArchiveLayerEntry {
+    Directory { path: String },
+    File { path: String, content_hash: String, size: u64 }
+}
+
+/// Groups the entries of the archive into layers, in the same way as `ImageDefinition::create_from_directory`.
+fn archive_layer_entries(content: &ArchiveContent) -> Vec<(String, Vec<ArchiveLayerEntry>)> {
+    let mut directories = BTreeMap::<&str, Vec<ArchiveLayerEntry>>::new();
+    for directory in &content.directories {
+        let (top_directory, sub_path) = directory.split_once('/').unwrap_or((directory, ""));
+        let entries = directories.entry(top_directory).or_default();
+
+        // Like when copying a directory, the top directory itself is not part of the operations
+        if !sub_path.is_empty() {
+            entries.push(ArchiveLayerEntry::Directory { path: directory.clone() });
+        }
+    }
+
+    let mut root_files = Vec::new();
+    for (path, file) in &content.files {
+        let entry = ArchiveLayerEntry::File {
+            path: path.clone(),
+            content_hash: file.content_hash.clone(),
+            size: file.size
+        };
+
+        match path.split_once('/') {
+            Some((top_directory, _)) => directories.get_mut(top_directory).unwrap().push(entry),
+            None => root_files.push((path, entry))
+        }
+    }
+
+    let mut layers = Vec::new();
+    for (directory, entries) in directories {
+        layers.push((format!("directory: {}", directory), entries));
+    }
+
+    for (path, entry) in root_files {
+        layers.push((format!("root file: {}", path), vec![entry]));
+    }
+
+    layers
+}
+
+/// Creates the layer of an archive group, where the source paths of the files are set once extracted.
+fn archive_layer(parent_hash: Option<ImageId>, entries: &[ArchiveLayerEntry]) -> Layer {
+    let mut layer_operations = Vec::new();
+    let mut layer_hash = LayerHash::new();
+    layer_hash.add_parent_hash(parent_hash.as_ref());
+    let mut storage_size = DataSize(0);
+
+    for entry in entries {
+        match entry {
+            ArchiveLayerEntry::Directory { path } => {
+                layer_hash.add_directory(path);
+                layer_operations.push(LayerOperation::Directory { path: path.clone() });
+            }
+            ArchiveLayerEntry::File { path, content_hash, size, .. } => {
+                let operation = LayerOperation::File {
+                    path: path.clone(),
+                    source_path: String::new(),
+                    original_source_path: path.clone(),
+                    content_hash: content_hash.clone(),
+                    link_type: LinkType::Hard,
+                    writable: false,
+                    template: false
+                };
+
+                storage_size += DataSize(*size as usize);
+                layer_hash.add_file(&operation, false);
+                layer_operations.push(operation);
+            }
+        }
+    }
+
+    Layer::new(parent_hash, layer_hash.finalize(), layer_operations, storage_size)
+}
+
+/// Creates the operations for copying the given path out of another image, reusing its stored files.
+fn image_file_operations(session: &StateSession,
+                         layer_manager: &LayerManager,
+                         reference: &Reference,
+                         source_path: &str,
+                         destination_path: &str,
+                         link_type: LinkType,
+                         writable: bool) -> ImageManagerResult<Vec<LayerOperation>> {
+    let hash = layer_manager.fully_qualify_reference(session, reference)?;
+    if !layer_manager.layer_exist(session, &hash)? {
+        return Err(ImageManagerError::ReferenceNotFound { reference: reference.clone() });
+    }
+
+    // The top layer is visited first, so the first operation for a path is the one in use
+    let mut visited_paths = HashSet::new();
+    let mut directories = BTreeMap::new();
+    let mut files = BTreeMap::new();
+    layer_manager.visit_operations(
+        session,
+        &hash.to_ref(),
+        |operation| {
+            let new_path = match operation {
+                LayerOperation::Directory { path } => copied_path(source_path, destination_path, path, false),
+                LayerOperation::File { path, .. } | LayerOperation::CompressedFile { path, .. } => copied_path(source_path, destination_path, path, true),
+                _ => None
+            };
+
+            let new_path = new_path?;
+            if !visited_paths.insert(new_path.clone()) {
+                return Option::<()>::None;
+            }
+
+            let mut operation = operation.clone();
+            match &mut operation {
+                LayerOperation::Directory { path } => {
+                    *path = new_path.clone();
+                    directories.insert(new_path, operation);
+                }
+                LayerOperation::File { path, link_type: file_link_type, writable: file_writable, .. }
+                | LayerOperation::CompressedFile { path, link_type: file_link_type, writable: file_writable, .. } => {
+                    *path = new_path.clone();
+                    *file_link_type = link_type;
+                    *file_writable = writable;
+                    files.insert(new_path, operation);
+                }
+                LayerOperation::Image { .. } => {}
+                LayerOperation::ImageAlias { .. } => {}
+                LayerOperation::Label { .. } => {}
+            }
+
+            None
+        }
+    )?;
+
+    if files.is_empty() && directories.is_empty() {
+        return Err(ImageManagerError::PathNotInImage { reference: reference.clone(), path: source_path.to_owned() });
+    }
+
+    Ok(directories.into_values().chain(files.into_values()).collect())
+}
+
+/// Returns the path of the entry when copying the source path of an image to the destination path.
+/// Like when copying a directory from the build context, the source directory itself is not included.
+fn copied_path(source_path: &str, destination_path: &str, path: &str, is_file: bool) -> Option<String> {
+    let source_path = source_path.trim_end_matches('/');
+    let relative_path = if source_path.is_empty() || source_path == "." {
+        path
+    } else if path == source_path {
+        if !is_file {
+            return None;
+        }
+
+        let file_name = Path::new(path).file_name()?.to_str()?;
+        return if destination_path.ends_with('/') {
+            Some(format!("{}{}", destination_path, file_name))
+        } else if destination_path == "." {
+            Some(file_name.to_owned())
+        } else {
+            Some(destination_path.to_owned())
+        };
+    } else {
+        path.strip_prefix(source_path)?.strip_prefix('/')?
+    };
+
+    let destination_path = destination_path.trim_end_matches('/');
+    if destination_path.is_empty() || destination_path == "." {
+        Some(relative_path.to_owned())
+    } else {
+        Some(format!("{}/{}", destination_path, relative_path))
+    }
+}
+
+fn create_hash(input: &str) -> String {
+    base16ct::lower::encode_string(&Sha256::digest(input.as_bytes()))
+}
+
+#[test]
+fn test_build() {
+    use crate::image_manager::{test_helpers, ConsolePrinter};
+    use crate::reference::Reference;
+    use crate::image_manager::details::state::StateManager;
+
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+    let config = ImageManagerConfig::with_base_folder(tmp_folder.owned());
+
+    let printer = ConsolePrinter::new();
+    let state_manager = StateManager::new(&config.base_folder()).unwrap();
+    let mut layer_manager = LayerManager::new(config.clone());
+    let build_manager = BuildManager::new(config, printer);
+    let mut session = state_manager.session().unwrap();
+
+    let result = test_helpers::build_image2(
+        &mut session,
+        &mut layer_manager,
+        &build_manager,
+        Path::new("testdata/definitions/simple1.labarfile"),
+        ImageTag::from_str("test").unwrap(),
+        false
+    );
+    assert!(result.is_ok());
+    let result = result.unwrap().image;
+
+    assert_eq!(ImageTag::from_str("test").unwrap(), result.tag);
+    assert_eq!(ImageId::from_str("1f2bc97496b012cfe7969d0a7672818695727b3cfa341748a01616bc5f3ca15a").unwrap(), result.hash);
+
+    let session = state_manager.session().unwrap();
+    let image = layer_manager.get_layer(&session, &Reference::from_str("test").unwrap());
+    assert!(image.is_ok());
+    let image = image.unwrap();
+    assert_eq!(image.hash, result.hash);
+
+    assert_eq!(layer_manager.get_image_hash(&session, &ImageTag::from_str("test").unwrap()).unwrap(), Some(result.hash));
+}
+
+#[test]
+fn test_build_with_cache1() {
+    use crate::image_manager::{test_helpers, ConsolePrinter};
+    use crate::reference::Reference;
+    use crate::image_manager::details::state::StateManager;
+
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+    let config = ImageManagerConfig::with_base_folder(tmp_folder.owned());
+
+    let printer = ConsolePrinter::new();
+    let state_manager = StateManager::new(&config.base_folder()).unwrap();
+    let mut layer_manager = LayerManager::new(config.clone());
     let build_manager = BuildManager::new(config, printer.clone());
     let mut session = state_manager.session().unwrap();
 
@@ -541,9 +1377,9 @@ fn test_build_with_cache2() {
     std::fs::write(&tmp_content_file, "Hello, World!").unwrap();
 
     // Build first time
-    let image_definition = ImageDefinition {
-        base_image: None,
-        layers: vec![
+    let image_definition = ImageDefinition::new(
+        None,
+        vec![
             LayerDefinition::new(
                 "".to_owned(),
                 vec![
@@ -551,12 +1387,13 @@ fn test_build_with_cache2() {
                         path: "test.txt".to_string(),
                         source_path: "test.txt".to_string(),
                         link_type: LinkType::Hard,
-                        writable: false
+                        writable: false,
+                        template: false
                     }
                 ]
             )
-        ],
-    };
+        ]
+    );
 
     let first_result = build_manager.build_image(
         &mut session,
@@ -567,7 +1404,12 @@ fn test_build_with_cache2() {
             tag: ImageTag::from_str("test").unwrap(),
             force: false,
             verbose_output: false,
-            print: true
+            print: true,
+            build_time: None,
+            labels: Vec::new(),
+            additional_tags: Vec::new(),
+            tag_latest: None,
+            stage_tags: Vec::new()
         }
     );
     assert!(first_result.is_ok(), "{}", first_result.unwrap_err());
@@ -584,7 +1426,12 @@ fn test_build_with_cache2() {
             tag: ImageTag::from_str("test").unwrap(),
             force: false,
             verbose_output: false,
-            print: true
+            print: true,
+            build_time: None,
+            labels: Vec::new(),
+            additional_tags: Vec::new(),
+            tag_latest: None,
+            stage_tags: Vec::new()
         }
     );
     assert!(second_result.is_ok());
@@ -616,7 +1463,12 @@ fn test_build_with_cache2() {
             tag: ImageTag::from_str("test").unwrap(),
             force: false,
             verbose_output: false,
-            print: true
+            print: true,
+            build_time: None,
+            labels: Vec::new(),
+            additional_tags: Vec::new(),
+            tag_latest: None,
+            stage_tags: Vec::new()
         }
     );
     assert!(third_result.is_ok());
@@ -726,4 +1578,510 @@ fn test_build_with_image_ref() {
     assert_eq!(image.hash, result.hash);
 
     assert_eq!(layer_manager.get_image_hash(&session, &ImageTag::from_str("that").unwrap()).unwrap(), Some(result.hash));
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_build_with_labels() {
+    use crate::image_manager::ConsolePrinter;
+    use crate::reference::Reference;
+    use crate::image_manager::details::state::StateManager;
+
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+    let config = ImageManagerConfig::with_base_folder(tmp_folder.owned());
+
+    let printer = ConsolePrinter::new();
+    let state_manager = StateManager::new(&config.base_folder()).unwrap();
+    let layer_manager = LayerManager::new(config.clone());
+    let build_manager = BuildManager::new(config, printer);
+    let mut session = state_manager.session().unwrap();
+
+    let definition_path = Path::new("testdata/definitions/simple5.labarfile");
+    let mut labels = vec![("owner".to_owned(), "data-team".to_owned())];
+    labels.extend(provenance_labels(definition_path, Path::new(""), &[("A".to_owned(), "1".to_owned())]).unwrap());
+
+    let result = build_manager.build_image(
+        &mut session,
+        &layer_manager,
+        BuildRequest {
+            build_context: Path::new("").to_path_buf(),
+            image_definition: ImageDefinition::parse_file_without_context(definition_path).unwrap(),
+            tag: ImageTag::from_str("test").unwrap(),
+            force: false,
+            verbose_output: false,
+            print: true,
+            build_time: None,
+            labels,
+            additional_tags: Vec::new(),
+            tag_latest: None,
+            stage_tags: Vec::new()
+        }
+    );
+    assert!(result.is_ok());
+    let result = result.unwrap();
+    assert_eq!(3, result.layers.len());
+
+    let top_layer = layer_manager.get_layer(&session, &Reference::from_str("test").unwrap()).unwrap();
+    let mut top_labels = Vec::new();
+    top_layer.visit_labels(|key, value| top_labels.push((key.to_owned(), value.to_owned())));
+
+    assert!(top_labels.contains(&("owner".to_owned(), "data-team".to_owned())));
+    assert!(top_labels.contains(&("labar.build.arguments".to_owned(), "A=1".to_owned())));
+    assert!(top_labels.contains(&("labar.version".to_owned(), env!("CARGO_PKG_VERSION").to_owned())));
+    assert!(top_labels.contains(&("labar.labarfile.hash".to_owned(), compute_content_hash(definition_path).unwrap())));
+    assert!(!top_labels.iter().any(|(key, _)| key == "labar.build.host"));
+}
+
+#[test]
+fn test_build_steps_and_events() {
+    use std::sync::{Arc, Mutex};
+
+    use crate::image_manager::printing::Printer;
+    use crate::image_manager::test_helpers;
+    use crate::image_manager::details::state::StateManager;
+
+    struct EventPrinter {
+        events: Mutex<Vec<BuildEvent>>
+    }
+
+    impl Printer for EventPrinter {
+        fn println(&self, _line: &str) {}
+        fn refresh_latest_line(&self, _line: &str) {}
+
+        fn event(&self, event: &BuildEvent) {
+            self.events.lock().unwrap().push(event.clone());
+        }
+    }
+
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+    let config = ImageManagerConfig::with_base_folder(tmp_folder.owned());
+
+    let printer = Arc::new(EventPrinter { events: Mutex::new(Vec::new()) });
+    let state_manager = StateManager::new(config.base_folder()).unwrap();
+    let layer_manager = LayerManager::new(config.clone());
+    let build_manager = BuildManager::new(config, printer.clone());
+    let mut session = state_manager.session().unwrap();
+
+    let first_result = test_helpers::build_image2(
+        &mut session,
+        &layer_manager,
+        &build_manager,
+        Path::new("testdata/definitions/simple1.labarfile"),
+        ImageTag::from_str("test").unwrap(),
+        false
+    ).unwrap();
+    assert_eq!(1, first_result.steps.len());
+    assert_eq!("COPY testdata/rawdata/file1.txt file1.txt", first_result.steps[0].input_line);
+    assert_eq!(first_result.image.hash, first_result.steps[0].hash);
+    assert!(!first_result.steps[0].cached);
+    assert_eq!(DataSize(973), first_result.steps[0].bytes_copied);
+
+    let second_result = test_helpers::build_image2(
+        &mut session,
+        &layer_manager,
+        &build_manager,
+        Path::new("testdata/definitions/simple1.labarfile"),
+        ImageTag::from_str("test").unwrap(),
+        false
+    ).unwrap();
+    assert_eq!(1, second_result.steps.len());
+    assert!(second_result.steps[0].cached);
+    assert_eq!(DataSize(0), second_result.steps[0].bytes_copied);
+
+    let events = printer.events.lock().unwrap();
+    assert_eq!(6, events.len());
+    assert!(matches!(&events[0], BuildEvent::StepStarted { step: 1, num_steps: 1, .. }));
+    assert!(matches!(&events[1], BuildEvent::StepFinished { result, .. } if !result.cached));
+    assert!(matches!(&events[2], BuildEvent::BuildFinished { built_layers: 1, .. }));
+    assert!(matches!(&events[4], BuildEvent::StepFinished { result, .. } if result.cached));
+    assert!(matches!(&events[5], BuildEvent::BuildFinished { built_layers: 0, .. }));
+
+    let json = serde_json::to_value(&events[1]).unwrap();
+    assert_eq!("step_finished", json["event"]);
+    assert_eq!(973, json["bytes_copied"]);
+}
+
+#[test]
+fn test_build_without_latest() {
+    use crate::image_manager::{test_helpers, ConsolePrinter};
+    use crate::image_manager::details::state::StateManager;
+
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+    let config = ImageManagerConfig::with_base_folder(tmp_folder.owned());
+
+    let printer = ConsolePrinter::new();
+    let state_manager = StateManager::new(config.base_folder()).unwrap();
+    let layer_manager = LayerManager::new(config.clone());
+    let build_manager = BuildManager::new(config, printer);
+    let mut session = state_manager.session().unwrap();
+
+    let first_result = test_helpers::build_image2(
+        &mut session,
+        &layer_manager,
+        &build_manager,
+        Path::new("testdata/definitions/simple1.labarfile"),
+        ImageTag::from_str("test:v1").unwrap(),
+        false
+    ).unwrap();
+
+    let second_result = build_manager.build_image(
+        &mut session,
+        &layer_manager,
+        BuildRequest {
+            build_context: Path::new("").to_path_buf(),
+            image_definition: ImageDefinition::parse_file_without_context(Path::new("testdata/definitions/simple3.labarfile")).unwrap(),
+            tag: ImageTag::from_str("test:v2").unwrap(),
+            force: false,
+            verbose_output: false,
+            print: true,
+            build_time: None,
+            labels: Vec::new(),
+            additional_tags: vec![ImageTag::from_str("other:v2").unwrap()],
+            tag_latest: Some(false),
+            stage_tags: Vec::new()
+        }
+    ).unwrap();
+    assert_ne!(first_result.image.hash, second_result.image.hash);
+
+    let get_image_hash = |tag: &str| layer_manager.get_image_hash(&session, &ImageTag::from_str(tag).unwrap()).unwrap();
+    assert_eq!(Some(first_result.image.hash.clone()), get_image_hash("test:latest"));
+    assert_eq!(Some(second_result.image.hash.clone()), get_image_hash("test:v2"));
+    assert_eq!(Some(second_result.image.hash.clone()), get_image_hash("other:v2"));
+    assert_eq!(None, get_image_hash("other:latest"));
+}
+
+#[test]
+fn test_build_without_latest_config() {
+    use crate::image_manager::{test_helpers, ConsolePrinter};
+    use crate::image_manager::details::state::StateManager;
+
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+    let mut config = ImageManagerConfig::with_base_folder(tmp_folder.owned());
+    config.tag_latest = false;
+
+    let printer = ConsolePrinter::new();
+    let state_manager = StateManager::new(config.base_folder()).unwrap();
+    let layer_manager = LayerManager::new(config.clone());
+    let build_manager = BuildManager::new(config, printer);
+    let mut session = state_manager.session().unwrap();
+
+    let result = test_helpers::build_image2(
+        &mut session,
+        &layer_manager,
+        &build_manager,
+        Path::new("testdata/definitions/simple1.labarfile"),
+        ImageTag::from_str("test:v1").unwrap(),
+        false
+    ).unwrap();
+
+    assert_eq!(Some(result.image.hash), layer_manager.get_image_hash(&session, &ImageTag::from_str("test:v1").unwrap()).unwrap());
+    assert_eq!(None, layer_manager.get_image_hash(&session, &ImageTag::from_str("test:latest").unwrap()).unwrap());
+}
+
+#[test]
+fn test_build_quota() {
+    use crate::image_manager::ConsolePrinter;
+    use crate::image_manager::details::state::StateManager;
+
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+    let mut config = ImageManagerConfig::with_base_folder(tmp_folder.owned());
+    config.max_layer_size = Some(DataSize(2000));
+    config.max_store_size = Some(DataSize(1500));
+
+    let printer = ConsolePrinter::new();
+    let state_manager = StateManager::new(config.base_folder()).unwrap();
+    let layer_manager = LayerManager::new(config.clone());
+    let build_manager = BuildManager::new(config.clone(), printer);
+    let mut session = state_manager.session().unwrap();
+
+    let build = |session: &mut StateSession, path: &str, tag: &str| {
+        build_manager.build_image(
+            session,
+            &layer_manager,
+            BuildRequest {
+                build_context: Path::new("").to_path_buf(),
+                image_definition: ImageDefinition::parse_file_without_context(Path::new(path)).unwrap(),
+                tag: ImageTag::from_str(tag).unwrap(),
+                force: false,
+                verbose_output: false,
+                print: true,
+                build_time: None,
+                labels: Vec::new(),
+                additional_tags: Vec::new(),
+                tag_latest: None,
+                stage_tags: Vec::new()
+            }
+        )
+    };
+
+    // file2.txt is 2028 bytes
+    let result = build(&mut session, "testdata/definitions/simple6.labarfile", "test1");
+    assert!(matches!(result, Err(ImageManagerError::QuotaExceeded { quota: Quota::LayerSize, size: DataSize(2028), limit: DataSize(2000) })));
+    assert_eq!(0, layer_manager.all_layers(&session).unwrap().len());
+
+    // file1.txt is 973 bytes
+    let result = build(&mut session, "testdata/definitions/simple1.labarfile", "test2");
+    assert!(result.is_ok());
+    assert_eq!(DataSize(973), layer_manager.total_storage_size(&session).unwrap());
+
+    let result = build(&mut session, "testdata/definitions/simple4.labarfile", "test3");
+    assert!(matches!(result, Err(ImageManagerError::QuotaExceeded { quota: Quota::LayerSize, size: DataSize(3001), .. })));
+
+    let result = build(&mut session, "testdata/definitions/simple3.labarfile", "test4");
+    assert!(matches!(result, Err(ImageManagerError::QuotaExceeded { quota: Quota::StoreSize, size: DataSize(1946), limit: DataSize(1500) })), "{:?}", result);
+
+    // The archive is rejected from the sizes of its entries, before it is extracted
+    let result = build(&mut session, "testdata/definitions/unpack_tar.labarfile", "test5");
+    assert!(matches!(result, Err(ImageManagerError::QuotaExceeded { quota: Quota::LayerSize, size: DataSize(4257), limit: DataSize(2000) })), "{:?}", result);
+}
+
+#[test]
+fn test_build_stages() {
+    use crate::image_manager::ConsolePrinter;
+    use crate::image_manager::details::state::StateManager;
+
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+    let config = ImageManagerConfig::with_base_folder(tmp_folder.owned());
+
+    let printer = ConsolePrinter::new();
+    let state_manager = StateManager::new(config.base_folder()).unwrap();
+    let layer_manager = LayerManager::new(config.clone());
+    let build_manager = BuildManager::new(config, printer);
+    let mut session = state_manager.session().unwrap();
+
+    let create_request = |stage_tags: Vec<(String, ImageTag)>| {
+        BuildRequest {
+            build_context: Path::new("").to_path_buf(),
+            image_definition: ImageDefinition::parse_file_without_context(Path::new("testdata/definitions/stages.labarfile")).unwrap(),
+            tag: ImageTag::from_str("test").unwrap(),
+            force: false,
+            verbose_output: false,
+            print: true,
+            build_time: None,
+            labels: Vec::new(),
+            additional_tags: Vec::new(),
+            tag_latest: None,
+            stage_tags
+        }
+    };
+
+    let first_result = build_manager.build_image(
+        &mut session,
+        &layer_manager,
+        create_request(vec![
+            ("files".to_owned(), ImageTag::from_str("files:v1").unwrap()),
+            ("unused".to_owned(), ImageTag::from_str("unused:v1").unwrap())
+        ])
+    ).unwrap();
+    assert_eq!(3, first_result.built_layers.len());
+    assert_eq!(vec!["files", "unused"], first_result.stages.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>());
+
+    let get_image_hash = |tag: &str| layer_manager.get_image_hash(&session, &ImageTag::from_str(tag).unwrap()).unwrap();
+    let files_hash = first_result.stages[0].1.clone();
+    assert_eq!(Some(files_hash.clone()), get_image_hash("files:v1"));
+    assert_eq!(Some(first_result.stages[1].1.clone()), get_image_hash("unused:v1"));
+    assert_eq!(Some(first_result.image.hash.clone()), get_image_hash("test:latest"));
+
+    let top_layer = layer_manager.get_layer(&session, &first_result.image.hash.clone().to_ref()).unwrap();
+    assert_eq!(Some(files_hash.clone()), top_layer.parent_hash);
+
+    // Only the stages needed by the image are built, reusing the previous layers
+    let second_result = build_manager.build_image(&mut session, &layer_manager, create_request(Vec::new())).unwrap();
+    assert_eq!(0, second_result.built_layers.len());
+    assert_eq!(vec![("files".to_owned(), files_hash)], second_result.stages);
+    assert_eq!(first_result.image.hash, second_result.image.hash);
+
+    let result = build_manager.build_image(
+        &mut session,
+        &layer_manager,
+        create_request(vec![("missing".to_owned(), ImageTag::from_str("missing:v1").unwrap())])
+    );
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_build_copy_from_image() {
+    use crate::image_manager::{test_helpers, ConsolePrinter};
+    use crate::image_manager::details::state::StateManager;
+
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+    let config = ImageManagerConfig::with_base_folder(tmp_folder.owned());
+
+    let printer = ConsolePrinter::new();
+    let state_manager = StateManager::new(config.base_folder()).unwrap();
+    let layer_manager = LayerManager::new(config.clone());
+    let build_manager = BuildManager::new(config.clone(), printer);
+    let mut session = state_manager.session().unwrap();
+
+    test_helpers::build_image2(
+        &mut session,
+        &layer_manager,
+        &build_manager,
+        Path::new("testdata/definitions/simple5.labarfile"),
+        ImageTag::from_str("source:v1").unwrap(),
+        false
+    ).unwrap();
+
+    let result = test_helpers::build_image2(
+        &mut session,
+        &layer_manager,
+        &build_manager,
+        Path::new("testdata/definitions/copy_from.labarfile"),
+        ImageTag::from_str("test").unwrap(),
+        false
+    ).unwrap();
+    assert_eq!(2, result.built_layers.len());
+    assert!(result.steps.iter().all(|step| step.bytes_copied == DataSize(0)));
+
+    let file_paths = |layer: &Layer| {
+        layer.operations
+            .iter()
+            .filter_map(|operation| match operation {
+                LayerOperation::File { path, .. } => Some(path.clone()),
+                _ => None
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let first_layer = layer_manager.get_layer(&session, &result.layers[0].clone().to_ref()).unwrap();
+    assert_eq!(vec!["sub/file1.txt", "sub/file2.txt"], file_paths(&first_layer));
+
+    let top_layer = layer_manager.get_layer(&session, &result.image.hash.clone().to_ref()).unwrap();
+    assert_eq!(vec!["file2.txt"], file_paths(&top_layer));
+
+    let source_path = top_layer.operations[0].source_path().unwrap();
+    assert!(source_path.starts_with(config.get_layer_folder(&top_layer.hash).strip_prefix(config.base_folder()).unwrap().to_str().unwrap()));
+    assert_eq!(
+        std::fs::read_to_string("testdata/rawdata/file2.txt").unwrap(),
+        std::fs::read_to_string(config.base_folder().join(source_path)).unwrap()
+    );
+
+    let result = test_helpers::build_image2(
+        &mut session,
+        &layer_manager,
+        &build_manager,
+        Path::new("testdata/definitions/copy_from_missing.labarfile"),
+        ImageTag::from_str("test").unwrap(),
+        false
+    );
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_build_unpack_archive() {
+    use crate::image_manager::{test_helpers, ConsolePrinter};
+    use crate::image_manager::details::state::StateManager;
+
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+    let config = ImageManagerConfig::with_base_folder(tmp_folder.owned());
+
+    let printer = ConsolePrinter::new();
+    let state_manager = StateManager::new(config.base_folder()).unwrap();
+    let layer_manager = LayerManager::new(config.clone());
+    let build_manager = BuildManager::new(config.clone(), printer);
+    let mut session = state_manager.session().unwrap();
+
+    let tar_result = test_helpers::build_image2(
+        &mut session,
+        &layer_manager,
+        &build_manager,
+        Path::new("testdata/definitions/unpack_tar.labarfile"),
+        ImageTag::from_str("test:tar").unwrap(),
+        false
+    ).unwrap();
+    assert_eq!(1, tar_result.built_layers.len());
+
+    let layer = layer_manager.get_layer(&session, &tar_result.image.hash.clone().to_ref()).unwrap();
+    assert_eq!(DataSize(4257), layer.storage_size);
+    let paths = layer.operations
+        .iter()
+        .map(|operation| match operation {
+            LayerOperation::Directory { path } => format!("dir:{}", path),
+            LayerOperation::File { path, .. } => format!("file:{}", path),
+            _ => String::new()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        vec![
+            "dir:vendor/dir1", "dir:vendor/dir2",
+            "file:vendor/dir1/file1.txt", "file:vendor/dir2/file2.txt", "file:vendor/file1.txt", "file:vendor/file2.txt"
+        ],
+        paths
+    );
+
+    let source_path = layer.operations[4].source_path().unwrap();
+    assert!(source_path.starts_with(config.get_layer_folder(&layer.hash).strip_prefix(config.base_folder()).unwrap().to_str().unwrap()));
+    assert_eq!(
+        std::fs::read_to_string("testdata/rawdata2/file1.txt").unwrap(),
+        std::fs::read_to_string(config.base_folder().join(source_path)).unwrap()
+    );
+
+    // The same entries in another archive format gives the same layer
+    let zip_result = test_helpers::build_image2(
+        &mut session,
+        &layer_manager,
+        &build_manager,
+        Path::new("testdata/definitions/unpack_zip.labarfile"),
+        ImageTag::from_str("test:zip").unwrap(),
+        false
+    ).unwrap();
+    assert_eq!(0, zip_result.built_layers.len());
+    assert_eq!(tar_result.image.hash, zip_result.image.hash);
+
+    // The staged files are removed once the build is done
+    let staging_folders = std::fs::read_dir(config.base_folder())
+        .unwrap()
+        .filter(|entry| entry.as_ref().unwrap().file_name().to_str().unwrap().starts_with("tmp-staging"))
+        .count();
+    assert_eq!(0, staging_folders);
+}
+
+#[test]
+fn test_reproducible_build_time_uses_utc() {
+    let build_time = reproducible_build_time(true).unwrap().unwrap();
+    assert_eq!(0, build_time.offset().local_minus_utc());
+    assert_eq!(0, build_time.timestamp());
+
+    let mut layer = Layer::new(None, ImageId::from_str("679447d45a6c8ed2dce1d106fd2ffbc61b96c3633ec3ae4ee20034055d7e0216").unwrap(), Vec::new(), DataSize(0));
+    layer.created = build_time;
+    let layer_json = serde_json::to_string(&layer).unwrap();
+    assert!(layer_json.contains("\"created\":\"1970-01-01T00:00:00Z\""));
+
+    // The offset survives storage, so that the layer serializes the same everywhere
+    let layer_json = layer_json.replace("1970-01-01T00:00:00Z", "1970-01-01T02:00:00+02:00");
+    let layer: Layer = serde_json::from_str(&layer_json).unwrap();
+    assert_eq!(7200, layer.created.offset().local_minus_utc());
+    assert_eq!(0, layer.created.timestamp());
+}
+
+#[test]
+fn test_build_empty() {
+    use crate::image_manager::ConsolePrinter;
+    use crate::image_manager::details::state::StateManager;
+
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+    let config = ImageManagerConfig::with_base_folder(tmp_folder.owned());
+
+    let state_manager = StateManager::new(config.base_folder()).unwrap();
+    let layer_manager = LayerManager::new(config.clone());
+    let build_manager = BuildManager::new(config.clone(), ConsolePrinter::new());
+    let mut session = state_manager.session().unwrap();
+
+    let result = build_manager.build_image(
+        &mut session,
+        &layer_manager,
+        BuildRequest {
+            build_context: Path::new("").to_path_buf(),
+            image_definition: ImageDefinition::new(None, Vec::new()),
+            tag: ImageTag::from_str("test").unwrap(),
+            force: false,
+            verbose_output: false,
+            print: false,
+            build_time: None,
+            labels: Vec::new(),
+            additional_tags: Vec::new(),
+            tag_latest: None,
+            stage_tags: Vec::new()
+        }
+    );
+    assert!(matches!(result, Err(ImageManagerError::ImageParser { error: ImageParseError::Other(_) })));
+}
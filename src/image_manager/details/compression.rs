@@ -39,7 +39,7 @@ impl CompressionManager {
         let mut compressed_operations = Vec::new();
         for (operation_index, operation) in layer.operations.iter().enumerate() {
             match operation {
-                LayerOperation::File { path, source_path, original_source_path, content_hash, link_type, writable } => {
+                LayerOperation::File { path, source_path, original_source_path, content_hash, link_type, writable, template } => {
                     let abs_source_path = self.config.base_folder().join(source_path);
 
                     if !always {
@@ -64,6 +64,7 @@ impl CompressionManager {
                             content_hash: content_hash.to_owned(),
                             link_type: *link_type,
                             writable: *writable,
+                            template: *template,
                             compressed_content_hash
                         }
                     ));
@@ -103,7 +104,7 @@ impl CompressionManager {
         let mut decompressed_operations = Vec::new();
         for (operation_index, operation) in layer.operations.iter().enumerate() {
             match operation {
-                LayerOperation::CompressedFile { path, source_path, original_source_path, content_hash, link_type, writable, .. } => {
+                LayerOperation::CompressedFile { path, source_path, original_source_path, content_hash, link_type, writable, template, .. } => {
                     let abs_source_path = self.config.base_folder.join(&source_path);
 
                     let temp_source_path = abs_source_path.to_str().unwrap().to_owned() + ".tmp";
@@ -120,7 +121,8 @@ impl CompressionManager {
                             original_source_path: original_source_path.to_owned(),
                             content_hash: content_hash.to_owned(),
                             link_type: *link_type,
-                            writable: *writable
+                            writable: *writable,
+                            template: *template
                         }
                     ));
                 }
@@ -169,7 +171,7 @@ impl CompressionManager {
                                 data_path: &Path,
                                 always: bool) -> ImageManagerResult<Option<(PathBuf, LayerOperation)>> {
         match operation {
-            LayerOperation::File { path, source_path, original_source_path, content_hash, link_type, writable } => {
+            LayerOperation::File { path, source_path, original_source_path, content_hash, link_type, writable, template } => {
                 if !always {
                     if DataSize::from_file(&data_path) < DataSize(1024) {
                         return Ok(None);
@@ -194,6 +196,7 @@ impl CompressionManager {
                             content_hash: content_hash.to_owned(),
                             link_type: *link_type,
                             writable: *writable,
+                            template: *template,
                             compressed_content_hash
                         }
                     ))
@@ -211,7 +214,7 @@ impl CompressionManager {
                                   operation: &LayerOperation,
                                   data_path: &Path) -> ImageManagerResult<Option<(PathBuf, LayerOperation)>> {
         match operation {
-            LayerOperation::CompressedFile { path, source_path, original_source_path, content_hash, link_type, writable, .. } => {
+            LayerOperation::CompressedFile { path, source_path, original_source_path, content_hash, link_type, writable, template, .. } => {
                 let temp_source_path = data_path.to_str().unwrap().to_owned() + ".tmp";
                 let temp_source_path = Path::new(&temp_source_path).to_path_buf();
 
@@ -228,7 +231,8 @@ impl CompressionManager {
                             original_source_path: original_source_path.to_owned(),
                             content_hash: content_hash.to_owned(),
                             link_type: *link_type,
-                            writable: *writable
+                            writable: *writable,
+                            template: *template
                         }
                     ))
                 )
@@ -0,0 +1,204 @@
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::content::compute_content_hash;
+use crate::image::{Layer, LayerOperation};
+use crate::image_manager::{ImageManagerConfig, ImageManagerResult};
+use crate::image_manager::details::layer::LayerManager;
+use crate::image_manager::details::state::StateSession;
+use crate::image_manager::printing::PrinterRef;
+use crate::reference::{ImageId, ImageTag};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsckIssue {
+    OrphanLayerFolder { path: PathBuf },
+    TemporaryFile { path: PathBuf },
+    MissingFile { layer: ImageId, path: String },
+    CorruptFile { layer: ImageId, path: String },
+    MissingLayer { layer: ImageId, reference: ImageId },
+    DanglingImage { tag: ImageTag, hash: ImageId },
+    StaleUnpacking { destination: String, hash: ImageId }
+}
+
+impl FsckIssue {
+    pub fn requires_registry(&self) -> bool {
+        match self {
+            FsckIssue::MissingFile { .. } | FsckIssue::CorruptFile { .. } | FsckIssue::MissingLayer { .. } => true,
+            FsckIssue::OrphanLayerFolder { .. } | FsckIssue::TemporaryFile { .. } | FsckIssue::DanglingImage { .. } | FsckIssue::StaleUnpacking { .. } => false
+        }
+    }
+}
+
+impl Display for FsckIssue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FsckIssue::OrphanLayerFolder { path } => {
+                write!(f, "The layer folder {} does not belong to any layer", path.display())
+            }
+            FsckIssue::TemporaryFile { path } => {
+                write!(f, "The temporary file {} was left behind", path.display())
+            }
+            FsckIssue::MissingFile { layer, path } => {
+                write!(f, "The file '{}' of layer {} is missing", path, layer)
+            }
+            FsckIssue::CorruptFile { layer, path } => {
+                write!(f, "The file '{}' of layer {} has invalid content", path, layer)
+            }
+            FsckIssue::MissingLayer { layer, reference } => {
+                write!(f, "The layer {} refers to the missing layer {}", layer, reference)
+            }
+            FsckIssue::DanglingImage { tag, hash } => {
+                write!(f, "The image {} refers to the missing layer {}", tag, hash)
+            }
+            FsckIssue::StaleUnpacking { destination, hash } => {
+                write!(f, "The unpacking of {} at {} is stale", hash, destination)
+            }
+        }
+    }
+}
+
+pub struct FsckManager {
+    config: ImageManagerConfig,
+    printer: PrinterRef,
+    /// Temporary folders changed after this can belong to a build or verify that is still running.
+    start_time: SystemTime
+}
+
+impl FsckManager {
+    pub fn new(config: ImageManagerConfig, printer: PrinterRef) -> FsckManager {
+        FsckManager {
+            config,
+            printer,
+            // File system timestamps are coarser than the clock, so recent folders can appear slightly older
+            start_time: SystemTime::now() - Duration::from_secs(1)
+        }
+    }
+
+    pub fn check(&self, session: &StateSession, layer_manager: &LayerManager) -> ImageManagerResult<Vec<FsckIssue>> {
+        let mut issues = Vec::new();
+
+        let layers = layer_manager.all_layers(session)?;
+        let layer_hashes = layers.iter().map(|layer| layer.hash.clone()).collect::<HashSet<_>>();
+
+        self.check_base_folder(&layer_hashes, &mut issues)?;
+
+        for layer in &layers {
+            layer.visit_image_ids(|image_id| {
+                if !layer_hashes.contains(image_id) {
+                    issues.push(FsckIssue::MissingLayer { layer: layer.hash.clone(), reference: image_id.clone() });
+                }
+            });
+
+            for operation in &layer.operations {
+                let (path, source_path, expected_content_hash) = match operation {
+                    LayerOperation::File { path, source_path, content_hash, .. } => (path, source_path, content_hash),
+                    LayerOperation::CompressedFile { path, source_path, compressed_content_hash, .. } => (path, source_path, compressed_content_hash),
+                    LayerOperation::Image { .. } => continue,
+                    LayerOperation::ImageAlias { .. } => continue,
+                    LayerOperation::Directory { .. } => continue,
+                    LayerOperation::Label { .. } => continue
+                };
+
+                let abs_source_path = self.config.base_folder().join(source_path);
+                if !abs_source_path.exists() {
+                    issues.push(FsckIssue::MissingFile { layer: layer.hash.clone(), path: path.clone() });
+                } else if compute_content_hash(&abs_source_path).ok().as_ref() != Some(expected_content_hash) {
+                    issues.push(FsckIssue::CorruptFile { layer: layer.hash.clone(), path: path.clone() });
+                }
+            }
+        }
+
+        for image in layer_manager.images_iter(session)? {
+            if !layer_hashes.contains(&image.hash) {
+                issues.push(FsckIssue::DanglingImage { tag: image.tag, hash: image.hash });
+            }
+        }
+
+        for unpacking in session.all_unpackings()? {
+            if !layer_hashes.contains(&unpacking.hash) || !Path::new(&unpacking.destination).exists() {
+                issues.push(FsckIssue::StaleUnpacking { destination: unpacking.destination, hash: unpacking.hash });
+            }
+        }
+
+        Ok(issues)
+    }
+
+    fn check_base_folder(&self, layer_hashes: &HashSet<ImageId>, issues: &mut Vec<FsckIssue>) -> ImageManagerResult<()> {
+        let layers_base_folder = self.config.layers_base_folder();
+        if layers_base_folder.exists() {
+            for entry in std::fs::read_dir(&layers_base_folder)? {
+                let entry = entry?;
+                let is_layer = entry.file_name().to_str()
+                    .and_then(|name| name.parse::<ImageId>().ok())
+                    .map(|hash| layer_hashes.contains(&hash))
+                    .unwrap_or(false);
+
+                if !is_layer {
+                    issues.push(FsckIssue::OrphanLayerFolder { path: entry.path() });
+                }
+            }
+        }
+
+        if self.config.base_folder().exists() {
+            for entry in std::fs::read_dir(self.config.base_folder())? {
+                let entry = entry?;
+                let name = entry.file_name().to_str().unwrap_or("").to_owned();
+                if name == "tmp-download" {
+                    for download_entry in std::fs::read_dir(entry.path())? {
+                        issues.push(FsckIssue::TemporaryFile { path: download_entry?.path() });
+                    }
+                } else if name.starts_with("tmp-") && entry.path().is_dir() && entry.metadata()?.modified()? < self.start_time {
+                    issues.push(FsckIssue::TemporaryFile { path: entry.path() });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn remove_stored_file(&self, layer: &Layer, path: &str) -> ImageManagerResult<()> {
+        for operation in &layer.operations {
+            match operation {
+                LayerOperation::File { path: file_path, source_path, .. } | LayerOperation::CompressedFile { path: file_path, source_path, .. } if file_path == path => {
+                    let abs_source_path = self.config.base_folder().join(source_path);
+                    if abs_source_path.exists() {
+                        std::fs::remove_file(abs_source_path)?;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Repairs the issues that can be repaired without a registry. Returns true if the issue was repaired.
+    pub fn repair(&self, session: &mut StateSession, issue: &FsckIssue) -> ImageManagerResult<bool> {
+        match issue {
+            FsckIssue::OrphanLayerFolder { path } | FsckIssue::TemporaryFile { path } => {
+                if path.is_dir() {
+                    std::fs::remove_dir_all(path)?;
+                } else {
+                    std::fs::remove_file(path)?;
+                }
+
+                self.printer.println(&format!("Removed {}", path.display()));
+            }
+            FsckIssue::DanglingImage { tag, .. } => {
+                session.remove_image(tag)?;
+                self.printer.println(&format!("Removed image {}", tag));
+            }
+            FsckIssue::StaleUnpacking { destination, .. } => {
+                session.remove_unpacking(destination)?;
+                self.printer.println(&format!("Removed unpacking at {}", destination));
+            }
+            FsckIssue::CorruptFile { .. } | FsckIssue::MissingFile { .. } | FsckIssue::MissingLayer { .. } => {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+}
@@ -1,4 +1,5 @@
 use std::collections::{HashSet};
+use std::path::Path;
 
 use crate::helpers::DataSize;
 use crate::image_manager::{ImageManagerConfig, ImageManagerError, ImageManagerResult};
@@ -46,6 +47,11 @@ impl LayerManager {
         Ok(())
     }
 
+    pub fn insert_layers_with_image(&self, session: &mut StateSession, layers: &[Layer], image: &Image) -> ImageManagerResult<()> {
+        session.insert_layers_with_image(layers, image)?;
+        Ok(())
+    }
+
     pub fn remove_layer(&self, session: &StateSession, hash: &ImageId) -> ImageManagerResult<()> {
         let removed = session.remove_layer(hash)?;
         if !removed {
@@ -82,6 +88,48 @@ impl LayerManager {
         Ok(())
     }
 
+    /// Visits the directory and file operations of the image, starting with the top layer.
+    pub fn visit_operations<T, F: FnMut(&LayerOperation) -> Option<T>>(&self,
+                                                                       session: &StateSession,
+                                                                       reference: &Reference,
+                                                                       mut on_operation: F) -> ImageManagerResult<Option<T>> {
+        let mut stack = Vec::new();
+        stack.push(reference.clone());
+
+        while let Some(current) = stack.pop() {
+            let layer = self.get_layer(session, &current)?;
+            if let Some(parent_hash) = layer.parent_hash.as_ref() {
+                stack.push(parent_hash.clone().to_ref());
+            }
+
+            for operation in &layer.operations {
+                match operation {
+                    LayerOperation::Image { hash } | LayerOperation::ImageAlias { hash } => {
+                        stack.push(hash.clone().to_ref());
+                    }
+                    LayerOperation::Directory { .. } => {
+                        if let Some(result) = on_operation(operation) {
+                            return Ok(Some(result));
+                        }
+                    }
+                    LayerOperation::File { .. } => {
+                        if let Some(result) = on_operation(operation) {
+                            return Ok(Some(result));
+                        }
+                    }
+                    LayerOperation::CompressedFile { .. } => {
+                        if let Some(result) = on_operation(operation) {
+                            return Ok(Some(result));
+                        }
+                    }
+                    LayerOperation::Label { .. } => {}
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
     pub fn fully_qualify_reference(&self, session: &StateSession, reference: &Reference) -> ImageManagerResult<ImageId> {
         match reference {
             Reference::ImageTag(tag) => {
@@ -89,11 +137,24 @@ impl LayerManager {
                     return Ok(image_hash);
                 }
 
+                if let Some(prefix) = tag.image_id_prefix() {
+                    let mut candidates = session.find_layers_by_prefix(prefix)?;
+                    if candidates.len() == 1 {
+                        return Ok(candidates.remove(0));
+                    } else if candidates.len() > 1 {
+                        return Err(ImageManagerError::AmbiguousReference { prefix: prefix.to_owned(), candidates });
+                    }
+                }
+
                 Err(ImageManagerError::ReferenceNotFound { reference: reference.clone() })
             }
             Reference::ImageId(id) => {
                 Ok(id.clone())
             }
+            Reference::Stage(_) => {
+                // Stages are resolved to image ids before building, so they do not exist outside of a build
+                Err(ImageManagerError::ReferenceNotFound { reference: reference.clone() })
+            }
         }
     }
 
@@ -112,6 +173,20 @@ impl LayerManager {
         Ok(image.map(|image| image.hash.clone()))
     }
 
+    /// Checks that replacing the image does not move a pinned tag to another image, unless forced.
+    pub fn check_replace_pinned(&self, session: &StateSession, image: &Image, force: bool) -> ImageManagerResult<()> {
+        if !force && session.is_image_pinned(&image.tag)? {
+            match self.get_image_hash(session, &image.tag)? {
+                Some(hash) if hash != image.hash => {
+                    return Err(ImageManagerError::ImagePinned { tag: image.tag.clone() });
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn insert_or_replace_image(&self, session: &mut StateSession, image: Image) -> ImageManagerResult<()> {
         session.insert_or_replace_image(image)?;
         Ok(())
@@ -124,6 +199,10 @@ impl LayerManager {
 
     pub fn size_of_reference(&self, session: &StateSession, reference: &Reference, recursive: bool) -> ImageManagerResult<DataSize> {
         let layer = self.get_layer(session, reference)?;
+        self.size_of_layer(session, &layer, recursive)
+    }
+
+    pub fn size_of_layer(&self, session: &StateSession, layer: &Layer, recursive: bool) -> ImageManagerResult<DataSize> {
         let mut total_size = layer.storage_size;
 
         if recursive {
@@ -143,4 +222,33 @@ impl LayerManager {
 
         Ok(total_size)
     }
-}
\ No newline at end of file
+
+    pub fn total_storage_size(&self, session: &StateSession) -> ImageManagerResult<DataSize> {
+        Ok(session.total_storage_size()?)
+    }
+}
+
+/// Hard links a file that is already stored, so that the data is shared instead of copied.
+pub fn link_stored_file(abs_source_path: &Path, destination_path: &Path) -> ImageManagerResult<()> {
+    if abs_source_path == destination_path {
+        return Ok(());
+    }
+
+    #[allow(unused_must_use)] {
+        std::fs::remove_file(destination_path);
+    }
+
+    std::fs::hard_link(abs_source_path, destination_path)
+        .map_err(|err|
+            ImageManagerError::FileIOError {
+                message: format!(
+                    "Failed to link file {} -> {} due to: {}",
+                    abs_source_path.display(),
+                    destination_path.display(),
+                    err
+                )
+            }
+        )?;
+
+    Ok(())
+}
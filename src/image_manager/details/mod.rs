@@ -5,4 +5,7 @@ pub mod state;
 pub mod transfer;
 pub mod compression;
 pub mod storage;
-pub mod unpack;
\ No newline at end of file
+pub mod unpack;
+pub mod rewrite;
+pub mod fsck;
+pub mod retention;
@@ -0,0 +1,243 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::Duration;
+
+use chrono::{DateTime, Local};
+use regex::Regex;
+
+use crate::helpers::DataSize;
+use crate::image::Image;
+use crate::image_manager::ImageManagerResult;
+use crate::image_manager::details::layer::LayerManager;
+use crate::image_manager::details::state::StateSession;
+use crate::reference::{ImageId, ImageTag};
+
+#[derive(Debug, Clone, Default)]
+pub struct RetentionPolicy {
+    pub max_age: Option<Duration>,
+    pub max_unused: Option<Duration>,
+    pub keep_recent: Option<usize>,
+    pub keep_tags: Option<Regex>,
+    pub protect_label: Option<String>,
+    pub max_store_size: Option<DataSize>,
+    pub include_pinned: bool
+}
+
+#[derive(Debug, Clone)]
+pub struct PurgePlan {
+    pub images: Vec<ImageTag>,
+    pub layers: Vec<ImageId>,
+    pub reclaimed_size: DataSize
+}
+
+struct ImageCandidate {
+    image: Image,
+    created: DateTime<Local>,
+    last_used: DateTime<Local>,
+    protected: bool
+}
+
+pub struct RetentionManager;
+
+impl RetentionManager {
+    /// Decides which images the policy removes and which layers garbage collection would then free.
+    pub fn plan(&self,
+                session: &StateSession,
+                layer_manager: &LayerManager,
+                policy: &RetentionPolicy,
+                other_references: &[ImageId]) -> ImageManagerResult<PurgePlan> {
+        let mut candidates = Vec::new();
+        for image in layer_manager.images_iter(session)? {
+            let layer = layer_manager.get_layer(session, &image.hash.clone().to_ref())?;
+
+            let mut protected = policy.keep_tags.as_ref().map(|keep_tags| keep_tags.is_match(&image.tag.to_string())).unwrap_or(false);
+            if !policy.include_pinned {
+                protected |= session.is_image_pinned(&image.tag)?;
+            }
+
+            if let Some(protect_label) = policy.protect_label.as_ref() {
+                protected |= self.has_label(session, layer_manager, &image.hash, protect_label)?;
+            }
+
+            // Images used before the usage was tracked count as last used when created
+            let created = layer.created.with_timezone(&Local);
+            let last_used = session.get_last_used(&image.hash)?.unwrap_or(created);
+            candidates.push(ImageCandidate { image, created, last_used, protected });
+        }
+
+        // Newest first, so that the most recent tags of each repository are seen first
+        candidates.sort_by_key(|candidate| Reverse(candidate.created));
+
+        let now = Local::now();
+        let mut removed = HashSet::new();
+        let mut kept_per_repository = BTreeMap::<String, HashSet<ImageId>>::new();
+        for (index, candidate) in candidates.iter_mut().enumerate() {
+            if candidate.protected {
+                continue;
+            }
+
+            if let Some(keep_recent) = policy.keep_recent {
+                // Tags of the same image only count once
+                let kept = kept_per_repository.entry(candidate.image.tag.full_repository()).or_default();
+                if kept.contains(&candidate.image.hash) || kept.len() < keep_recent {
+                    kept.insert(candidate.image.hash.clone());
+                    candidate.protected = true;
+                    continue;
+                }
+            }
+
+            if let Some(max_age) = policy.max_age {
+                if (now - candidate.created).to_std().unwrap_or_default() > max_age {
+                    removed.insert(index);
+                }
+            }
+
+            if let Some(max_unused) = policy.max_unused {
+                if (now - candidate.last_used).to_std().unwrap_or_default() > max_unused {
+                    removed.insert(index);
+                }
+            }
+        }
+
+        if let Some(max_store_size) = policy.max_store_size {
+            let mut usage = LayerUsage::new(session, layer_manager, &candidates, &removed, other_references)?;
+
+            let mut eviction_order = (0..candidates.len()).collect::<Vec<_>>();
+            eviction_order.sort_by_key(|&index| candidates[index].last_used);
+
+            for index in eviction_order {
+                if usage.used_size <= max_store_size {
+                    break;
+                }
+
+                if !candidates[index].protected && removed.insert(index) {
+                    usage.remove(index);
+                }
+            }
+        }
+
+        let used_layers = self.used_layers(session, layer_manager, &candidates, &removed, other_references)?;
+        let mut plan = PurgePlan { images: Vec::new(), layers: Vec::new(), reclaimed_size: DataSize(0) };
+        for layer in layer_manager.all_layers(session)? {
+            if !used_layers.contains(&layer.hash) {
+                plan.reclaimed_size += layer.storage_size;
+                plan.layers.push(layer.hash);
+            }
+        }
+
+        for (index, candidate) in candidates.into_iter().enumerate() {
+            if removed.contains(&index) {
+                plan.images.push(candidate.image.tag);
+            }
+        }
+        plan.images.sort();
+
+        Ok(plan)
+    }
+
+    fn has_label(&self, session: &StateSession, layer_manager: &LayerManager, hash: &ImageId, protect_label: &str) -> ImageManagerResult<bool> {
+        let (key, value) = match protect_label.split_once('=') {
+            Some((key, value)) => (key, Some(value)),
+            None => (protect_label, None)
+        };
+
+        let mut found = false;
+        let mut current = Some(hash.clone());
+        while let Some(hash) = current {
+            let layer = layer_manager.get_layer(session, &hash.to_ref())?;
+            layer.visit_labels(|label_key, label_value| {
+                if label_key == key && value.map(|value| value == label_value).unwrap_or(true) {
+                    found = true;
+                }
+            });
+
+            current = layer.parent_hash;
+        }
+
+        Ok(found)
+    }
+
+    fn used_layers(&self,
+                   session: &StateSession,
+                   layer_manager: &LayerManager,
+                   candidates: &[ImageCandidate],
+                   removed: &HashSet<usize>,
+                   other_references: &[ImageId]) -> ImageManagerResult<HashSet<ImageId>> {
+        let mut used_layers = HashSet::new();
+        for (index, candidate) in candidates.iter().enumerate() {
+            if !removed.contains(&index) {
+                layer_manager.find_used_layers(session, &candidate.image.hash, &mut used_layers)?;
+            }
+        }
+
+        for hash in other_references {
+            layer_manager.find_used_layers(session, hash, &mut used_layers)?;
+        }
+
+        Ok(used_layers)
+    }
+}
+
+/// Tracks the size of the layers used by the kept images, so that it can be updated as images are removed.
+struct LayerUsage {
+    candidate_layers: Vec<HashSet<ImageId>>,
+    layer_references: HashMap<ImageId, usize>,
+    layer_sizes: HashMap<ImageId, DataSize>,
+    used_size: DataSize
+}
+
+impl LayerUsage {
+    fn new(session: &StateSession,
+           layer_manager: &LayerManager,
+           candidates: &[ImageCandidate],
+           removed: &HashSet<usize>,
+           other_references: &[ImageId]) -> ImageManagerResult<LayerUsage> {
+        let mut usage = LayerUsage {
+            candidate_layers: Vec::new(),
+            layer_references: HashMap::new(),
+            layer_sizes: HashMap::new(),
+            used_size: DataSize(0)
+        };
+
+        for (index, candidate) in candidates.iter().enumerate() {
+            let mut layers = HashSet::new();
+            layer_manager.find_used_layers(session, &candidate.image.hash, &mut layers)?;
+            if !removed.contains(&index) {
+                usage.add_references(&layers);
+            }
+
+            usage.candidate_layers.push(layers);
+        }
+
+        for hash in other_references {
+            let mut layers = HashSet::new();
+            layer_manager.find_used_layers(session, hash, &mut layers)?;
+            usage.add_references(&layers);
+        }
+
+        for hash in usage.layer_references.keys() {
+            let storage_size = layer_manager.get_layer(session, &hash.clone().to_ref())?.storage_size;
+            usage.used_size += storage_size;
+            usage.layer_sizes.insert(hash.clone(), storage_size);
+        }
+
+        Ok(usage)
+    }
+
+    fn add_references(&mut self, layers: &HashSet<ImageId>) {
+        for hash in layers {
+            *self.layer_references.entry(hash.clone()).or_default() += 1;
+        }
+    }
+
+    /// Removes the references of the candidate, where layers no longer used by any image stop counting.
+    fn remove(&mut self, index: usize) {
+        for hash in &self.candidate_layers[index] {
+            let references = self.layer_references.get_mut(hash).unwrap();
+            *references -= 1;
+            if *references == 0 {
+                self.used_size = DataSize(self.used_size.0 - self.layer_sizes[hash].0);
+            }
+        }
+    }
+}
@@ -0,0 +1,289 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::path::Path;
+
+use crate::helpers::DataSize;
+use crate::image::{Image, Layer, LayerOperation};
+use crate::image_manager::details::build::LayerHash;
+use crate::image_manager::details::layer::{link_stored_file, LayerManager};
+use crate::image_manager::details::state::StateSession;
+use crate::image_manager::printing::PrinterRef;
+use crate::image_manager::{ImageManagerConfig, ImageManagerError, ImageManagerResult};
+use crate::reference::{ImageId, ImageTag, Reference};
+
+pub struct RewriteManager {
+    config: ImageManagerConfig,
+    printer: PrinterRef
+}
+
+impl RewriteManager {
+    pub fn new(config: ImageManagerConfig, printer: PrinterRef) -> RewriteManager {
+        RewriteManager {
+            config,
+            printer
+        }
+    }
+
+    pub fn squash(&self,
+                  session: &mut StateSession,
+                  layer_manager: &LayerManager,
+                  request: SquashRequest) -> ImageManagerResult<SquashResult> {
+        let mut chain = Vec::new();
+        let mut current = Some(layer_manager.fully_qualify_reference(session, &request.reference)?);
+        while let Some(hash) = current {
+            let layer = layer_manager.get_layer(session, &hash.to_ref())?;
+            current = layer.parent_hash.clone();
+            chain.push(layer);
+        }
+
+        let num_layers = request.layers.unwrap_or(chain.len()).min(chain.len());
+        if num_layers == 0 {
+            return Err(ImageManagerError::OtherError { message: "At least one layer must be squashed".to_owned() });
+        }
+
+        let parent_hash = chain[num_layers - 1].parent_hash.clone();
+
+        let mut squashed = SquashedOperations::new();
+        let mut already_visited = HashSet::new();
+        for layer in chain[..num_layers].iter().rev() {
+            self.squash_operations(session, layer_manager, &mut already_visited, layer, &mut squashed)?;
+        }
+
+        let (operations, storage_size) = squashed.finalize(&self.config);
+        let hash = LayerHash::from_operations(parent_hash.as_ref(), &operations);
+        let mut layer = Layer::new(parent_hash, hash, operations, storage_size);
+
+        self.printer.println(&format!(
+            "Squashed {} layers of {} into {}",
+            num_layers,
+            request.reference,
+            layer.hash
+        ));
+
+        let mut new_layers = Vec::new();
+        if !layer_manager.layer_exist(session, &layer.hash)? {
+            self.link_layer_files(&mut layer)?;
+            new_layers.push(layer.clone());
+        }
+
+        let image = Image::new(layer.hash.clone(), request.tag);
+        layer_manager.insert_layers_with_image(session, &new_layers, &image)?;
+
+        Ok(
+            SquashResult {
+                image,
+                squashed_layers: num_layers
+            }
+        )
+    }
+
+    pub fn rebase(&self,
+                  session: &mut StateSession,
+                  layer_manager: &LayerManager,
+                  request: RebaseRequest) -> ImageManagerResult<RebaseResult> {
+        let from_hash = layer_manager.fully_qualify_reference(session, &request.from)?;
+        let onto_hash = layer_manager.fully_qualify_reference(session, &request.onto)?;
+        if !layer_manager.layer_exist(session, &onto_hash)? {
+            return Err(ImageManagerError::ReferenceNotFound { reference: request.onto.clone() });
+        }
+
+        let mut layers_to_rebase = Vec::new();
+        let mut current = Some(layer_manager.fully_qualify_reference(session, &request.reference)?);
+        loop {
+            match current {
+                Some(hash) if hash == from_hash => {
+                    break;
+                }
+                Some(hash) => {
+                    let layer = layer_manager.get_layer(session, &hash.to_ref())?;
+                    current = layer.parent_hash.clone();
+                    layers_to_rebase.push(layer);
+                }
+                None => {
+                    return Err(ImageManagerError::NotBasedOn { reference: request.reference.clone(), base: from_hash });
+                }
+            }
+        }
+
+        self.printer.println(&format!(
+            "Rebasing {} layers of {} from {} onto {}",
+            layers_to_rebase.len(),
+            request.reference,
+            from_hash,
+            onto_hash
+        ));
+
+        let mut parent_hash = onto_hash;
+        let mut rebased_layers = Vec::new();
+        let mut new_layers = Vec::new();
+        for layer in layers_to_rebase.into_iter().rev() {
+            if !layer.verify_valid_paths(self.config.base_folder()) {
+                return Err(ImageManagerError::InvalidRebase);
+            }
+
+            let hash = LayerHash::from_operations(Some(&parent_hash), &layer.operations);
+            let mut new_layer = Layer::new(Some(parent_hash), hash, layer.operations, layer.storage_size);
+            self.printer.println(&format!("\t* Layer {} -> {}", layer.hash, new_layer.hash));
+
+            if !layer_manager.layer_exist(session, &new_layer.hash)? {
+                self.link_layer_files(&mut new_layer)?;
+                if !new_layer.verify_valid_paths(self.config.base_folder()) {
+                    return Err(ImageManagerError::InvalidRebase);
+                }
+
+                new_layers.push(new_layer.clone());
+            }
+
+            rebased_layers.push(new_layer.hash.clone());
+            parent_hash = new_layer.hash;
+        }
+
+        let image = Image::new(parent_hash, request.tag);
+        layer_manager.insert_layers_with_image(session, &new_layers, &image)?;
+
+        Ok(
+            RebaseResult {
+                image,
+                rebased_layers
+            }
+        )
+    }
+
+    fn squash_operations(&self,
+                         session: &StateSession,
+                         layer_manager: &LayerManager,
+                         already_visited: &mut HashSet<ImageId>,
+                         layer: &Layer,
+                         squashed: &mut SquashedOperations) -> ImageManagerResult<()> {
+        if already_visited.contains(&layer.hash) {
+            return Err(ImageManagerError::SelfReferential);
+        }
+
+        already_visited.insert(layer.hash.clone());
+
+        for operation in &layer.operations {
+            match operation {
+                LayerOperation::Image { hash } | LayerOperation::ImageAlias { hash } => {
+                    // Referenced images are applied with their full chain, just like when unpacking
+                    let mut chain = Vec::new();
+                    let mut current = Some(hash.clone());
+                    while let Some(hash) = current {
+                        let layer = layer_manager.get_layer(session, &Reference::ImageId(hash))?;
+                        current = layer.parent_hash.clone();
+                        chain.push(layer);
+                    }
+
+                    for referenced_layer in chain.iter().rev() {
+                        self.squash_operations(session, layer_manager, already_visited, referenced_layer, squashed)?;
+                    }
+                }
+                LayerOperation::Directory { path } => {
+                    squashed.directories.insert(path.clone());
+                }
+                LayerOperation::File { path, .. } | LayerOperation::CompressedFile { path, .. } => {
+                    squashed.files.insert(path.clone(), operation.clone());
+                }
+                LayerOperation::Label { key_values } => {
+                    for (key, value) in key_values {
+                        squashed.labels.insert(key.clone(), value.clone());
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Hard links the stored files of the given layer into its own layer folder, sharing the data with the original layers.
+    fn link_layer_files(&self, layer: &mut Layer) -> ImageManagerResult<()> {
+        let destination_base_path = self.config.get_layer_folder(&layer.hash);
+        std::fs::create_dir_all(&destination_base_path)?;
+
+        for operation in &mut layer.operations {
+            match operation {
+                LayerOperation::File { source_path, .. } | LayerOperation::CompressedFile { source_path, .. } => {
+                    let abs_source_path = self.config.base_folder().join(&source_path);
+                    let file_name = Path::new(source_path.as_str()).file_name()
+                        .ok_or_else(|| ImageManagerError::FileIOError { message: format!("Invalid source path: {}", source_path) })?;
+
+                    let destination_path = destination_base_path.join(file_name);
+                    link_stored_file(&abs_source_path, &destination_path)?;
+
+                    let relative_destination_path = destination_path.strip_prefix(self.config.base_folder()).unwrap();
+                    *source_path = relative_destination_path.to_str().unwrap().to_owned();
+                }
+                LayerOperation::Image { .. } => {}
+                LayerOperation::ImageAlias { .. } => {}
+                LayerOperation::Directory { .. } => {}
+                LayerOperation::Label { .. } => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+struct SquashedOperations {
+    directories: BTreeSet<String>,
+    files: BTreeMap<String, LayerOperation>,
+    labels: BTreeMap<String, String>
+}
+
+impl SquashedOperations {
+    fn new() -> SquashedOperations {
+        SquashedOperations {
+            directories: BTreeSet::new(),
+            files: BTreeMap::new(),
+            labels: BTreeMap::new()
+        }
+    }
+
+    fn finalize(self, config: &ImageManagerConfig) -> (Vec<LayerOperation>, DataSize) {
+        let mut operations = Vec::new();
+        let mut storage_size = DataSize(0);
+
+        for path in self.directories {
+            operations.push(LayerOperation::Directory { path });
+        }
+
+        for (_, operation) in self.files {
+            if let Some(source_path) = operation.source_path() {
+                storage_size += DataSize::from_file(&config.base_folder().join(source_path));
+            }
+
+            operations.push(operation);
+        }
+
+        if !self.labels.is_empty() {
+            operations.push(LayerOperation::Label { key_values: self.labels.into_iter().collect() });
+        }
+
+        (operations, storage_size)
+    }
+}
+
+#[derive(Debug)]
+pub struct SquashRequest {
+    pub reference: Reference,
+    pub tag: ImageTag,
+    pub layers: Option<usize>
+}
+
+#[derive(Debug)]
+pub struct SquashResult {
+    pub image: Image,
+    pub squashed_layers: usize
+}
+
+#[derive(Debug)]
+pub struct RebaseRequest {
+    pub reference: Reference,
+    pub from: Reference,
+    pub onto: Reference,
+    pub tag: ImageTag
+}
+
+#[derive(Debug)]
+pub struct RebaseResult {
+    pub image: Image,
+    pub rebased_layers: Vec<ImageId>
+}
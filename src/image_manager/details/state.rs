@@ -2,11 +2,12 @@ use std::path::{Path, PathBuf};
 use std::sync::{Arc};
 
 use chrono::{DateTime, Local};
-use rusqlite::{Connection, OptionalExtension};
+use rusqlite::{Connection, OpenFlags, OptionalExtension, MAIN_DB};
+use rusqlite::backup::Progress;
 
-use crate::helpers::{PooledResource, ResourcePool};
-use crate::image::{Image, Layer};
-use crate::image_manager::ImageManagerResult;
+use crate::helpers::{DataSize, PooledResource, ResourcePool};
+use crate::image::{Image, Layer, LayerOperation};
+use crate::image_manager::{ImageManagerError, ImageManagerResult};
 use crate::image_manager::details::unpack::Unpacking;
 use crate::reference::{ImageId, ImageTag};
 
@@ -18,110 +19,39 @@ pub struct StateManager {
 }
 
 impl StateManager {
-    pub fn new(base_folder: &Path) -> SqlResult<StateManager> {
-        if !base_folder.exists() {
-            std::fs::create_dir_all(base_folder).map_err(|_| rusqlite::Error::InvalidPath(base_folder.to_path_buf()))?;
-        }
-
-        let connection = StateManager::open_connection(base_folder)?;
-        connection.execute(
-            r#"
-            CREATE TABLE IF NOT EXISTS layers(
-                hash TEXT PRIMARY KEY,
-                metadata JSONB
-            );
-            "#,
-            ()
-        )?;
+    pub fn new(base_folder: &Path) -> ImageManagerResult<StateManager> {
+        StateManager::create_base_folder(base_folder)?;
 
-        connection.execute(
-            r#"
-            CREATE TABLE IF NOT EXISTS images(
-                tag TEXT PRIMARY KEY,
-                hash TEXT,
-                FOREIGN KEY(hash) REFERENCES layers(hash) ON DELETE CASCADE
-            );
-            "#,
-            ()
-        )?;
-
-        connection.execute(
-            r#"
-            CREATE TABLE IF NOT EXISTS unpackings(
-                destination TEXT PRIMARY KEY,
-                hash TEXT,
-                time TIMESTAMPTZ,
-                FOREIGN KEY(hash) REFERENCES layers(hash) ON DELETE RESTRICT
-            );
-            "#,
-            ()
-        )?;
-
-        connection.execute(
-            r#"
-            CREATE TABLE IF NOT EXISTS logins(
-                registry TEXT PRIMARY KEY,
-                token TEXT
-            );
-            "#,
-            ()
-        )?;
-
-        connection.execute(
-            r#"
-            CREATE TABLE IF NOT EXISTS content_hash_cache(
-                file TEXT,
-                modified INTEGER,
-                hash TEXT,
-                PRIMARY KEY (file, modified)
-            );
-            "#,
-            ()
-        )?;
-
-        connection.execute(
-            r#"
-            CREATE TABLE IF NOT EXISTS registry_pending_layer_uploads(
-                hash TEXT PRIMARY KEY,
-                layer_metadata JSONB,
-                last_updated TIMESTAMPTZ,
-                upload_id TEXT,
-                state TEXT
-            );
-            "#,
-            ()
-        )?;
-
-        connection.execute(
-            r#"
-            CREATE INDEX IF NOT EXISTS index_registry_pending_layer_uploads_upload_id ON registry_pending_layer_uploads(upload_id);
-            "#,
-            ()
-        )?;
-
-        connection.execute(
-            r#"
-            CREATE TABLE IF NOT EXISTS registry_users(
-                username TEXT PRIMARY KEY,
-                password TEXT NOT NULL,
-                access_rights JSONB NOT NULL
-            );
-            "#,
-            ()
-        )?;
+        let mut connection = StateManager::open_connection(base_folder)?;
+        migrate_connection(&mut connection, base_folder)?;
 
         Ok(
             StateManager {
                 base_folder: base_folder.to_path_buf(),
-                pool: Arc::new(ResourcePool::new(vec![StateSession { connection }]))
+                pool: Arc::new(ResourcePool::new(vec![StateSession { connection, base_folder: base_folder.to_path_buf() }]))
             }
         )
     }
 
+    pub fn migrate(base_folder: &Path) -> ImageManagerResult<StateMigration> {
+        StateManager::create_base_folder(base_folder)?;
+        let mut connection = StateManager::open_connection(base_folder)?;
+        migrate_connection(&mut connection, base_folder)
+    }
+
+    fn create_base_folder(base_folder: &Path) -> SqlResult<()> {
+        if !base_folder.exists() {
+            std::fs::create_dir_all(base_folder).map_err(|_| rusqlite::Error::InvalidPath(base_folder.to_path_buf()))?;
+        }
+
+        Ok(())
+    }
+
     pub fn session(&self) -> SqlResult<StateSession> {
         Ok(
             StateSession {
-                connection: StateManager::open_connection(&self.base_folder)?
+                connection: StateManager::open_connection(&self.base_folder)?,
+                base_folder: self.base_folder.clone()
             }
         )
     }
@@ -141,8 +71,162 @@ impl StateManager {
 
 pub const STATE_FILENAME: &str = "state.sqlite3";
 
+type Migration = fn(&Connection, &Path) -> SqlResult<()>;
+
+const MIGRATIONS: &[Migration] = &[
+    migrate_initial_schema,
+    migrate_files_table,
+    migrate_layer_usage_table,
+    migrate_pinned_images_table
+];
+
+fn migrate_initial_schema(connection: &Connection, _base_folder: &Path) -> SqlResult<()> {
+    connection.execute_batch(
+        r#"
+        CREATE TABLE IF NOT EXISTS layers(
+            hash TEXT PRIMARY KEY,
+            metadata JSONB
+        );
+
+        CREATE TABLE IF NOT EXISTS images(
+            tag TEXT PRIMARY KEY,
+            hash TEXT,
+            FOREIGN KEY(hash) REFERENCES layers(hash) ON DELETE CASCADE
+        );
+
+        CREATE TABLE IF NOT EXISTS unpackings(
+            destination TEXT PRIMARY KEY,
+            hash TEXT,
+            time TIMESTAMPTZ,
+            FOREIGN KEY(hash) REFERENCES layers(hash) ON DELETE RESTRICT
+        );
+
+        CREATE TABLE IF NOT EXISTS logins(
+            registry TEXT PRIMARY KEY,
+            token TEXT
+        );
+
+        CREATE TABLE IF NOT EXISTS content_hash_cache(
+            file TEXT,
+            modified INTEGER,
+            hash TEXT,
+            PRIMARY KEY (file, modified)
+        );
+
+        CREATE TABLE IF NOT EXISTS registry_pending_layer_uploads(
+            hash TEXT PRIMARY KEY,
+            layer_metadata JSONB,
+            last_updated TIMESTAMPTZ,
+            upload_id TEXT,
+            state TEXT
+        );
+
+        CREATE INDEX IF NOT EXISTS index_registry_pending_layer_uploads_upload_id ON registry_pending_layer_uploads(upload_id);
+
+        CREATE TABLE IF NOT EXISTS registry_users(
+            username TEXT PRIMARY KEY,
+            password TEXT NOT NULL,
+            access_rights JSONB NOT NULL
+        );
+        "#
+    )
+}
+
+fn migrate_files_table(connection: &Connection, base_folder: &Path) -> SqlResult<()> {
+    connection.execute_batch(
+        r#"
+        CREATE TABLE IF NOT EXISTS files(
+            layer TEXT,
+            path TEXT,
+            content_hash TEXT,
+            size INTEGER,
+            PRIMARY KEY (layer, path),
+            FOREIGN KEY(layer) REFERENCES layers(hash) ON DELETE CASCADE
+        );
+
+        CREATE INDEX IF NOT EXISTS index_files_path ON files(path);
+        CREATE INDEX IF NOT EXISTS index_files_content_hash ON files(content_hash);
+        "#
+    )?;
+
+    let layers = {
+        let mut statement = connection.prepare("SELECT metadata FROM layers")?;
+        let layers = statement.query_map([], |row| row.get::<_, Layer>(0))?.collect::<SqlResult<Vec<_>>>()?;
+        layers
+    };
+
+    for layer in &layers {
+        StateSession::insert_layer_files(connection, base_folder, layer)?;
+    }
+
+    Ok(())
+}
+
+fn migrate_layer_usage_table(connection: &Connection, _base_folder: &Path) -> SqlResult<()> {
+    connection.execute_batch(
+        r#"
+        CREATE TABLE IF NOT EXISTS layer_usage(
+            hash TEXT PRIMARY KEY,
+            last_used TIMESTAMPTZ,
+            FOREIGN KEY(hash) REFERENCES layers(hash) ON DELETE CASCADE
+        );
+        "#
+    )
+}
+
+fn migrate_pinned_images_table(connection: &Connection, _base_folder: &Path) -> SqlResult<()> {
+    // Not a foreign key, as a pin should survive the tag being replaced
+    connection.execute_batch(
+        r#"
+        CREATE TABLE IF NOT EXISTS pinned_images(
+            tag TEXT PRIMARY KEY
+        );
+        "#
+    )
+}
+
+pub const STATE_VERSION: u32 = MIGRATIONS.len() as u32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StateMigration {
+    pub from_version: u32,
+    pub to_version: u32
+}
+
+fn migrate_connection(connection: &mut Connection, base_folder: &Path) -> ImageManagerResult<StateMigration> {
+    let from_version = state_version(connection)?;
+    if from_version > STATE_VERSION {
+        return Err(ImageManagerError::StateVersionNotSupported { version: from_version, supported: STATE_VERSION });
+    }
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(from_version as usize) {
+        let transaction = connection.transaction()?;
+        migration(&transaction, base_folder)?;
+        transaction.pragma_update(None, "user_version", index as u32 + 1)?;
+        transaction.commit()?;
+    }
+
+    Ok(
+        StateMigration {
+            from_version,
+            to_version: STATE_VERSION
+        }
+    )
+}
+
+fn state_version(connection: &Connection) -> SqlResult<u32> {
+    connection.query_row("PRAGMA user_version", (), |row| row.get(0))
+}
+
+/// Returns the version of the state database in the given file, without migrating it.
+pub fn state_file_version(path: &Path) -> SqlResult<u32> {
+    let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    state_version(&connection)
+}
+
 pub struct StateSession {
-    pub connection: Connection
+    pub connection: Connection,
+    base_folder: PathBuf
 }
 
 impl StateSession {
@@ -182,6 +266,18 @@ impl StateSession {
         Ok(count as usize)
     }
 
+    /// The sum of the storage sizes of all layers. Files that are shared between layers through hard links, such as the
+    /// ones copied from other images, are counted once per layer, so this can be larger than the actual disk usage.
+    pub fn total_storage_size(&self) -> SqlResult<DataSize> {
+        let size = self.connection.query_one(
+            "SELECT COALESCE(SUM(json_extract(metadata, '$.storage_size')), 0) FROM layers",
+            [],
+            |row| row.get::<_, i64>(0)
+        )?;
+
+        Ok(DataSize(size as usize))
+    }
+
     pub fn all_layers(&self) -> SqlResult<Vec<Layer>> {
         let mut statement = self.connection.prepare("SELECT metadata FROM layers")?;
 
@@ -201,6 +297,13 @@ impl StateSession {
         ).optional()
     }
 
+    pub fn find_layers_by_prefix(&self, prefix: &str) -> SqlResult<Vec<ImageId>> {
+        // The prefix only contains hex digits, so it can not contain any wildcards
+        let mut statement = self.connection.prepare("SELECT hash FROM layers WHERE hash GLOB ?1 ORDER BY hash")?;
+        let hashes = statement.query_map([format!("{}*", prefix)], |row| row.get(0))?.collect::<SqlResult<Vec<_>>>()?;
+        Ok(hashes)
+    }
+
     pub fn layer_exists(&self, hash: &ImageId) -> SqlResult<bool> {
         StateSession::layer_exists_internal(&self.connection, hash)
     }
@@ -216,7 +319,10 @@ impl StateSession {
     }
 
     pub fn insert_layer(&self, layer: &Layer) -> SqlResult<()> {
-        StateSession::insert_layer_internal(&self.connection, layer)
+        let transaction = self.connection.unchecked_transaction()?;
+        StateSession::insert_layer_internal(&transaction, &self.base_folder, layer)?;
+        transaction.commit()?;
+        Ok(())
     }
 
     pub fn insert_or_replace_layer(&mut self, layer: &Layer) -> SqlResult<()> {
@@ -227,27 +333,99 @@ impl StateSession {
                 "UPDATE layers set metadata=?2 WHERE hash=?1",
                 (&layer.hash, &serde_json::to_value(&layer).unwrap())
             )?;
+
+            transaction.execute("DELETE FROM files WHERE layer=?1", (&layer.hash, ))?;
+            StateSession::insert_layer_files(&transaction, &self.base_folder, layer)?;
         } else {
-            StateSession::insert_layer_internal(&transaction, layer)?;
+            StateSession::insert_layer_internal(&transaction, &self.base_folder, layer)?;
+        }
+
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Inserts the layers together with the image, so that nothing is inserted if any of them fails.
+    pub fn insert_layers_with_image(&mut self, layers: &[Layer], image: &Image) -> SqlResult<()> {
+        let transaction = self.connection.transaction()?;
+        for layer in layers {
+            StateSession::insert_layer_internal(&transaction, &self.base_folder, layer)?;
         }
 
+        transaction.execute("REPLACE INTO images (tag, hash) VALUES (?1, ?2)", (&image.tag, &image.hash))?;
         transaction.commit()?;
         Ok(())
     }
 
-    fn insert_layer_internal(connection: &Connection, layer: &Layer) -> SqlResult<()> {
+    fn insert_layer_internal(connection: &Connection, base_folder: &Path, layer: &Layer) -> SqlResult<()> {
         connection.execute(
             "INSERT INTO layers (hash, metadata) VALUES (?1, ?2)",
             (&layer.hash, &serde_json::to_value(&layer).unwrap())
         )?;
+
+        StateSession::insert_layer_files(connection, base_folder, layer)?;
+        Ok(())
+    }
+
+    fn insert_layer_files(connection: &Connection, base_folder: &Path, layer: &Layer) -> SqlResult<()> {
+        for operation in &layer.operations {
+            match operation {
+                LayerOperation::File { path, source_path, content_hash, .. } | LayerOperation::CompressedFile { path, source_path, content_hash, .. } => {
+                    connection.execute(
+                        "REPLACE INTO files (layer, path, content_hash, size) VALUES (?1, ?2, ?3, ?4)",
+                        (&layer.hash, path, content_hash, DataSize::from_file(&base_folder.join(source_path)).0)
+                    )?;
+                }
+                LayerOperation::Image { .. } => {}
+                LayerOperation::ImageAlias { .. } => {}
+                LayerOperation::Directory { .. } => {}
+                LayerOperation::Label { .. } => {}
+            }
+        }
+
         Ok(())
     }
 
+    pub fn find_files_by_path(&self, pattern: &str) -> SqlResult<Vec<FileEntry>> {
+        self.find_files("SELECT layer, path, content_hash, size FROM files WHERE path GLOB ?1 ORDER BY path, layer", pattern)
+    }
+
+    pub fn find_files_by_content_hash(&self, content_hash: &str) -> SqlResult<Vec<FileEntry>> {
+        self.find_files("SELECT layer, path, content_hash, size FROM files WHERE content_hash=?1 ORDER BY path, layer", content_hash)
+    }
+
+    fn find_files(&self, query: &str, parameter: &str) -> SqlResult<Vec<FileEntry>> {
+        let mut statement = self.connection.prepare(query)?;
+
+        let mut files = Vec::new();
+        for file in statement.query_map([parameter], FileEntry::from_row)? {
+            files.push(file?);
+        }
+
+        Ok(files)
+    }
+
     pub fn remove_layer(&self, hash: &ImageId) -> SqlResult<bool> {
         let removed = self.connection.execute("DELETE FROM layers WHERE hash=?1", (&hash, ))? > 0;
         Ok(removed)
     }
 
+    pub fn mark_layers_used(&self, hashes: &[ImageId], time: DateTime<Local>) -> SqlResult<()> {
+        let transaction = self.connection.unchecked_transaction()?;
+        for hash in hashes {
+            transaction.execute("REPLACE INTO layer_usage (hash, last_used) VALUES (?1, ?2)", (hash, &time))?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    pub fn get_last_used(&self, hash: &ImageId) -> SqlResult<Option<DateTime<Local>>> {
+        self.connection.query_row(
+            "SELECT last_used FROM layer_usage WHERE hash=?1",
+            [hash],
+            |row| row.get(0)
+        ).optional()
+    }
+
     pub fn all_images(&self) -> SqlResult<Vec<Image>> {
         let mut statement = self.connection.prepare("SELECT hash, tag FROM images")?;
 
@@ -305,6 +483,26 @@ impl StateSession {
         Ok(image)
     }
 
+    pub fn pin_image(&self, tag: &ImageTag) -> SqlResult<()> {
+        self.connection.execute("INSERT OR IGNORE INTO pinned_images (tag) VALUES (?1)", (tag, ))?;
+        Ok(())
+    }
+
+    pub fn unpin_image(&self, tag: &ImageTag) -> SqlResult<bool> {
+        let removed = self.connection.execute("DELETE FROM pinned_images WHERE tag=?1", (tag, ))? > 0;
+        Ok(removed)
+    }
+
+    pub fn is_image_pinned(&self, tag: &ImageTag) -> SqlResult<bool> {
+        let count = self.connection.query_one(
+            "SELECT COUNT(*) FROM pinned_images WHERE tag=?1",
+            [tag],
+            |row| row.get::<_, i64>(0)
+        )?;
+
+        Ok(count > 0)
+    }
+
     pub fn all_unpackings(&self) -> SqlResult<Vec<Unpacking>> {
         let mut statement = self.connection.prepare("SELECT destination, hash, time FROM unpackings")?;
 
@@ -420,7 +618,7 @@ impl StateSession {
             return Ok(false);
         }
 
-        StateSession::insert_layer_internal(&transaction, &layer)?;
+        StateSession::insert_layer_internal(&transaction, &self.base_folder, &layer)?;
 
         transaction.commit()?;
         Ok(true)
@@ -430,6 +628,84 @@ impl StateSession {
         let count = self.connection.execute("DELETE FROM registry_pending_layer_uploads WHERE upload_id=?1", (&upload_id, ))?;
         Ok(count != 1)
     }
+
+    /// Writes a consistent snapshot of the database to the given file, using the online backup API.
+    pub fn backup(&self, path: &Path) -> SqlResult<()> {
+        self.connection.backup(MAIN_DB, path, None)
+    }
+
+    pub fn restore(&mut self, path: &Path) -> SqlResult<()> {
+        self.connection.restore(MAIN_DB, path, None::<fn(Progress)>)
+    }
+}
+
+pub type PooledStateSession = PooledResource<StateSession>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry {
+    pub layer: ImageId,
+    pub path: String,
+    pub content_hash: String,
+    pub size: DataSize
+}
+
+impl FileEntry {
+    fn from_row(row: &rusqlite::Row) -> SqlResult<FileEntry> {
+        Ok(
+            FileEntry {
+                layer: row.get(0)?,
+                path: row.get(1)?,
+                content_hash: row.get(2)?,
+                size: DataSize(row.get(3)?)
+            }
+        )
+    }
+}
+
+#[test]
+fn test_migrate_new_state() {
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+
+    let migration = StateManager::migrate(&tmp_folder).unwrap();
+    assert_eq!(StateMigration { from_version: 0, to_version: STATE_VERSION }, migration);
+
+    let migration = StateManager::migrate(&tmp_folder).unwrap();
+    assert_eq!(StateMigration { from_version: STATE_VERSION, to_version: STATE_VERSION }, migration);
+
+    let state_manager = StateManager::new(&tmp_folder).unwrap();
+    assert_eq!(0, state_manager.pooled_session().unwrap().number_of_layers().unwrap());
+}
+
+#[test]
+fn test_migrate_unversioned_state() {
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+
+    {
+        StateManager::create_base_folder(&tmp_folder).unwrap();
+        let connection = StateManager::open_connection(&tmp_folder).unwrap();
+        connection.execute("CREATE TABLE layers(hash TEXT PRIMARY KEY, metadata JSONB);", ()).unwrap();
+    }
+
+    let migration = StateManager::migrate(&tmp_folder).unwrap();
+    assert_eq!(StateMigration { from_version: 0, to_version: STATE_VERSION }, migration);
 }
 
-pub type PooledStateSession = PooledResource<StateSession>;
\ No newline at end of file
+#[test]
+fn test_migrate_newer_state() {
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+
+    {
+        StateManager::create_base_folder(&tmp_folder).unwrap();
+        let connection = StateManager::open_connection(&tmp_folder).unwrap();
+        connection.pragma_update(None, "user_version", STATE_VERSION + 1).unwrap();
+    }
+
+    let result = StateManager::new(&tmp_folder);
+    match result {
+        Err(ImageManagerError::StateVersionNotSupported { version, supported }) => {
+            assert_eq!(STATE_VERSION + 1, version);
+            assert_eq!(STATE_VERSION, supported);
+        }
+        _ => panic!("Expected StateVersionNotSupported")
+    }
+}
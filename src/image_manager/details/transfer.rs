@@ -9,6 +9,7 @@ use zip::{ZipArchive, ZipWriter};
 use crate::image::{Image, Layer, LayerOperation};
 use crate::image_manager::{ImageManagerConfig, ImageManagerError, ImageManagerResult, PrinterRef, StateSession};
 use crate::image_manager::details::layer::LayerManager;
+use crate::image_manager::details::state::{state_file_version, STATE_FILENAME, STATE_VERSION};
 use crate::reference::{ImageId, ImageTag};
 
 pub struct TransferManager {
@@ -152,6 +153,102 @@ impl TransferManager {
 
         Ok(import_result)
     }
+
+    pub fn backup(&self,
+                  session: &StateSession,
+                  layer_manager: &LayerManager,
+                  path: &Path,
+                  include_layers: bool) -> ImageManagerResult<()> {
+        let file = File::create(path)
+            .map_err(|err| ImageManagerError::FileIOError { message: format!("Failed to create backup file due to: {}", err) })?;
+        let mut writer = ZipWriter::new(file);
+
+        let snapshot = tempfile::Builder::new().prefix("tmp-backup").tempfile_in(self.config.base_folder())?;
+        session.backup(snapshot.path())?;
+
+        writer.start_file(STATE_FILENAME, SimpleFileOptions::default())?;
+        std::io::copy(&mut BufReader::new(File::open(snapshot.path())?), &mut writer)?;
+
+        if include_layers {
+            let layers = layer_manager.all_layers(session)?;
+            for layer in &layers {
+                for operation in &layer.operations {
+                    match operation {
+                        LayerOperation::File { source_path, .. } | LayerOperation::CompressedFile { source_path, .. } => {
+                            let abs_source_path = self.config.base_folder.join(source_path);
+                            let mut reader = BufReader::new(File::open(&abs_source_path)?);
+
+                            writer.start_file_from_path(source_path, SimpleFileOptions::default())?;
+                            std::io::copy(&mut reader, &mut writer)?;
+                        }
+                        LayerOperation::Image { .. } => {}
+                        LayerOperation::ImageAlias { .. } => {}
+                        LayerOperation::Directory { .. } => {}
+                        LayerOperation::Label { .. } => {}
+                    }
+                }
+            }
+
+            self.printer.println(&format!("Backed up the state and {} layers.", layers.len()));
+        } else {
+            self.printer.println("Backed up the state.");
+        }
+
+        writer.finish()?;
+        Ok(())
+    }
+
+    pub fn restore(&self,
+                   session: &mut StateSession,
+                   layer_manager: &LayerManager,
+                   path: &Path) -> ImageManagerResult<()> {
+        let file = File::open(path)
+            .map_err(|err| ImageManagerError::FileIOError { message: format!("Failed to open backup file due to: {}", err) })?;
+        let mut archive = ZipArchive::new(BufReader::new(file))?;
+
+        if archive.index_for_name(STATE_FILENAME).is_none() {
+            return Err(ImageManagerError::InvalidBackup);
+        }
+
+        // A backup from a newer version can not be migrated, so it must not replace the current state
+        let snapshot = tempfile::Builder::new().prefix("tmp-restore").tempfile_in(self.config.base_folder())?;
+        std::io::copy(&mut archive.by_name(STATE_FILENAME)?, &mut snapshot.as_file())?;
+        let version = state_file_version(snapshot.path()).map_err(|_| ImageManagerError::InvalidBackup)?;
+        if version > STATE_VERSION {
+            return Err(ImageManagerError::StateVersionNotSupported { version, supported: STATE_VERSION });
+        }
+
+        // The layer files are restored before the state so that the state never refers to missing files
+        let mut restored_files = 0;
+        for index in 0..archive.len() {
+            let mut archive_file = archive.by_index(index)?;
+            if archive_file.name() == STATE_FILENAME {
+                continue;
+            }
+
+            let relative_path = archive_file.enclosed_name()
+                .filter(|relative_path| relative_path.starts_with("layers"))
+                .ok_or(ImageManagerError::InvalidBackup)?;
+
+            let abs_path = self.config.base_folder.join(relative_path);
+            if let Some(parent) = abs_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+
+            let mut file = File::create(&abs_path)?;
+            std::io::copy(&mut archive_file, &mut file)?;
+            restored_files += 1;
+        }
+
+        session.restore(snapshot.path())?;
+
+        for layer in layer_manager.all_layers(session)? {
+            std::fs::create_dir_all(self.config.get_layer_folder(&layer.hash))?;
+        }
+
+        self.printer.println(&format!("Restored the state and {} layer files.", restored_files));
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -238,4 +335,163 @@ fn test_export_import() {
 
         assert!(layer_manager.get_image(&session, &ImageTag::from_str("test").unwrap()).is_ok());
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_backup_restore() {
+    use std::str::FromStr;
+
+    use crate::content::compute_content_hash;
+    use crate::reference::ImageTag;
+    use crate::image_manager::details::build::BuildManager;
+    use crate::image_manager::{test_helpers, ImageManagerConfig};
+    use crate::image_manager::printing::{ConsolePrinter};
+    use crate::image_manager::details::state::StateManager;
+
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+    tmp_folder.create().unwrap();
+    let backup_file = tmp_folder.owned().join("backup.zip");
+
+    // Backup
+    let expected_image;
+    let expected_layers;
+    {
+        let tmp_env_folder = crate::test_helpers::TempFolder::new();
+        let config = ImageManagerConfig::with_base_folder(tmp_env_folder.owned().clone());
+
+        let printer = ConsolePrinter::new();
+        let state_manager = StateManager::new(config.base_folder()).unwrap();
+        let layer_manager = LayerManager::new(config.clone());
+        let build_manager = BuildManager::new(config.clone(), printer.clone());
+        let transfer_manager = TransferManager::new(config.clone(), printer.clone());
+        let mut session = state_manager.session().unwrap();
+
+        let build_result = test_helpers::build_image2(
+            &mut session,
+            &layer_manager,
+            &build_manager,
+            Path::new("testdata/definitions/simple5.labarfile"),
+            ImageTag::from_str("test").unwrap(),
+            false
+        ).unwrap();
+        expected_image = build_result.image;
+        expected_layers = build_result.layers;
+
+        let backup_result = transfer_manager.backup(&session, &layer_manager, &backup_file, true);
+        assert!(backup_result.is_ok(), "{}", backup_result.unwrap_err());
+        assert!(backup_file.exists());
+    }
+
+    // Restore
+    {
+        let tmp_env_folder = crate::test_helpers::TempFolder::new();
+        let config = ImageManagerConfig::with_base_folder(tmp_env_folder.owned().clone());
+
+        let printer = ConsolePrinter::new();
+        let state_manager = StateManager::new(config.base_folder()).unwrap();
+        let layer_manager = LayerManager::new(config.clone());
+        let transfer_manager = TransferManager::new(config.clone(), printer.clone());
+        let mut session = state_manager.session().unwrap();
+
+        let restore_result = transfer_manager.restore(&mut session, &layer_manager, &backup_file);
+        assert!(restore_result.is_ok(), "{}", restore_result.unwrap_err());
+
+        let image = layer_manager.get_image(&session, &ImageTag::from_str("test").unwrap()).unwrap();
+        assert_eq!(expected_image, image);
+
+        let mut layers = layer_manager.all_layers(&session).unwrap().into_iter().map(|layer| layer.hash).collect::<Vec<_>>();
+        let mut expected_layers = expected_layers.clone();
+        layers.sort();
+        expected_layers.sort();
+        assert_eq!(expected_layers, layers);
+
+        for layer in layer_manager.all_layers(&session).unwrap() {
+            assert!(config.get_layer_folder(&layer.hash).exists());
+            for operation in &layer.operations {
+                if let LayerOperation::File { source_path, content_hash, .. } = operation {
+                    assert_eq!(content_hash, &compute_content_hash(&config.base_folder().join(source_path)).unwrap());
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_restore_invalid_backup() {
+    use crate::image_manager::ImageManagerConfig;
+    use crate::image_manager::printing::{ConsolePrinter};
+    use crate::image_manager::details::state::StateManager;
+
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+    tmp_folder.create().unwrap();
+    let backup_file = tmp_folder.owned().join("backup.zip");
+
+    {
+        let mut writer = ZipWriter::new(File::create(&backup_file).unwrap());
+        writer.start_file("layers/test.txt", SimpleFileOptions::default()).unwrap();
+        writer.write_all(b"test").unwrap();
+        writer.finish().unwrap();
+    }
+
+    let tmp_env_folder = crate::test_helpers::TempFolder::new();
+    let config = ImageManagerConfig::with_base_folder(tmp_env_folder.owned().clone());
+
+    let state_manager = StateManager::new(config.base_folder()).unwrap();
+    let layer_manager = LayerManager::new(config.clone());
+    let transfer_manager = TransferManager::new(config.clone(), ConsolePrinter::new());
+    let mut session = state_manager.session().unwrap();
+
+    let restore_result = transfer_manager.restore(&mut session, &layer_manager, &backup_file);
+    assert!(matches!(restore_result, Err(ImageManagerError::InvalidBackup)));
+    assert!(!config.layers_base_folder().join("test.txt").exists());
+}
+
+#[test]
+fn test_restore_newer_backup() {
+    use std::str::FromStr;
+
+    use crate::reference::ImageTag;
+    use crate::image_manager::details::build::BuildManager;
+    use crate::image_manager::{test_helpers, ImageManagerConfig};
+    use crate::image_manager::printing::{ConsolePrinter};
+    use crate::image_manager::details::state::StateManager;
+
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+    tmp_folder.create().unwrap();
+    let backup_file = tmp_folder.owned().join("backup.zip");
+
+    {
+        let state_path = tmp_folder.owned().join(STATE_FILENAME);
+        let connection = rusqlite::Connection::open(&state_path).unwrap();
+        connection.pragma_update(None, "user_version", STATE_VERSION + 1).unwrap();
+        drop(connection);
+
+        let mut writer = ZipWriter::new(File::create(&backup_file).unwrap());
+        writer.start_file(STATE_FILENAME, SimpleFileOptions::default()).unwrap();
+        writer.write_all(&std::fs::read(&state_path).unwrap()).unwrap();
+        writer.finish().unwrap();
+    }
+
+    let tmp_env_folder = crate::test_helpers::TempFolder::new();
+    let config = ImageManagerConfig::with_base_folder(tmp_env_folder.owned().clone());
+
+    let printer = ConsolePrinter::new();
+    let state_manager = StateManager::new(config.base_folder()).unwrap();
+    let layer_manager = LayerManager::new(config.clone());
+    let build_manager = BuildManager::new(config.clone(), printer.clone());
+    let transfer_manager = TransferManager::new(config.clone(), printer.clone());
+    let mut session = state_manager.session().unwrap();
+
+    test_helpers::build_image2(
+        &mut session,
+        &layer_manager,
+        &build_manager,
+        Path::new("testdata/definitions/simple1.labarfile"),
+        ImageTag::from_str("test").unwrap(),
+        false
+    ).unwrap();
+
+    let restore_result = transfer_manager.restore(&mut session, &layer_manager, &backup_file);
+    assert!(matches!(restore_result, Err(ImageManagerError::StateVersionNotSupported { .. })));
+    assert!(layer_manager.get_image(&session, &ImageTag::from_str("test").unwrap()).is_ok());
+}
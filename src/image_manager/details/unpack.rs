@@ -1,12 +1,13 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fmt::{Display, Formatter};
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufReader, Read};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use chrono::{DateTime, Local};
 
 use itertools::izip;
+use regex::{Captures, Regex};
 use rusqlite::Row;
 
 use serde::{Deserialize, Serialize};
@@ -158,7 +159,8 @@ impl UnpackManager {
         for (request, top_layer, unpack_folder) in izip!(requests.iter(), top_layers.iter(), unpack_folders.iter()) {
             let unpack_folder_str = unpack_folder.to_str().unwrap().to_owned();
             self.printer.println(&format!("Unpacking {} ({}) to {}", &request.reference, top_layer.hash, unpack_folder_str));
-            self.unpack_layer(&session, unpacker, layer_manager, &mut HashSet::new(), &top_layer, &unpack_folder)?;
+            let target = UnpackTarget { folder: unpack_folder, variables: &request.variables };
+            self.unpack_layer(&session, unpacker, layer_manager, &mut HashSet::new(), &top_layer, &target)?;
 
             if unpacker.should_insert() {
                 session.insert_unpacking(Unpacking::new(&top_layer, &unpack_folder_str))?;
@@ -196,7 +198,8 @@ impl UnpackManager {
                     layer_manager: &LayerManager,
                     already_unpacked: &mut HashSet<ImageId>,
                     layer: &Layer,
-                    unpack_folder: &Path) -> ImageManagerResult<()> {
+                    target: &UnpackTarget) -> ImageManagerResult<()> {
+        let unpack_folder = target.folder;
         if already_unpacked.contains(&layer.hash) {
             return Err(ImageManagerError::SelfReferential);
         }
@@ -212,7 +215,7 @@ impl UnpackManager {
                 layer_manager,
                 already_unpacked,
                 &parent_layer,
-                &unpack_folder
+                target
             )?;
         }
 
@@ -226,14 +229,14 @@ impl UnpackManager {
                         layer_manager,
                         already_unpacked,
                         &layer_manager.get_layer(session, &Reference::ImageId(hash.clone()))?,
-                        &unpack_folder
+                        target
                     )?;
                 },
                 LayerOperation::Directory { path } => {
                     self.printer.println(&format!("\t* Creating directory {}", path));
                     unpacker.create_dir_all(&unpack_folder.join(path))?;
                 },
-                LayerOperation::File { path, source_path, link_type, writable, .. } => {
+                LayerOperation::File { path, source_path, link_type, writable, template, .. } => {
                     let abs_source_path = self.config.base_folder.canonicalize()?.join(source_path);
                     if abs_source_path != clean_path(&abs_source_path) {
                         return Err(ImageManagerError::InvalidUnpack);
@@ -255,20 +258,25 @@ impl UnpackManager {
                         unpacker.remove_file(&destination_path);
                     }
 
-                    match link_type {
-                        LinkType::Soft => {
-                            unpacker.create_soft_link(&abs_source_path, &destination_path)?;
-                        },
-                        LinkType::Hard => {
-                            unpacker.create_hard_link(&abs_source_path, &destination_path)?;
-                        },
+                    if *template {
+                        let template = std::fs::read_to_string(&abs_source_path)?;
+                        unpacker.write_file(&destination_path, &render_template(path, &template, target.variables)?)?;
+                    } else {
+                        match link_type {
+                            LinkType::Soft => {
+                                unpacker.create_soft_link(&abs_source_path, &destination_path)?;
+                            },
+                            LinkType::Hard => {
+                                unpacker.create_hard_link(&abs_source_path, &destination_path)?;
+                            }
+                        }
                     }
 
                     if !writable {
                         unpacker.set_readonly(&destination_path)?;
                     }
                 },
-                LayerOperation::CompressedFile { path, source_path, writable, .. } => {
+                LayerOperation::CompressedFile { path, source_path, writable, template, .. } => {
                     let abs_source_path = self.config.base_folder.canonicalize()?.join(source_path);
                     if abs_source_path != clean_path(&abs_source_path) {
                         return Err(ImageManagerError::InvalidUnpack);
@@ -290,7 +298,13 @@ impl UnpackManager {
                         unpacker.remove_file(&destination_path);
                     }
 
-                    unpacker.decompress_file(&abs_source_path, &destination_path)?;
+                    if *template {
+                        let mut template = String::new();
+                        GzDecoder::new(File::open(&abs_source_path)?).read_to_string(&mut template)?;
+                        unpacker.write_file(&destination_path, &render_template(path, &template, target.variables)?)?;
+                    } else {
+                        unpacker.decompress_file(&abs_source_path, &destination_path)?;
+                    }
 
                     if !writable {
                         unpacker.set_readonly(&destination_path)?;
@@ -307,6 +321,13 @@ impl UnpackManager {
         Ok(())
     }
 
+    pub fn get_unpacking(&self, session: &StateSession, unpack_folder: &Path) -> ImageManagerResult<Unpacking> {
+        let unpack_folder_str = unpack_folder.canonicalize()?.to_str().unwrap().to_owned();
+
+        session.get_unpacking(&unpack_folder_str)?
+            .ok_or_else(|| ImageManagerError::UnpackingNotFound { path: unpack_folder_str.clone() })
+    }
+
     pub fn remove_unpacking(&self,
                             session: &StateSession,
                             layer_manager: &LayerManager,
@@ -352,6 +373,11 @@ impl UnpackManager {
                     self.printer.println(&format!("\t* Deleting directory {}", path.to_str().unwrap()));
                     std::fs::remove_dir(path)?
                 },
+                LayerOperation::File { path, template: true, .. } => {
+                    let destination_path = unpack_folder.join(path);
+                    self.printer.println(&format!("\t* Deleting rendered file {}", destination_path.to_str().unwrap()));
+                    std::fs::remove_file(destination_path)?;
+                },
                 LayerOperation::File { path, .. } => {
                     let destination_path = unpack_folder.join(path);
                     self.printer.println(&format!("\t* Deleting link of file {}", destination_path.to_str().unwrap()));
@@ -435,7 +461,37 @@ pub struct UnpackRequest {
     pub reference: Reference,
     pub unpack_folder: PathBuf,
     pub replace: bool,
-    pub dry_run: bool
+    pub dry_run: bool,
+    /// The variables used for rendering templates
+    pub variables: HashMap<String, String>
+}
+
+struct UnpackTarget<'a> {
+    folder: &'a Path,
+    variables: &'a HashMap<String, String>
+}
+
+/// Replaces the `{{NAME}}` placeholders of the template with the values of the variables.
+pub fn render_template(path: &str, template: &str, variables: &HashMap<String, String>) -> ImageManagerResult<String> {
+    let regex = Regex::new(r"\{\{\s*([A-Za-z0-9_]+)\s*\}\}").map_err(ImageManagerError::Regex)?;
+
+    let mut missing_variable = None;
+    let rendered = regex.replace_all(template, |captures: &Captures| {
+        let name = &captures[1];
+        match variables.get(name) {
+            Some(value) => value.clone(),
+            None => {
+                missing_variable.get_or_insert_with(|| name.to_owned());
+                String::new()
+            }
+        }
+    });
+
+    if let Some(variable) = missing_variable {
+        return Err(ImageManagerError::TemplateVariableNotFound { path: path.to_owned(), variable });
+    }
+
+    Ok(rendered.into_owned())
 }
 
 impl UnpackRequest {
@@ -444,7 +500,8 @@ impl UnpackRequest {
             reference: tag.clone().to_ref(),
             unpack_folder: unpack_folder.to_owned(),
             replace: false,
-            dry_run: false
+            dry_run: false,
+            variables: HashMap::new()
         }
     }
 }
@@ -464,9 +521,12 @@ impl UnpackFile {
                 let unpack_folder = Path::new(&parts[1]).to_owned();
 
                 let mut replace = false;
-                if parts.len() == 3 {
-                    if parts[2] == "--replace" {
+                let mut variables = HashMap::new();
+                for part in parts.iter().skip(2) {
+                    if part == "--replace" {
                         replace = true;
+                    } else if let Some((key, value)) = part.split_once('=') {
+                        variables.insert(key.to_owned(), value.to_owned());
                     }
                 }
 
@@ -475,7 +535,8 @@ impl UnpackFile {
                         reference,
                         unpack_folder,
                         replace,
-                        dry_run
+                        dry_run,
+                        variables
                     }
                 );
             } else {
@@ -523,6 +584,7 @@ pub trait Unpacker {
     fn create_hard_link(&self, source: &Path, target: &Path) -> ImageManagerResult<()>;
     fn decompress_file(&self, source: &Path, target: &Path) -> ImageManagerResult<()>;
     fn set_readonly(&self, path: &Path) -> ImageManagerResult<()>;
+    fn write_file(&self, path: &Path, content: &str) -> ImageManagerResult<()>;
 }
 
 pub struct StandardUnpacker;
@@ -579,6 +641,11 @@ impl Unpacker for StandardUnpacker {
         file.set_permissions(permissions)?;
         Ok(())
     }
+
+    fn write_file(&self, path: &Path, content: &str) -> ImageManagerResult<()> {
+        std::fs::write(path, content)?;
+        Ok(())
+    }
 }
 
 pub struct DryRunUnpacker {
@@ -631,6 +698,11 @@ impl Unpacker for DryRunUnpacker {
         self.printer.println(&format!("\t\t* Setting {} to read only", path.display()));
         Ok(())
     }
+
+    fn write_file(&self, path: &Path, _content: &str) -> ImageManagerResult<()> {
+        self.printer.println(&format!("\t\t* Rendering template to {}", path.display()));
+        Ok(())
+    }
 }
 
 #[test]
@@ -670,6 +742,7 @@ fn test_unpack() {
             unpack_folder: tmp_folder.owned().join("unpack"),
             replace: false,
             dry_run: false,
+            variables: HashMap::new()
         }
     );
 
@@ -729,12 +802,14 @@ fn test_unpack_file() {
                     unpack_folder: tmp_folder.owned().join("unpack"),
                     replace: false,
                     dry_run: false,
+                    variables: HashMap::new()
                 },
                 UnpackRequest {
                     reference: Reference::from_str("test2").unwrap(),
                     unpack_folder: tmp_folder.owned().join("unpack2"),
                     replace: false,
                     dry_run: false,
+                    variables: HashMap::new()
                 }
             ],
         }
@@ -783,6 +858,7 @@ fn test_unpack_exist() {
             unpack_folder: tmp_folder.owned().join("unpack"),
             replace: false,
             dry_run: false,
+            variables: HashMap::new()
         }
     ).unwrap();
 
@@ -794,6 +870,7 @@ fn test_unpack_exist() {
             unpack_folder: tmp_folder.owned().join("unpack"),
             replace: false,
             dry_run: false,
+            variables: HashMap::new()
         }
     );
 
@@ -838,6 +915,7 @@ fn test_remove_unpack() {
             unpack_folder: tmp_folder.owned().join("unpack"),
             replace: false,
             dry_run: false,
+            variables: HashMap::new()
         }
     ).unwrap();
 
@@ -890,6 +968,7 @@ fn test_unpack_replace1() {
             unpack_folder: tmp_folder.owned().join("unpack"),
             replace: false,
             dry_run: false,
+            variables: HashMap::new()
         }
     ).unwrap();
 
@@ -901,6 +980,7 @@ fn test_unpack_replace1() {
             unpack_folder: tmp_folder.owned().join("unpack"),
             replace: true,
             dry_run: false,
+            variables: HashMap::new()
         }
     );
 
@@ -945,6 +1025,7 @@ fn test_unpack_replace2() {
             unpack_folder: tmp_folder.owned().join("unpack"),
             replace: true,
             dry_run: false,
+            variables: HashMap::new()
         }
     );
 
@@ -987,6 +1068,7 @@ fn test_unpack_self_reference() {
             unpack_folder: tmp_folder.owned().join("unpack"),
             replace: false,
             dry_run: false,
+            variables: HashMap::new()
         }
     );
 
@@ -1053,4 +1135,98 @@ fn test_parse_unpack_file1() {
     assert_eq!(Reference::from_str("test2:latest").unwrap(), unpack_file.requests[1].reference);
     assert_eq!(Path::new("/home/labar/test2").to_owned(), unpack_file.requests[1].unpack_folder);
     assert_eq!(true, unpack_file.requests[1].replace);
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_parse_unpack_file2() {
+    let content = std::fs::read_to_string("testdata/unpack_file/test2.unpackfile").unwrap();
+    let unpack_file = UnpackFile::parse(&content, false).unwrap();
+
+    assert_eq!(2, unpack_file.requests.len());
+
+    assert!(!unpack_file.requests[0].replace);
+    assert_eq!(Some("web1"), unpack_file.requests[0].variables.get("HOSTNAME").map(|value| value.as_str()));
+    assert_eq!(Some("8080"), unpack_file.requests[0].variables.get("PORT").map(|value| value.as_str()));
+
+    assert!(unpack_file.requests[1].replace);
+    assert_eq!(1, unpack_file.requests[1].variables.len());
+}
+
+#[test]
+fn test_unpack_template() {
+    use std::str::FromStr;
+
+    use crate::reference::ImageTag;
+    use crate::image_manager::details::build::BuildManager;
+    use crate::image_manager::{test_helpers, ImageManagerConfig};
+    use crate::image_manager::printing::{ConsolePrinter};
+    use crate::image_manager::details::state::StateManager;
+
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+    let config = ImageManagerConfig::with_base_folder(tmp_folder.owned());
+
+    let printer = ConsolePrinter::new();
+    let state_manager = StateManager::new(config.base_folder()).unwrap();
+    let layer_manager = LayerManager::new(config.clone());
+    let build_manager = BuildManager::new(config.clone(), printer.clone());
+    let unpack_manager = UnpackManager::new(config.clone(), printer.clone());
+    let mut session = state_manager.session().unwrap();
+
+    test_helpers::build_image2(
+        &mut session,
+        &layer_manager,
+        &build_manager,
+        Path::new("testdata/definitions/template.labarfile"),
+        ImageTag::from_str("test").unwrap(),
+        false
+    ).unwrap();
+
+    let unpack_result = unpack_manager.unpack(
+        &session,
+        &layer_manager,
+        UnpackRequest {
+            reference: Reference::from_str("test").unwrap(),
+            unpack_folder: tmp_folder.owned().join("unpack_missing"),
+            replace: false,
+            dry_run: false,
+            variables: HashMap::from([("HOSTNAME".to_owned(), "web1".to_owned())])
+        }
+    );
+    assert!(matches!(unpack_result, Err(ImageManagerError::TemplateVariableNotFound { variable, .. }) if variable == "PORT"));
+
+    unpack_manager.unpack(
+        &session,
+        &layer_manager,
+        UnpackRequest {
+            reference: Reference::from_str("test").unwrap(),
+            unpack_folder: tmp_folder.owned().join("unpack"),
+            replace: false,
+            dry_run: false,
+            variables: HashMap::from([("HOSTNAME".to_owned(), "web1".to_owned()), ("PORT".to_owned(), "8080".to_owned())])
+        }
+    ).unwrap();
+
+    let rendered_path = tmp_folder.owned().join("unpack").join("config").join("server.conf");
+    assert_eq!("host=web1\nport=8080\n", std::fs::read_to_string(&rendered_path).unwrap());
+    assert!(!rendered_path.is_symlink());
+    assert!(std::fs::metadata(&rendered_path).unwrap().permissions().readonly());
+
+    let result = unpack_manager.remove_unpacking(
+        &session,
+        &layer_manager,
+        &tmp_folder.owned().join("unpack"),
+        false
+    );
+    assert!(result.is_ok(), "{}", result.unwrap_err());
+    assert!(!rendered_path.exists());
+}
+
+#[test]
+fn test_template_flag_compatible() {
+    // Stored before the template flag existed
+    let operation: LayerOperation = serde_json::from_str(
+        r#"{"File":{"path":"a.txt","source_path":"","original_source_path":"","content_hash":"","link_type":"Hard","writable":false}}"#
+    ).unwrap();
+    assert!(matches!(operation, LayerOperation::File { template: false, .. }));
+    assert!(!serde_json::to_string(&operation).unwrap().contains("template"));
+}
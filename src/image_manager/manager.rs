@@ -1,25 +1,28 @@
 use std::cmp::Ordering;
-use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::ops::Deref;
 use std::path::{Path, PathBuf};
 use std::time::{Duration, Instant};
 
-use chrono::Local;
+use chrono::{DateTime, FixedOffset, Local};
 use regex::Regex;
 use tokio::runtime::Handle;
 
 use crate::content::compute_content_hash;
-use crate::image::{Image, ImageMetadata, Layer, LayerOperation};
-use crate::image_manager::{ImageManagerConfig, ImageManagerError, ImageManagerResult, RegistryError, StorageMode, UnpackFile};
-use crate::image_manager::details::layer::LayerManager;
+use crate::image::{Image, ImageMetadata, Layer, LayerOperation, LinkType};
+use crate::image_manager::{ImageManagerConfig, ImageManagerError, ImageManagerResult, Quota, RegistryError, StorageMode, UnpackFile};
+use crate::image_manager::details::layer::{link_stored_file, LayerManager};
 use crate::image_manager::details::unpack::{UnpackManager, UnpackRequest, Unpacking};
-use crate::image_manager::details::build::{BuildManager, BuildRequest, BuildResult};
+use crate::image_manager::details::build::{BuildManager, BuildOptions, BuildRequest, BuildResult};
 use crate::helpers::DataSize;
-use crate::image_definition::{ImageDefinition, LayerDefinition, LayerOperationDefinition};
+use crate::image_definition::{ImageDefinition, LayerDefinition, LayerGrouping, LayerOperationDefinition};
 use crate::image_manager::details::compression::CompressionManager;
-use crate::image_manager::printing::PrinterRef;
+use crate::image_manager::details::fsck::{FsckIssue, FsckManager};
+use crate::image_manager::details::retention::{PurgePlan, RetentionManager, RetentionPolicy};
+use crate::image_manager::printing::{EmptyPrinter, PrinterRef};
 use crate::image_manager::details::registry::{RegistryManager, RegistrySession};
-use crate::image_manager::details::state::{PooledStateSession, StateManager, StateSession, STATE_FILENAME};
+use crate::image_manager::details::rewrite::{RebaseRequest, RebaseResult, RewriteManager, SquashRequest, SquashResult};
+use crate::image_manager::details::state::{FileEntry, PooledStateSession, StateManager, StateMigration, StateSession, STATE_FILENAME};
 use crate::image_manager::details::storage::ArcImageStorage;
 use crate::image_manager::details::transfer::TransferManager;
 use crate::reference::{ImageId, ImageTag, Reference};
@@ -36,6 +39,9 @@ pub struct ImageManager {
     unpack_manager: UnpackManager,
     transfer_manager: TransferManager,
     compression_manager: CompressionManager,
+    rewrite_manager: RewriteManager,
+    fsck_manager: FsckManager,
+    retention_manager: RetentionManager,
     registry_manager: RegistryManager
 }
 
@@ -62,11 +68,19 @@ impl ImageManager {
                 unpack_manager: UnpackManager::new(config.clone(), printer.clone()),
                 transfer_manager: TransferManager::new(config.clone(), printer.clone()),
                 compression_manager: CompressionManager::new(config.clone(), printer.clone()),
+                rewrite_manager: RewriteManager::new(config.clone(), printer.clone()),
+                fsck_manager: FsckManager::new(config.clone(), printer.clone()),
+                retention_manager: RetentionManager,
                 registry_manager: RegistryManager::new(config.clone(), printer.clone(), image_storage),
             }
         )
     }
 
+    /// Migrates the state database of the given store without opening the rest of the image manager.
+    pub fn migrate_state(config: &ImageManagerConfig) -> ImageManagerResult<StateMigration> {
+        StateManager::migrate(config.base_folder())
+    }
+
     pub fn config(&self) -> &ImageManagerConfig {
         &self.config
     }
@@ -108,27 +122,133 @@ impl ImageManager {
     pub fn build_image(&mut self, request: BuildRequest) -> ImageManagerResult<BuildResult> {
         let mut session = self.state_manager.pooled_session()?;
 
-        let image = self.build_manager.build_image(&mut session, &mut self.layer_manager, request)?;
-        Ok(image)
+        let result = self.build_manager.build_image(&mut session, &mut self.layer_manager, request)?;
+        self.mark_used(&session, &result.image.hash);
+        Ok(result)
+    }
+
+    /// Rebuilds the given request into a temporary store and compares the manifests with the ones in this store.
+    /// Returns the layers that does not match.
+    pub fn verify_reproducible_build(&self, request: BuildRequest) -> ImageManagerResult<Vec<ImageId>> {
+        let session = self.state_manager.pooled_session()?;
+
+        // Inside the store, so that stored files can be hard linked into it
+        let tmp_folder = tempfile::Builder::new()
+            .prefix("tmp-verify")
+            .tempdir_in(self.config.base_folder())?;
+        let mut verify_image_manager = ImageManager::new(
+            ImageManagerConfig::with_base_folder(tmp_folder.path().to_owned()),
+            EmptyPrinter::new()
+        )?;
+
+        let references = request.image_definition.external_references();
+
+        // The referenced images are needed in the temporary store, with their files linked for COPY --from
+        let mut inserted_layers = HashSet::new();
+        for reference in references {
+            let hash = self.layer_manager.fully_qualify_reference(&session, &reference)?;
+
+            let mut stack = vec![hash.clone()];
+            while let Some(current) = stack.pop() {
+                if !inserted_layers.insert(current.clone()) {
+                    continue;
+                }
+
+                let layer = self.layer_manager.get_layer(&session, &current.to_ref())?;
+                layer.visit_image_ids(|hash| stack.push(hash.clone()));
+                stack.extend(layer.get_alias());
+
+                for source_path in layer.operations.iter().filter_map(|operation| operation.source_path()) {
+                    let destination_path = tmp_folder.path().join(source_path);
+                    if let Some(parent) = destination_path.parent() {
+                        std::fs::create_dir_all(parent)?;
+                    }
+
+                    link_stored_file(&self.config.base_folder().join(source_path), &destination_path)?;
+                }
+
+                verify_image_manager.insert_layer(layer)?;
+            }
+
+            if let Reference::ImageTag(tag) = reference {
+                verify_image_manager.insert_or_replace_image(Image::new(hash, tag))?;
+            }
+        }
+
+        let verify_result = verify_image_manager.build_image(
+            BuildRequest {
+                force: true,
+                print: false,
+                ..request
+            }
+        )?;
+
+        let mut mismatched_layers = Vec::new();
+        for hash in verify_result.layers {
+            let verify_layer = verify_image_manager.get_layer(&hash.clone().to_ref())?;
+            let matches = match self.layer_manager.get_layer(&session, &hash.clone().to_ref()) {
+                Ok(layer) => serde_json::to_vec(&layer)? == serde_json::to_vec(&verify_layer)?,
+                Err(_) => false
+            };
+
+            if !matches {
+                mismatched_layers.push(hash);
+            }
+        }
+
+        Ok(mismatched_layers)
     }
 
     pub fn build_image_from_directory(&mut self,
                                       directory: &Path,
+                                      grouping: &LayerGrouping,
                                       tag: ImageTag,
                                       force: bool,
-                                      verbose_output: bool) -> ImageManagerResult<BuildResult> {
+                                      verbose_output: bool,
+                                      build_time: Option<DateTime<FixedOffset>>) -> ImageManagerResult<BuildResult> {
         self.build_image(
             BuildRequest {
                 build_context: Default::default(),
-                image_definition: ImageDefinition::create_from_directory(directory)?,
+                image_definition: ImageDefinition::create_from_directory_with_grouping(directory, grouping)?,
                 tag,
                 force,
                 verbose_output,
-                print: true
+                print: true,
+                build_time,
+                labels: Vec::new(),
+                additional_tags: Vec::new(),
+                tag_latest: None,
+                stage_tags: Vec::new()
             }
         )
     }
 
+    pub fn build_image_from_archive(&mut self,
+                                    archive_path: &Path,
+                                    tag: ImageTag,
+                                    force: bool,
+                                    verbose_output: bool,
+                                    build_time: Option<DateTime<FixedOffset>>) -> ImageManagerResult<BuildResult> {
+        let mut session = self.state_manager.pooled_session()?;
+
+        let result = self.build_manager.build_image_from_archive(
+            &mut session,
+            &self.layer_manager,
+            archive_path,
+            tag,
+            &BuildOptions {
+                force,
+                verbose_output,
+                print: true,
+                build_time,
+                ..Default::default()
+            }
+        )?;
+
+        self.mark_used(&session, &result.image.hash);
+        Ok(result)
+    }
+
     pub fn merge_image(&mut self,
                        first: &Reference,
                        second: &Reference,
@@ -152,16 +272,33 @@ impl ImageManager {
                 tag,
                 force: false,
                 verbose_output: false,
-                print: false
+                print: false,
+                build_time: None,
+                labels: Vec::new(),
+                additional_tags: Vec::new(),
+                tag_latest: None,
+                stage_tags: Vec::new()
             }
         )
     }
 
-    pub fn tag_image(&mut self, reference: &Reference, tag: &ImageTag) -> ImageManagerResult<Image> {
+    pub fn squash_image(&mut self, request: SquashRequest) -> ImageManagerResult<SquashResult> {
+        let mut session = self.state_manager.pooled_session()?;
+        self.rewrite_manager.squash(&mut session, &self.layer_manager, request)
+    }
+
+    pub fn rebase_image(&mut self, request: RebaseRequest) -> ImageManagerResult<RebaseResult> {
+        let mut session = self.state_manager.pooled_session()?;
+        self.rewrite_manager.rebase(&mut session, &self.layer_manager, request)
+    }
+
+    /// Tags the image, where a pinned tag can only be moved to another image if forced.
+    pub fn tag_image(&mut self, reference: &Reference, tag: &ImageTag, force: bool) -> ImageManagerResult<Image> {
         let mut session = self.state_manager.pooled_session()?;
 
         let layer = self.layer_manager.get_layer(&session, reference)?;
         let image = Image::new(layer.hash.clone(), tag.clone());
+        self.layer_manager.check_replace_pinned(&session, &image, force)?;
 
         self.layer_manager.insert_or_replace_image(&mut session, image.clone())?;
         Ok(image)
@@ -169,16 +306,33 @@ impl ImageManager {
 
     pub fn unpack(&mut self, request: UnpackRequest) -> ImageManagerResult<()> {
         let session = self.state_manager.pooled_session()?;
+        let used = self.used_by_unpack(&session, &request)?;
         self.unpack_manager.unpack(&session, &mut self.layer_manager, request)?;
+        self.mark_all_used(&session, used.as_slice());
         Ok(())
     }
 
     pub fn unpack_file(&mut self, unpack_file: UnpackFile) -> ImageManagerResult<()> {
         let session = self.state_manager.pooled_session()?;
+
+        let mut used = Vec::new();
+        for request in &unpack_file.requests {
+            used.extend(self.used_by_unpack(&session, request)?);
+        }
+
         self.unpack_manager.unpack_file(&session, &mut self.layer_manager, unpack_file)?;
+        self.mark_all_used(&session, &used);
         Ok(())
     }
 
+    fn used_by_unpack(&self, session: &StateSession, request: &UnpackRequest) -> ImageManagerResult<Option<ImageId>> {
+        if request.dry_run {
+            return Ok(None);
+        }
+
+        Ok(Some(self.layer_manager.fully_qualify_reference(session, &request.reference)?))
+    }
+
     pub fn remove_unpacking(&mut self, unpack_folder: &Path, force: bool) -> ImageManagerResult<()> {
         let session = self.state_manager.pooled_session()?;
         self.unpack_manager.remove_unpacking(&session, &mut self.layer_manager, unpack_folder, force)?;
@@ -188,6 +342,7 @@ impl ImageManager {
     pub fn extract(&self, reference: &Reference, archive_path: &Path) -> ImageManagerResult<()> {
         let session = self.state_manager.pooled_session()?;
         self.unpack_manager.extract(&session, &self.layer_manager, reference, archive_path)?;
+        self.mark_used(&session, &self.layer_manager.fully_qualify_reference(&session, reference)?);
         Ok(())
     }
 
@@ -203,14 +358,30 @@ impl ImageManager {
         Ok(())
     }
 
-    pub fn remove_image(&mut self, tag: &ImageTag) -> ImageManagerResult<Vec<ImageId>> {
-        self.remove_image_internal(tag, true)
+    pub fn backup(&self, path: &Path, include_layers: bool) -> ImageManagerResult<()> {
+        let session = self.state_manager.pooled_session()?;
+        self.transfer_manager.backup(&session, &self.layer_manager, path, include_layers)
+    }
+
+    pub fn restore(&mut self, path: &Path) -> ImageManagerResult<()> {
+        let mut session = self.state_manager.pooled_session()?;
+        self.transfer_manager.restore(&mut session, &self.layer_manager, path)?;
+
+        // The backup might be from an older version
+        StateManager::migrate(self.config.base_folder())?;
+        Ok(())
+    }
+
+    pub fn remove_image(&mut self, tag: &ImageTag, force: bool) -> ImageManagerResult<Vec<ImageId>> {
+        self.remove_image_internal(tag, true, force)
     }
 
-    fn remove_image_internal(&mut self, tag: &ImageTag, gc: bool) -> ImageManagerResult<Vec<ImageId>> {
+    fn remove_image_internal(&mut self, tag: &ImageTag, gc: bool, force: bool) -> ImageManagerResult<Vec<ImageId>> {
         let mut session = self.state_manager.pooled_session()?;
+        self.check_not_pinned(&session, tag, force)?;
 
         if let Some(image) = self.layer_manager.remove_image(&mut session, tag)? {
+            session.unpin_image(tag)?;
             self.printer.println(&format!("Removed image: {} ({})", tag, image.hash));
 
             if gc {
@@ -223,10 +394,12 @@ impl ImageManager {
         }
     }
 
-    pub async fn remove_image_async(&mut self, tag: &ImageTag) -> ImageManagerResult<Vec<ImageId>> {
+    pub async fn remove_image_async(&mut self, tag: &ImageTag, force: bool) -> ImageManagerResult<Vec<ImageId>> {
         let mut session = self.state_manager.pooled_session()?;
+        self.check_not_pinned(&session, tag, force)?;
 
         if let Some(image) = self.layer_manager.remove_image(&mut session, tag)? {
+            session.unpin_image(tag)?;
             self.printer.println(&format!("Removed image: {} ({})", tag, image.hash));
             Ok(self.garbage_collect_async().await?)
         } else {
@@ -234,20 +407,68 @@ impl ImageManager {
         }
     }
 
-    pub fn clean_old_images(&mut self, duration: Duration) -> ImageManagerResult<()> {
+    /// Checks that the image is not pinned, unless forced. The pin is removed together with the image.
+    fn check_not_pinned(&self, session: &StateSession, tag: &ImageTag, force: bool) -> ImageManagerResult<()> {
+        if !force && session.is_image_pinned(tag)? {
+            return Err(ImageManagerError::ImagePinned { tag: tag.clone() });
+        }
+
+        Ok(())
+    }
+
+    pub fn pin_image(&mut self, tag: &ImageTag) -> ImageManagerResult<()> {
         let session = self.state_manager.pooled_session()?;
+        self.get_image(tag)?;
+        session.pin_image(tag)?;
+        Ok(())
+    }
 
-        let now = Local::now();
-        for image in self.layer_manager.images_iter(&session)? {
-            let layer = self.layer_manager.get_layer(&session, &image.hash.clone().to_ref())?;
-            if (now - layer.created).to_std().unwrap() > duration {
-                self.remove_image_internal(&image.tag, false)?;
-            }
+    pub fn unpin_image(&mut self, tag: &ImageTag) -> ImageManagerResult<()> {
+        let session = self.state_manager.pooled_session()?;
+        if !session.unpin_image(tag)? {
+            self.get_image(tag)?;
         }
 
         Ok(())
     }
 
+    pub fn is_image_pinned(&self, tag: &ImageTag) -> ImageManagerResult<bool> {
+        let session = self.state_manager.pooled_session()?;
+        Ok(session.is_image_pinned(tag)?)
+    }
+
+    /// Removes the images selected by the retention policy followed by garbage collection.
+    /// In a dry run, only reports what would be removed.
+    pub fn purge(&mut self, policy: &RetentionPolicy, dry_run: bool) -> ImageManagerResult<PurgePlan> {
+        let plan = {
+            let session = self.state_manager.pooled_session()?;
+            let other_references = self.unpack_manager.unpackings(&session)?
+                .into_iter()
+                .map(|unpacking| unpacking.hash)
+                .collect::<Vec<_>>();
+
+            self.retention_manager.plan(&session, &self.layer_manager, policy, &other_references)?
+        };
+
+        if dry_run {
+            for tag in &plan.images {
+                self.printer.println(&format!("Would remove image: {}", tag));
+            }
+
+            for layer in &plan.layers {
+                self.printer.println(&format!("Would remove layer: {}", layer));
+            }
+        } else {
+            for tag in &plan.images {
+                self.remove_image_internal(tag, false, policy.include_pinned)?;
+            }
+
+            self.garbage_collect()?;
+        }
+
+        Ok(plan)
+    }
+
     pub fn garbage_collect(&mut self) -> ImageManagerResult<Vec<ImageId>> {
         let session = self.state_manager.pooled_session()?;
 
@@ -349,6 +570,27 @@ impl ImageManager {
         Ok(used_layers)
     }
 
+    /// Records that the image and all the layers it depends on were used now.
+    fn mark_used(&self, session: &StateSession, hash: &ImageId) {
+        self.mark_all_used(session, std::slice::from_ref(hash))
+    }
+
+    /// Records when the layers of the images were last used, which is only used by the retention policies.
+    /// This is best effort, as it also happens for commands that only hold the shared read lock, where a concurrent
+    /// write can fail because the database is busy. A failure must never fail the command itself.
+    fn mark_all_used(&self, session: &StateSession, hashes: &[ImageId]) {
+        let mut used_layers = HashSet::new();
+        for hash in hashes {
+            if self.layer_manager.find_used_layers(session, hash, &mut used_layers).is_err() {
+                return;
+            }
+        }
+
+        #[allow(unused_must_use)] {
+            session.mark_layers_used(&used_layers.into_iter().collect::<Vec<_>>(), Local::now());
+        }
+    }
+
     fn get_hard_references(&self, session: &StateSession) -> ImageManagerResult<Vec<ImageId>> {
         let mut hard_references = Vec::new();
         for image in self.layer_manager.images_iter(&session)? {
@@ -392,7 +634,9 @@ impl ImageManager {
         Ok(
             ImageMetadata {
                 image: image.clone(),
-                created: self.layer_manager.get_layer(&session, &reference)?.created,
+                created: self.layer_manager.get_layer(&session, &reference)?.created.with_timezone(&Local),
+                last_used: session.get_last_used(&image.hash)?,
+                pinned: session.is_image_pinned(&image.tag)?,
                 size: self.image_size(&reference)?
             }
         )
@@ -420,10 +664,19 @@ impl ImageManager {
 
         layers.reverse();
 
+        let image_tags = self.get_image_tags(reference)?;
+        let mut pinned_tags = Vec::new();
+        for tag in &image_tags {
+            if self.is_image_pinned(tag)? {
+                pinned_tags.push(tag.clone());
+            }
+        }
+
         Ok(
             InspectResult {
                 top_layer,
-                image_tags: self.get_image_tags(reference)?,
+                image_tags,
+                pinned_tags,
                 size: self.image_size(reference)?,
                 labels: labels.into_iter().collect(),
                 layers
@@ -545,7 +798,122 @@ impl ImageManager {
         )
     }
 
+    pub fn fsck(&self) -> ImageManagerResult<Vec<FsckIssue>> {
+        let session = self.state_manager.pooled_session()?;
+        self.fsck_manager.check(&session, &self.layer_manager)
+    }
+
+    /// Repairs the given issues, re-downloading missing and corrupt layer content from the registry if given.
+    /// Returns the issues that could not be repaired.
+    pub async fn repair_fsck_issues(&mut self, issues: Vec<FsckIssue>, registry: Option<&str>) -> ImageManagerResult<Vec<FsckIssue>> {
+        let mut remaining_issues = Vec::new();
+        let mut layer_issues: BTreeMap<ImageId, Vec<FsckIssue>> = BTreeMap::new();
+        let mut missing_layer_issues = Vec::new();
+
+        for issue in issues {
+            match &issue {
+                _ if !issue.requires_registry() => {
+                    let mut session = self.state_manager.pooled_session()?;
+                    if !self.fsck_manager.repair(&mut session, &issue)? {
+                        remaining_issues.push(issue);
+                    }
+                }
+                _ if registry.is_none() => {
+                    remaining_issues.push(issue);
+                }
+                FsckIssue::MissingFile { layer, .. } | FsckIssue::CorruptFile { layer, .. } => {
+                    layer_issues.entry(layer.clone()).or_default().push(issue);
+                }
+                _ => {
+                    missing_layer_issues.push(issue);
+                }
+            }
+        }
+
+        let Some(registry) = registry else {
+            return Ok(remaining_issues);
+        };
+
+        for (hash, issues) in layer_issues {
+            let layer = self.get_layer(&hash.clone().to_ref())?;
+            for issue in &issues {
+                if let FsckIssue::CorruptFile { path, .. } = issue {
+                    self.fsck_manager.remove_stored_file(&layer, path)?;
+                }
+            }
+
+            self.printer.println(&format!("Downloading layer {}...", hash));
+            match self.pull_layer(registry, &hash).await {
+                Ok(layer) => {
+                    let mut session = self.state_manager.pooled_session()?;
+                    self.layer_manager.insert_or_replace_layer(&mut session, &layer)?;
+                }
+                Err(err) => {
+                    self.printer.println(&format!("Failed to download layer {}: {}", hash, err));
+                    remaining_issues.extend(issues);
+                }
+            }
+        }
+
+        for issue in missing_layer_issues {
+            if let FsckIssue::MissingLayer { reference, .. } = &issue {
+                if let Err(err) = self.pull_missing_layers(registry, reference).await {
+                    self.printer.println(&format!("Failed to download layer {}: {}", reference, err));
+                    remaining_issues.push(issue);
+                }
+            }
+        }
+
+        Ok(remaining_issues)
+    }
+
+    async fn pull_missing_layers(&mut self, registry: &str, hash: &ImageId) -> ImageManagerResult<()> {
+        for layer in self.get_layers_to_download(registry, hash).await? {
+            self.printer.println(&format!("Downloading layer {}...", layer.hash));
+            let layer = self.pull_layer(registry, &layer.hash).await?;
+            self.insert_layer(layer)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn find_files(&self, query: &FileQuery) -> ImageManagerResult<Vec<FoundFile>> {
+        let session = self.state_manager.pooled_session()?;
+
+        let files = match query {
+            FileQuery::Path(pattern) => session.find_files_by_path(pattern.trim_start_matches('/'))?,
+            FileQuery::ContentHash(content_hash) => session.find_files_by_content_hash(content_hash)?
+        };
+
+        if files.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut layer_images: HashMap<ImageId, Vec<ImageTag>> = HashMap::new();
+        for image in self.layer_manager.images_iter(&session)? {
+            let mut used_layers = HashSet::new();
+            self.layer_manager.find_used_layers(&session, &image.hash, &mut used_layers)?;
+            for layer in used_layers {
+                layer_images.entry(layer).or_default().push(image.tag.clone());
+            }
+        }
+
+        Ok(
+            files
+                .into_iter()
+                .map(|file| {
+                    let mut images = layer_images.get(&file.layer).cloned().unwrap_or_default();
+                    images.sort();
+                    FoundFile { file, images }
+                })
+                .collect()
+        )
+    }
+
     pub fn get_file(&self, reference: &Reference, requested_path: &str) -> ImageManagerResult<Option<GetFile>> {
+        let session = self.state_manager.pooled_session()?;
+        self.mark_used(&session, &self.layer_manager.fully_qualify_reference(&session, reference)?);
+
         self.visit_file_operations(
             reference,
             |_| None,
@@ -614,6 +982,161 @@ impl ImageManager {
         )
     }
 
+    pub fn commit(&mut self,
+                  unpack_folder: &Path,
+                  tag: ImageTag,
+                  verbose_output: bool,
+                  build_time: Option<DateTime<FixedOffset>>) -> ImageManagerResult<CommitResult> {
+        let unpack_folder = unpack_folder.canonicalize()?;
+        let unpacking = {
+            let session = self.state_manager.pooled_session()?;
+            self.unpack_manager.get_unpacking(&session, &unpack_folder)?
+        };
+
+        let mut directories = BTreeSet::new();
+        let mut files = BTreeMap::new();
+        self.visit_operations(
+            &unpacking.hash.clone().to_ref(),
+            |operation| {
+                match operation {
+                    LayerOperation::Directory { path } => {
+                        directories.insert(path.clone());
+                    }
+                    LayerOperation::File { path, content_hash, link_type, writable, template, .. } |
+                    LayerOperation::CompressedFile { path, content_hash, link_type, writable, template, .. } => {
+                        files.entry(path.clone()).or_insert((content_hash.clone(), *link_type, *writable, *template));
+                    }
+                    LayerOperation::Image { .. } => {}
+                    LayerOperation::ImageAlias { .. } => {}
+                    LayerOperation::Label { .. } => {}
+                }
+
+                Option::<()>::None
+            }
+        )?;
+
+        for path in files.keys() {
+            let mut parent = Path::new(path).parent();
+            while let Some(current) = parent {
+                if current == Path::new("") {
+                    break;
+                }
+
+                directories.insert(current.to_str().unwrap().to_owned());
+                parent = current.parent();
+            }
+        }
+
+        let mut operations = Vec::new();
+        let mut added_files = Vec::new();
+        let mut changed_files = Vec::new();
+        let mut found_files = HashSet::new();
+
+        let mut stack = vec![unpack_folder.clone()];
+        while let Some(current) = stack.pop() {
+            let mut entries = std::fs::read_dir(&current)?.collect::<Result<Vec<_>, _>>()?;
+            entries.sort_by_key(|entry| entry.path());
+
+            for entry in entries {
+                let entry_path = entry.path();
+                let relative_path = entry_path.strip_prefix(&unpack_folder).unwrap().to_str().unwrap().to_owned();
+
+                if entry_path.is_dir() {
+                    if !directories.contains(&relative_path) {
+                        operations.push(LayerOperationDefinition::Directory { path: relative_path.clone() });
+                    }
+
+                    stack.push(entry_path);
+                } else if entry_path.is_file() {
+                    let content_hash = compute_content_hash(&entry_path)?;
+                    found_files.insert(relative_path.clone());
+
+                    let (link_type, writable) = match files.get(&relative_path) {
+                        Some((image_content_hash, _, _, _)) if image_content_hash == &content_hash => {
+                            continue;
+                        }
+                        Some((_, _, _, true)) => {
+                            // Rendered templates differ from the stored template by design
+                            continue;
+                        }
+                        Some((_, link_type, writable, _)) => {
+                            changed_files.push(relative_path.clone());
+                            (*link_type, *writable)
+                        }
+                        None => {
+                            added_files.push(relative_path.clone());
+                            (LinkType::Hard, true)
+                        }
+                    };
+
+                    operations.push(LayerOperationDefinition::File {
+                        path: relative_path.clone(),
+                        source_path: relative_path,
+                        link_type,
+                        writable,
+                        template: false
+                    });
+                }
+            }
+        }
+
+        let removed_files = files.keys()
+            .filter(|path| !found_files.contains(*path))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for path in &removed_files {
+            self.printer.println(&format!("\t* The file {} has been removed, but deletions cannot be committed.", path));
+        }
+
+        let image = if !operations.is_empty() {
+            let build_result = self.build_image(
+                BuildRequest {
+                    build_context: unpack_folder.clone(),
+                    image_definition: ImageDefinition::new(
+                        Some(unpacking.hash.clone().to_ref()),
+                        vec![
+                            LayerDefinition::new(
+                                format!("COMMIT {}", unpack_folder.display()),
+                                operations
+                            )
+                        ]
+                    ),
+                    tag,
+                    force: false,
+                    verbose_output,
+                    print: true,
+                    build_time,
+                    labels: Vec::new(),
+                    additional_tags: Vec::new(),
+                    tag_latest: None,
+                    stage_tags: Vec::new()
+                }
+            )?;
+
+            build_result.image
+        } else {
+            self.printer.println("No changes to commit.");
+            self.tag_image(&unpacking.hash.clone().to_ref(), &tag, false)?
+        };
+
+        // The unpacking now corresponds to the committed image
+        let session = self.state_manager.pooled_session()?;
+        session.remove_unpacking(&unpacking.destination)?;
+        session.insert_unpacking(Unpacking { hash: image.hash.clone(), ..unpacking })?;
+
+        Ok(
+            CommitResult {
+                image,
+                diff: DiffResult {
+                    changed_files,
+                    added_files,
+                    removed_files
+                }
+            }
+        )
+    }
+
     pub fn list_unpackings(&self, filter: Option<&Regex>) -> ImageManagerResult<Vec<Unpacking>> {
         let session = self.state_manager.pooled_session()?;
         let mut unpackings = self.unpack_manager.unpackings(&session)?;
@@ -701,6 +1224,21 @@ impl ImageManager {
         self.printer.println(&format!("Pulling image: {}", pull_tag));
 
         let image_metadata = self.resolve_image_in_registry_internal(&registry_session, &pull_tag, true).await?;
+        self.config.check_quota(Quota::ImageSize, image_metadata.size)?;
+
+        let image_tag = request.new_tag.clone().unwrap_or_else(|| request.tag.clone());
+        self.layer_manager.check_replace_pinned(&session, &Image::new(image_metadata.image.hash.clone(), image_tag.clone()), request.force)?;
+
+        if self.config.max_layer_size.is_some() || self.config.max_store_size.is_some() {
+            // All missing layers are checked before downloading, so that a pull is not stopped halfway
+            let mut download_size = DataSize(0);
+            for layer in self.get_layers_to_download_internal(&registry_session, &image_metadata.image.hash).await? {
+                self.config.check_quota(Quota::LayerSize, layer.storage_size)?;
+                download_size += layer.storage_size;
+            }
+
+            self.config.check_quota(Quota::StoreSize, self.layer_manager.total_storage_size(&session)? + download_size)?;
+        }
 
         let mut stack = Vec::new();
         stack.push(image_metadata.image.hash.clone());
@@ -720,6 +1258,7 @@ impl ImageManager {
                 visit_layer(&mut stack, &layer);
             } else {
                 self.printer.println(&format!("\t* Pulling layer: {}...", current));
+
                 let mut retries = request.retry.unwrap_or(0);
                 let layer = loop {
                     self.printer.println("\t\t* Downloading...");
@@ -767,9 +1306,9 @@ impl ImageManager {
             }
         }
 
-        let image_tag = request.new_tag.unwrap_or_else(|| request.tag.clone());
-        let image = Image::new(top_level_hash.unwrap(), image_tag.clone());
+        let image = Image::new(top_level_hash.unwrap(), image_tag);
         self.insert_or_replace_image(image.clone())?;
+        self.mark_used(&session, &image.hash);
 
         self.printer.println(&format!("Pull complete in {:.1} seconds.", t0.elapsed().as_secs_f64()));
 
@@ -842,7 +1381,10 @@ impl ImageManager {
     pub async fn get_layers_to_download(&mut self, registry: &str, hash: &ImageId) -> ImageManagerResult<Vec<Layer>> {
         let session = self.state_manager.pooled_session()?;
         let registry_session = RegistrySession::new(&session, registry)?;
+        self.get_layers_to_download_internal(&registry_session, hash).await
+    }
 
+    async fn get_layers_to_download_internal(&self, registry_session: &RegistrySession, hash: &ImageId) -> ImageManagerResult<Vec<Layer>> {
         let mut stack = Vec::new();
         stack.push(hash.clone());
 
@@ -855,7 +1397,7 @@ impl ImageManager {
             if let Ok(layer) = self.get_layer(&current.clone().to_ref()) {
                 visit_layer(&mut stack, &layer);
             } else {
-                let layer = self.registry_manager.get_layer_definition(&registry_session, &current).await?;
+                let layer = self.registry_manager.get_layer_definition(registry_session, &current).await?;
                 layers.push(layer.clone());
                 visit_layer(&mut stack, &layer);
             }
@@ -962,46 +1504,21 @@ impl ImageManager {
     fn visit_operations<T, F: FnMut(&LayerOperation) -> Option<T>>(
         &self,
         reference: &Reference,
-        mut on_operation: F,
+        on_operation: F,
     ) -> ImageManagerResult<Option<T>> {
         let session = self.state_manager.pooled_session()?;
+        self.layer_manager.visit_operations(&session, reference, on_operation)
+    }
+}
 
-        let mut stack = Vec::new();
-        stack.push(reference.clone());
-
-        while let Some(current) = stack.pop() {
-            let layer = self.layer_manager.get_layer(&session, &current)?;
-            if let Some(parent_hash) = layer.parent_hash.as_ref() {
-                stack.push(parent_hash.clone().to_ref());
-            }
-
-            for operation in &layer.operations {
-                match operation {
-                    LayerOperation::Image { hash } | LayerOperation::ImageAlias { hash } => {
-                        stack.push(hash.clone().to_ref());
-                    }
-                    LayerOperation::Directory { .. } => {
-                        if let Some(result) = on_operation(operation) {
-                            return Ok(Some(result));
-                        }
-                    }
-                    LayerOperation::File { .. } => {
-                        if let Some(result) = on_operation(operation) {
-                            return Ok(Some(result));
-                        }
-                    }
-                    LayerOperation::CompressedFile { .. } => {
-                        if let Some(result) = on_operation(operation) {
-                            return Ok(Some(result));
-                        }
-                    }
-                    LayerOperation::Label { .. } => {}
-                }
-            }
-        }
+pub enum FileQuery {
+    Path(String),
+    ContentHash(String)
+}
 
-        Ok(None)
-    }
+pub struct FoundFile {
+    pub file: FileEntry,
+    pub images: Vec<ImageTag>
 }
 
 pub struct SystemUsage {
@@ -1056,12 +1573,19 @@ pub struct DiffResult {
     pub removed_files: Vec<String>
 }
 
+pub struct CommitResult {
+    pub image: Image,
+    pub diff: DiffResult
+}
+
 pub struct PullRequest<'a> {
     pub tag: ImageTag,
     pub default_registry: Option<&'a str>,
     pub new_tag: Option<ImageTag>,
     pub retry: Option<usize>,
-    pub verbose_output: bool
+    pub verbose_output: bool,
+    /// Replaces the local tag even if pinned to another image.
+    pub force: bool
 }
 
 impl<'a> PullRequest<'a> {
@@ -1072,6 +1596,7 @@ impl<'a> PullRequest<'a> {
             new_tag: None,
             retry: None,
             verbose_output: false,
+            force: false
         }
     }
 }
@@ -1079,6 +1604,7 @@ impl<'a> PullRequest<'a> {
 pub struct InspectResult {
     pub top_layer: Layer,
     pub image_tags: Vec<ImageTag>,
+    pub pinned_tags: Vec<ImageTag>,
     pub size: DataSize,
     pub labels: Vec<(String, String)>,
     pub layers: Vec<InspectLayerResult>
@@ -1149,9 +1675,11 @@ fn test_build_from_directory() {
 
         let result = image_manager.build_image_from_directory(
             Path::new("testdata/rawdata2"),
+            &LayerGrouping::TopLevel,
             ImageTag::from_str("test").unwrap(),
             false,
-            false
+            false,
+            None
         );
         assert!(result.is_ok());
         let result = result.unwrap().image;
@@ -1233,7 +1761,7 @@ fn test_remove_image1() {
         ImageTag::from_str("test").unwrap()
     ).unwrap();
 
-    let result = image_manager.remove_image(&ImageTag::from_str("test").unwrap());
+    let result = image_manager.remove_image(&ImageTag::from_str("test").unwrap(), false);
     assert!(result.is_ok());
 
     let images = image_manager.list_images(None);
@@ -1274,7 +1802,7 @@ fn test_remove_image2() {
         ImageTag::from_str("test2").unwrap()
     ).unwrap();
 
-    let result = image_manager.remove_image(&ImageTag::from_str("test").unwrap());
+    let result = image_manager.remove_image(&ImageTag::from_str("test").unwrap(), false);
     assert!(result.is_ok());
 
     let images = image_manager.list_images(None);
@@ -1350,6 +1878,7 @@ fn test_compress() {
             unpack_folder: unpack_folder.clone(),
             replace: false,
             dry_run: false,
+            variables: Default::default()
         });
         assert!(result.is_ok(), "{}", result.unwrap_err());
         crate::assert_file_content_eq!(Path::new("testdata/rawdata/file1.txt"), unpack_folder.join("file1.txt"));
@@ -1388,6 +1917,7 @@ fn test_decompress() {
             unpack_folder: unpack_folder.clone(),
             replace: false,
             dry_run: false,
+            variables: Default::default()
         });
         assert!(result.is_ok(), "{}", result.unwrap_err());
         crate::assert_file_content_eq!(Path::new("testdata/rawdata/file1.txt"), unpack_folder.join("file1.txt"));
@@ -1432,4 +1962,993 @@ fn test_diff() {
         assert_eq!(vec!["test/file2.txt".to_owned()], diff_result.added_files);
         assert_eq!(Vec::<String>::new(), diff_result.removed_files);
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_squash() {
+    use std::str::FromStr;
+
+    use crate::image_manager::ConsolePrinter;
+
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+
+    {
+        let config = ImageManagerConfig::with_base_folder(tmp_folder.owned());
+
+        let mut image_manager = ImageManager::new(config, ConsolePrinter::new()).unwrap();
+
+        let result = super::test_helpers::build_image(
+            &mut image_manager,
+            Path::new("testdata/definitions/overwrite.labarfile"),
+            ImageTag::from_str("test").unwrap()
+        );
+        assert!(result.is_ok());
+
+        let result = image_manager.squash_image(SquashRequest {
+            reference: Reference::from_str("test").unwrap(),
+            tag: ImageTag::from_str("test:squashed").unwrap(),
+            layers: None
+        });
+        assert!(result.is_ok());
+        let result = result.unwrap();
+        assert_eq!(4, result.squashed_layers);
+
+        let layers = image_manager.get_layers(&result.image.hash.clone().to_ref()).unwrap();
+        assert_eq!(1, layers.len());
+        assert_eq!(None, layers[0].parent_hash);
+        assert_eq!(2, layers[0].operations.len());
+        assert_eq!(
+            vec![("version".to_owned(), "2.0".to_owned())],
+            image_manager.get_labels(&result.image.tag.clone().to_ref()).unwrap()
+        );
+
+        image_manager.remove_image(&ImageTag::from_str("test").unwrap(), false).unwrap();
+
+        let unpack_folder = tmp_folder.owned().join("unpack");
+        let result = image_manager.unpack(UnpackRequest {
+            reference: result.image.tag.clone().to_ref(),
+            unpack_folder: unpack_folder.clone(),
+            replace: false,
+            dry_run: false,
+            variables: Default::default()
+        });
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        crate::assert_file_content_eq!(Path::new("testdata/rawdata/file2.txt"), unpack_folder.join("test/file.txt"));
+    }
+}
+
+#[test]
+fn test_squash_top_layers() {
+    use std::str::FromStr;
+
+    use crate::image_manager::ConsolePrinter;
+
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+
+    {
+        let config = ImageManagerConfig::with_base_folder(tmp_folder.owned());
+
+        let mut image_manager = ImageManager::new(config, ConsolePrinter::new()).unwrap();
+
+        let result = super::test_helpers::build_image(
+            &mut image_manager,
+            Path::new("testdata/definitions/simple5.labarfile"),
+            ImageTag::from_str("test").unwrap()
+        );
+        assert!(result.is_ok());
+        let build_result = result.unwrap();
+
+        let result = image_manager.squash_image(SquashRequest {
+            reference: Reference::from_str("test").unwrap(),
+            tag: ImageTag::from_str("test:squashed").unwrap(),
+            layers: Some(2)
+        });
+        assert!(result.is_ok());
+        let result = result.unwrap();
+        assert_eq!(2, result.squashed_layers);
+
+        let layers = image_manager.get_layers(&result.image.hash.clone().to_ref()).unwrap();
+        assert_eq!(2, layers.len());
+        assert_eq!(Some(build_result.layers[0].clone()), layers[0].parent_hash);
+        assert_eq!(2, layers[0].operations.len());
+
+        let unpack_folder = tmp_folder.owned().join("unpack");
+        let result = image_manager.unpack(UnpackRequest {
+            reference: result.image.tag.clone().to_ref(),
+            unpack_folder: unpack_folder.clone(),
+            replace: false,
+            dry_run: false,
+            variables: Default::default()
+        });
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        crate::assert_file_content_eq!(Path::new("testdata/rawdata/file1.txt"), unpack_folder.join("test/file1.txt"));
+        crate::assert_file_content_eq!(Path::new("testdata/rawdata/file2.txt"), unpack_folder.join("test/file2.txt"));
+        assert!(unpack_folder.join("test2").exists());
+    }
+}
+
+#[test]
+fn test_rebase() {
+    use std::str::FromStr;
+
+    use crate::image_manager::ConsolePrinter;
+
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+
+    {
+        let config = ImageManagerConfig::with_base_folder(tmp_folder.owned());
+
+        let mut image_manager = ImageManager::new(config, ConsolePrinter::new()).unwrap();
+
+        let result = super::test_helpers::build_image(
+            &mut image_manager,
+            Path::new("testdata/definitions/simple1.labarfile"),
+            ImageTag::from_str("base:v1").unwrap()
+        );
+        assert!(result.is_ok());
+
+        let result = super::test_helpers::build_image(
+            &mut image_manager,
+            Path::new("testdata/definitions/simple6.labarfile"),
+            ImageTag::from_str("base:v2").unwrap()
+        );
+        assert!(result.is_ok());
+        let base_result = result.unwrap();
+
+        let result = super::test_helpers::build_image(
+            &mut image_manager,
+            Path::new("testdata/definitions/with_base.labarfile"),
+            ImageTag::from_str("test").unwrap()
+        );
+        assert!(result.is_ok());
+
+        let result = image_manager.rebase_image(RebaseRequest {
+            reference: Reference::from_str("test").unwrap(),
+            from: Reference::from_str("base:v1").unwrap(),
+            onto: Reference::from_str("base:v2").unwrap(),
+            tag: ImageTag::from_str("test:rebased").unwrap()
+        });
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let result = result.unwrap();
+        assert_eq!(2, result.rebased_layers.len());
+
+        let layers = image_manager.get_layers(&result.image.hash.clone().to_ref()).unwrap();
+        assert_eq!(3, layers.len());
+        assert_eq!(base_result.image.hash, layers[2].hash);
+
+        image_manager.remove_image(&ImageTag::from_str("test").unwrap(), false).unwrap();
+
+        let unpack_folder = tmp_folder.owned().join("unpack");
+        let result = image_manager.unpack(UnpackRequest {
+            reference: result.image.tag.clone().to_ref(),
+            unpack_folder: unpack_folder.clone(),
+            replace: false,
+            dry_run: false,
+            variables: Default::default()
+        });
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        crate::assert_file_content_eq!(Path::new("testdata/rawdata/file2.txt"), unpack_folder.join("file2.txt"));
+        crate::assert_file_content_eq!(Path::new("testdata/rawdata/file2.txt"), unpack_folder.join("test/file2.txt"));
+        assert!(unpack_folder.join("dir").exists());
+        assert!(!unpack_folder.join("file1.txt").exists());
+    }
+}
+
+#[test]
+fn test_rebase_not_based_on() {
+    use std::str::FromStr;
+
+    use crate::image_manager::ConsolePrinter;
+
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+
+    {
+        let config = ImageManagerConfig::with_base_folder(tmp_folder.owned());
+
+        let mut image_manager = ImageManager::new(config, ConsolePrinter::new()).unwrap();
+
+        let result = super::test_helpers::build_image(
+            &mut image_manager,
+            Path::new("testdata/definitions/simple1.labarfile"),
+            ImageTag::from_str("base:v1").unwrap()
+        );
+        assert!(result.is_ok());
+
+        let result = super::test_helpers::build_image(
+            &mut image_manager,
+            Path::new("testdata/definitions/simple6.labarfile"),
+            ImageTag::from_str("base:v2").unwrap()
+        );
+        assert!(result.is_ok());
+
+        let result = image_manager.rebase_image(RebaseRequest {
+            reference: Reference::from_str("base:v2").unwrap(),
+            from: Reference::from_str("base:v1").unwrap(),
+            onto: Reference::from_str("base:v1").unwrap(),
+            tag: ImageTag::from_str("test:rebased").unwrap()
+        });
+        assert!(matches!(result, Err(ImageManagerError::NotBasedOn { .. })));
+    }
+}
+
+#[test]
+fn test_rebase_failed_insert() {
+    use std::str::FromStr;
+
+    use crate::image_manager::ConsolePrinter;
+
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+
+    {
+        let config = ImageManagerConfig::with_base_folder(tmp_folder.owned());
+
+        let mut image_manager = ImageManager::new(config, ConsolePrinter::new()).unwrap();
+
+        for (path, tag) in [("simple1", "base:v1"), ("simple6", "base:v2"), ("with_base", "test")] {
+            let result = super::test_helpers::build_image(
+                &mut image_manager,
+                Path::new(&format!("testdata/definitions/{}.labarfile", path)),
+                ImageTag::from_str(tag).unwrap()
+            );
+            assert!(result.is_ok());
+        }
+
+        let num_layers = image_manager.state_manager.pooled_session().unwrap().number_of_layers().unwrap();
+        image_manager.state_manager.pooled_session().unwrap().connection.execute_batch(
+            r#"
+            CREATE TRIGGER fail_images BEFORE INSERT ON images BEGIN SELECT RAISE(FAIL, 'failed'); END;
+            "#
+        ).unwrap();
+
+        // None of the rebased layers are inserted when the image can not be
+        let result = image_manager.rebase_image(RebaseRequest {
+            reference: Reference::from_str("test").unwrap(),
+            from: Reference::from_str("base:v1").unwrap(),
+            onto: Reference::from_str("base:v2").unwrap(),
+            tag: ImageTag::from_str("test:rebased").unwrap()
+        });
+        assert!(result.is_err());
+        assert_eq!(num_layers, image_manager.state_manager.pooled_session().unwrap().number_of_layers().unwrap());
+    }
+}
+
+#[test]
+fn test_commit() {
+    use std::str::FromStr;
+
+    use crate::image_manager::ConsolePrinter;
+
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+
+    {
+        let config = ImageManagerConfig::with_base_folder(tmp_folder.owned());
+
+        let mut image_manager = ImageManager::new(config, ConsolePrinter::new()).unwrap();
+
+        let result = super::test_helpers::build_image(
+            &mut image_manager,
+            Path::new("testdata/definitions/simple5.labarfile"),
+            ImageTag::from_str("test").unwrap()
+        );
+        assert!(result.is_ok());
+
+        let unpack_folder = tmp_folder.owned().join("unpack");
+        let result = image_manager.unpack(UnpackRequest {
+            reference: Reference::from_str("test").unwrap(),
+            unpack_folder: unpack_folder.clone(),
+            replace: false,
+            dry_run: false,
+            variables: Default::default()
+        });
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+
+        std::fs::remove_file(unpack_folder.join("test/file1.txt")).unwrap();
+        std::fs::copy("testdata/rawdata/file2.txt", unpack_folder.join("test/file1.txt")).unwrap();
+        std::fs::copy("testdata/rawdata/file1.txt", unpack_folder.join("test2/file3.txt")).unwrap();
+        std::fs::remove_file(unpack_folder.join("test/file2.txt")).unwrap();
+
+        let build_time = crate::image_manager::details::build::reproducible_build_time(true).unwrap();
+        let result = image_manager.commit(&unpack_folder, ImageTag::from_str("test:committed").unwrap(), false, build_time);
+        assert!(result.is_ok(), "{}", result.err().unwrap());
+        let result = result.unwrap();
+        assert_eq!(0, image_manager.get_layer(&result.image.hash.clone().to_ref()).unwrap().created.timestamp());
+
+        assert_eq!(vec!["test/file1.txt".to_owned()], result.diff.changed_files);
+        assert_eq!(vec!["test2/file3.txt".to_owned()], result.diff.added_files);
+        assert_eq!(vec!["test/file2.txt".to_owned()], result.diff.removed_files);
+        assert_eq!(4, image_manager.get_layers(&result.image.hash.clone().to_ref()).unwrap().len());
+
+        let file = image_manager.get_file(&result.image.tag.clone().to_ref(), "test/file1.txt").unwrap().unwrap();
+        crate::assert_file_content_eq!(Path::new("testdata/rawdata/file2.txt"), file.path);
+
+        let unpackings = image_manager.list_unpackings(None).unwrap();
+        assert_eq!(1, unpackings.len());
+        assert_eq!(result.image.hash, unpackings[0].hash);
+    }
+}
+
+#[test]
+fn test_commit_with_template() {
+    use std::str::FromStr;
+
+    use crate::image_manager::ConsolePrinter;
+
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+
+    {
+        let config = ImageManagerConfig::with_base_folder(tmp_folder.owned());
+
+        let mut image_manager = ImageManager::new(config, ConsolePrinter::new()).unwrap();
+
+        let result = super::test_helpers::build_image(
+            &mut image_manager,
+            Path::new("testdata/definitions/template.labarfile"),
+            ImageTag::from_str("test").unwrap()
+        );
+        assert!(result.is_ok());
+
+        let unpack_folder = tmp_folder.owned().join("unpack");
+        let result = image_manager.unpack(UnpackRequest {
+            reference: Reference::from_str("test").unwrap(),
+            unpack_folder: unpack_folder.clone(),
+            replace: false,
+            dry_run: false,
+            variables: [("HOSTNAME".to_owned(), "web1".to_owned()), ("PORT".to_owned(), "8080".to_owned())].into_iter().collect()
+        });
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+
+        // The rendered file is not a change
+        let result = image_manager.commit(&unpack_folder, ImageTag::from_str("test:committed").unwrap(), false, None);
+        assert!(result.is_ok(), "{}", result.err().unwrap());
+        let result = result.unwrap();
+        assert!(result.diff.changed_files.is_empty());
+        assert!(result.diff.added_files.is_empty());
+    }
+}
+
+#[test]
+fn test_verify_reproducible_build() {
+    use std::str::FromStr;
+
+    use crate::image_manager::ConsolePrinter;
+    use crate::image_manager::details::build::reproducible_build_time;
+
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+
+    {
+        let config = ImageManagerConfig::with_base_folder(tmp_folder.owned());
+
+        let mut image_manager = ImageManager::new(config, ConsolePrinter::new()).unwrap();
+
+        let result = super::test_helpers::build_image(
+            &mut image_manager,
+            Path::new("testdata/definitions/simple1.labarfile"),
+            ImageTag::from_str("test").unwrap()
+        );
+        assert!(result.is_ok());
+
+        let request = BuildRequest {
+            build_context: Path::new("").to_path_buf(),
+            image_definition: ImageDefinition::parse_file_without_context(Path::new("testdata/definitions/with_image_ref.labarfile")).unwrap(),
+            tag: ImageTag::from_str("that").unwrap(),
+            force: false,
+            verbose_output: false,
+            print: true,
+            build_time: reproducible_build_time(true).unwrap(),
+            labels: Vec::new(),
+            additional_tags: Vec::new(),
+            tag_latest: None,
+            stage_tags: Vec::new()
+        };
+
+        let result = image_manager.build_image(request.clone());
+        assert!(result.is_ok());
+        let result = result.unwrap();
+        assert_eq!(0, image_manager.get_layer(&result.image.hash.clone().to_ref()).unwrap().created.timestamp());
+
+        let mismatched_layers = image_manager.verify_reproducible_build(request.clone());
+        assert!(mismatched_layers.is_ok(), "{}", mismatched_layers.unwrap_err());
+        assert_eq!(Vec::<ImageId>::new(), mismatched_layers.unwrap());
+
+        let mismatched_layers = image_manager.verify_reproducible_build(BuildRequest { build_time: None, ..request });
+        assert!(mismatched_layers.is_ok());
+        assert_eq!(2, mismatched_layers.unwrap().len());
+    }
+}
+
+#[test]
+fn test_verify_reproducible_build_copy_from() {
+    use std::str::FromStr;
+
+    use crate::image_manager::ConsolePrinter;
+    use crate::image_manager::details::build::reproducible_build_time;
+
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+
+    {
+        let config = ImageManagerConfig::with_base_folder(tmp_folder.owned());
+
+        let mut image_manager = ImageManager::new(config, ConsolePrinter::new()).unwrap();
+
+        let result = super::test_helpers::build_image(
+            &mut image_manager,
+            Path::new("testdata/definitions/simple5.labarfile"),
+            ImageTag::from_str("source:v1").unwrap()
+        );
+        assert!(result.is_ok());
+
+        let request = BuildRequest {
+            build_context: Path::new("").to_path_buf(),
+            image_definition: ImageDefinition::parse_file_without_context(Path::new("testdata/definitions/copy_from.labarfile")).unwrap(),
+            tag: ImageTag::from_str("that").unwrap(),
+            force: false,
+            verbose_output: false,
+            print: true,
+            build_time: reproducible_build_time(true).unwrap(),
+            labels: Vec::new(),
+            additional_tags: Vec::new(),
+            tag_latest: None,
+            stage_tags: Vec::new()
+        };
+
+        let result = image_manager.build_image(request.clone());
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+
+        let mismatched_layers = image_manager.verify_reproducible_build(request);
+        assert!(mismatched_layers.is_ok(), "{}", mismatched_layers.unwrap_err());
+        assert_eq!(Vec::<ImageId>::new(), mismatched_layers.unwrap());
+    }
+}
+
+#[test]
+fn test_build_from_archive() {
+    use std::str::FromStr;
+
+    use crate::image_manager::ConsolePrinter;
+
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+
+    {
+        let config = ImageManagerConfig::with_base_folder(tmp_folder.owned());
+
+        let mut image_manager = ImageManager::new(config, ConsolePrinter::new()).unwrap();
+
+        let result = image_manager.build_image_from_archive(
+            Path::new("testdata/archives/rawdata2.zip"),
+            ImageTag::from_str("test:zip").unwrap(),
+            false,
+            false,
+            crate::image_manager::details::build::reproducible_build_time(true).unwrap()
+        );
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let zip_result = result.unwrap();
+        assert_eq!(0, image_manager.get_layer(&zip_result.image.hash.clone().to_ref()).unwrap().created.timestamp());
+        assert_eq!(4, zip_result.layers.len());
+        assert_eq!(4, zip_result.built_layers.len());
+        assert_eq!(Some(DataSize(4257)), image_manager.image_size(&zip_result.image.tag.clone().to_ref()).ok());
+
+        let result = image_manager.build_image_from_archive(
+            Path::new("testdata/archives/rawdata2.tar.gz"),
+            ImageTag::from_str("test:tar").unwrap(),
+            false,
+            false,
+            None
+        );
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        let tar_result = result.unwrap();
+        assert_eq!(zip_result.image.hash, tar_result.image.hash);
+        assert_eq!(0, tar_result.built_layers.len());
+
+        let unpack_folder = tmp_folder.owned().join("unpack");
+        let result = image_manager.unpack(UnpackRequest {
+            reference: tar_result.image.tag.clone().to_ref(),
+            unpack_folder: unpack_folder.clone(),
+            replace: false,
+            dry_run: false,
+            variables: Default::default()
+        });
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+        crate::assert_file_content_eq!(Path::new("testdata/rawdata2/dir1/file1.txt"), unpack_folder.join("dir1/file1.txt"));
+        crate::assert_file_content_eq!(Path::new("testdata/rawdata2/dir2/file2.txt"), unpack_folder.join("dir2/file2.txt"));
+        crate::assert_file_content_eq!(Path::new("testdata/rawdata2/file2.txt"), unpack_folder.join("file2.txt"));
+
+        assert_eq!(0, std::fs::read_dir(tmp_folder.owned()).unwrap().filter(|entry| entry.as_ref().unwrap().file_name().to_str().unwrap().starts_with("tmp-staging")).count());
+    }
+}
+
+#[test]
+fn test_build_from_archive_duplicates() {
+    use std::str::FromStr;
+
+    use crate::image_manager::ConsolePrinter;
+
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+    let long_path = format!("dir1/{}/file.txt", "a".repeat(120));
+
+    let archive_path = tmp_folder.owned().join("duplicates.tar");
+    {
+        std::fs::create_dir_all(tmp_folder.owned()).unwrap();
+        let mut builder = tar::Builder::new(std::fs::File::create(&archive_path).unwrap());
+        for (path, content) in [("dir1/file1.txt", "first"), (long_path.as_str(), "long"), ("dir1/file1.txt", "second")] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, path, content.as_bytes()).unwrap();
+        }
+        builder.finish().unwrap();
+    }
+
+    let mut config = ImageManagerConfig::with_base_folder(tmp_folder.owned().join("store"));
+    config.max_store_size = Some(DataSize(4));
+    let mut image_manager = ImageManager::new(config.clone(), ConsolePrinter::new()).unwrap();
+
+    // Nothing is extracted if the quota would be exceeded
+    let result = image_manager.build_image_from_archive(&archive_path, ImageTag::from_str("test").unwrap(), false, false, None);
+    assert!(matches!(result, Err(ImageManagerError::QuotaExceeded { quota: Quota::StoreSize, .. })));
+    assert_eq!(0, std::fs::read_dir(config.layers_base_folder()).map(|entries| entries.count()).unwrap_or(0));
+
+    config.max_store_size = None;
+    let mut image_manager = ImageManager::new(config, ConsolePrinter::new()).unwrap();
+    let result = image_manager.build_image_from_archive(&archive_path, ImageTag::from_str("test").unwrap(), false, false, None);
+    assert!(result.is_ok(), "{}", result.unwrap_err());
+    let result = result.unwrap();
+
+    let layer = image_manager.get_layer(&result.image.hash.clone().to_ref()).unwrap();
+    assert_eq!(2, layer.operations.iter().filter(|operation| matches!(operation, LayerOperation::File { .. })).count());
+    assert_eq!(DataSize(10), layer.storage_size);
+
+    let unpack_folder = tmp_folder.owned().join("unpack");
+    let result = image_manager.unpack(UnpackRequest {
+        reference: result.image.tag.clone().to_ref(),
+        unpack_folder: unpack_folder.clone(),
+        replace: false,
+        dry_run: false,
+        variables: Default::default()
+    });
+    assert!(result.is_ok(), "{}", result.unwrap_err());
+    assert_eq!("second", std::fs::read_to_string(unpack_folder.join("dir1/file1.txt")).unwrap());
+    assert_eq!("long", std::fs::read_to_string(unpack_folder.join(&long_path)).unwrap());
+}
+
+#[test]
+fn test_fsck() {
+    use std::str::FromStr;
+
+    use crate::image_manager::ConsolePrinter;
+
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+
+    let config = ImageManagerConfig::with_base_folder(tmp_folder.owned());
+    let mut image_manager = ImageManager::new(config.clone(), ConsolePrinter::new()).unwrap();
+
+    let image = super::test_helpers::build_image(
+        &mut image_manager,
+        Path::new("testdata/definitions/simple5.labarfile"),
+        ImageTag::from_str("test").unwrap()
+    ).unwrap().image;
+    assert_eq!(Vec::<FsckIssue>::new(), image_manager.fsck().unwrap());
+
+    let unpack_folder = tmp_folder.owned().join("unpack");
+    image_manager.unpack(UnpackRequest {
+        reference: image.hash.clone().to_ref(),
+        unpack_folder: unpack_folder.clone(),
+        replace: false,
+        dry_run: false,
+        variables: Default::default()
+    }).unwrap();
+    std::fs::remove_dir_all(&unpack_folder).unwrap();
+
+    let file1 = image_manager.get_file(&image.hash.clone().to_ref(), "test/file1.txt").unwrap().unwrap();
+    std::fs::write(&file1.path, "corrupt").unwrap();
+    let file2 = image_manager.get_file(&image.hash.clone().to_ref(), "test/file2.txt").unwrap().unwrap();
+    std::fs::remove_file(&file2.path).unwrap();
+
+    let directory_layer = image_manager.get_layers(&image.hash.clone().to_ref()).unwrap()
+        .into_iter()
+        .find(|layer| layer.operations.iter().any(|operation| matches!(operation, LayerOperation::Directory { .. })))
+        .unwrap();
+    image_manager.state_manager.session().unwrap().remove_layer(&directory_layer.hash).unwrap();
+
+    let missing_hash = ImageId::from_str(&"a".repeat(64)).unwrap();
+    {
+        let connection = rusqlite::Connection::open(config.base_folder().join(STATE_FILENAME)).unwrap();
+        connection.pragma_update(None, "foreign_keys", false).unwrap();
+        connection.execute("INSERT INTO images (tag, hash) VALUES (?1, ?2)", (&ImageTag::from_str("dangling").unwrap(), &missing_hash)).unwrap();
+    }
+
+    let orphan_folder = config.layers_base_folder().join("orphan");
+    std::fs::create_dir_all(&orphan_folder).unwrap();
+    let download_folder = config.base_folder().join("tmp-download");
+    std::fs::create_dir_all(&download_folder).unwrap();
+    let download_file = download_folder.join("partial");
+    std::fs::write(&download_file, "partial").unwrap();
+    let staging_folder = config.base_folder().join("tmp-staging1234");
+    std::fs::create_dir_all(&staging_folder).unwrap();
+    std::fs::File::open(&staging_folder).unwrap().set_modified(std::time::SystemTime::UNIX_EPOCH).unwrap();
+
+    let issues = image_manager.fsck().unwrap();
+    assert!(issues.contains(&FsckIssue::OrphanLayerFolder { path: orphan_folder.clone() }));
+    assert!(issues.contains(&FsckIssue::TemporaryFile { path: download_file.clone() }));
+    assert!(issues.contains(&FsckIssue::TemporaryFile { path: staging_folder.clone() }));
+    assert!(issues.contains(&FsckIssue::CorruptFile { layer: directory_layer.parent_hash.clone().unwrap(), path: "test/file1.txt".to_owned() }));
+    assert!(issues.contains(&FsckIssue::MissingFile { layer: image.hash.clone(), path: "test/file2.txt".to_owned() }));
+    assert!(issues.contains(&FsckIssue::MissingLayer { layer: image.hash.clone(), reference: directory_layer.hash.clone() }));
+    assert!(issues.contains(&FsckIssue::DanglingImage { tag: ImageTag::from_str("dangling").unwrap(), hash: missing_hash.clone() }));
+    assert!(issues.iter().any(|issue| matches!(issue, FsckIssue::StaleUnpacking { hash, .. } if hash == &image.hash)));
+
+    let remaining_issues = tokio::runtime::Runtime::new().unwrap().block_on(
+        image_manager.repair_fsck_issues(issues, None)
+    ).unwrap();
+    assert_eq!(3, remaining_issues.len());
+    assert!(remaining_issues.iter().all(|issue| issue.requires_registry()));
+
+    assert!(!orphan_folder.exists());
+    assert!(download_folder.exists());
+    assert!(!download_file.exists());
+    assert!(!staging_folder.exists());
+    assert_eq!(remaining_issues, image_manager.fsck().unwrap());
+}
+
+#[test]
+fn test_fsck_live_temporary_files() {
+    use crate::image_manager::ConsolePrinter;
+
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+    let config = ImageManagerConfig::with_base_folder(tmp_folder.owned());
+    let mut image_manager = ImageManager::new(config.clone(), ConsolePrinter::new()).unwrap();
+
+    // Like a build or backup that is still running
+    let staging_folder = config.base_folder().join("tmp-staging1234");
+    std::fs::create_dir_all(&staging_folder).unwrap();
+    let backup_file = config.base_folder().join("tmp-backup1234");
+    std::fs::write(&backup_file, "backup").unwrap();
+
+    let issues = image_manager.fsck().unwrap();
+    assert_eq!(Vec::<FsckIssue>::new(), issues);
+
+    let remaining_issues = tokio::runtime::Runtime::new().unwrap().block_on(
+        image_manager.repair_fsck_issues(issues, None)
+    ).unwrap();
+    assert!(remaining_issues.is_empty());
+    assert!(staging_folder.exists());
+    assert!(backup_file.exists());
+}
+
+#[test]
+fn test_find_files() {
+    use std::str::FromStr;
+
+    use crate::image_manager::ConsolePrinter;
+
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+
+    {
+        let config = ImageManagerConfig::with_base_folder(tmp_folder.owned());
+
+        let mut image_manager = ImageManager::new(config, ConsolePrinter::new()).unwrap();
+
+        super::test_helpers::build_image(
+            &mut image_manager,
+            Path::new("testdata/definitions/simple1.labarfile"),
+            ImageTag::from_str("test").unwrap()
+        ).unwrap();
+
+        super::test_helpers::build_image(
+            &mut image_manager,
+            Path::new("testdata/definitions/with_image_ref.labarfile"),
+            ImageTag::from_str("that").unwrap()
+        ).unwrap();
+
+        let found_files = image_manager.find_files(&FileQuery::Path("/file1.*".to_owned())).unwrap();
+        assert_eq!(1, found_files.len());
+        assert_eq!("file1.txt", found_files[0].file.path);
+        assert_eq!(
+            vec![ImageTag::from_str("test").unwrap(), ImageTag::from_str("that").unwrap()],
+            found_files[0].images
+        );
+
+        let content_hash = crate::content::compute_content_hash(Path::new("testdata/rawdata/file2.txt")).unwrap();
+        let found_files = image_manager.find_files(&FileQuery::ContentHash(content_hash)).unwrap();
+        assert_eq!(1, found_files.len());
+        assert_eq!("file2.txt", found_files[0].file.path);
+        assert_eq!(vec![ImageTag::from_str("that").unwrap()], found_files[0].images);
+
+        image_manager.remove_image(&ImageTag::from_str("that").unwrap(), false).unwrap();
+        let found_files = image_manager.find_files(&FileQuery::Path("*".to_owned())).unwrap();
+        assert_eq!(1, found_files.len());
+        assert_eq!("file1.txt", found_files[0].file.path);
+    }
+}
+
+#[test]
+fn test_purge_retention_policy() {
+    use std::str::FromStr;
+
+    use crate::image_manager::ConsolePrinter;
+
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+
+    {
+        let config = ImageManagerConfig::with_base_folder(tmp_folder.owned());
+
+        let mut image_manager = ImageManager::new(config, ConsolePrinter::new()).unwrap();
+
+        for (definition, tag) in [("simple1", "test:v1"), ("simple3", "test:v2"), ("simple6", "test:v3"), ("overwrite", "other:v1")] {
+            super::test_helpers::build_image(
+                &mut image_manager,
+                Path::new(&format!("testdata/definitions/{}.labarfile", definition)),
+                ImageTag::from_str(tag).unwrap()
+            ).unwrap();
+        }
+
+        let count_layers = |image_manager: &ImageManager| {
+            let session = image_manager.state_manager.pooled_session().unwrap();
+            image_manager.layer_manager.all_layers(&session).unwrap().len()
+        };
+        let num_layers = count_layers(&image_manager);
+
+        let policy = RetentionPolicy { keep_recent: Some(1), max_age: Some(Duration::ZERO), ..Default::default() };
+        let plan = image_manager.purge(&policy, true).unwrap();
+        assert_eq!(vec![ImageTag::from_str("test:v1").unwrap(), ImageTag::from_str("test:v2").unwrap()], plan.images);
+        assert_eq!(3, plan.layers.len());
+        assert_eq!(num_layers, count_layers(&image_manager));
+
+        let policy = RetentionPolicy { keep_recent: Some(1), max_age: Some(Duration::ZERO), keep_tags: Some(Regex::new("v1$").unwrap()), ..Default::default() };
+        let plan = image_manager.purge(&policy, true).unwrap();
+        assert_eq!(vec![ImageTag::from_str("test:v2").unwrap()], plan.images);
+
+        let policy = RetentionPolicy { max_store_size: Some(DataSize(0)), protect_label: Some("version=2.0".to_owned()), ..Default::default() };
+        let plan = image_manager.purge(&policy, true).unwrap();
+        assert_eq!(
+            vec![
+                ImageTag::from_str("test:latest").unwrap(),
+                ImageTag::from_str("test:v1").unwrap(),
+                ImageTag::from_str("test:v2").unwrap(),
+                ImageTag::from_str("test:v3").unwrap()
+            ],
+            plan.images
+        );
+
+        let policy = RetentionPolicy { keep_recent: Some(1), max_age: Some(Duration::ZERO), ..Default::default() };
+        image_manager.purge(&policy, false).unwrap();
+        let images = image_manager.list_images(None).unwrap();
+        assert_eq!(4, images.len());
+        assert_eq!(num_layers - 3, count_layers(&image_manager));
+    }
+}
+
+#[test]
+fn test_purge_keep_recent_with_max_age() {
+    use std::str::FromStr;
+
+    use crate::image_manager::ConsolePrinter;
+
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+
+    {
+        let config = ImageManagerConfig::with_base_folder(tmp_folder.owned());
+
+        let mut image_manager = ImageManager::new(config, ConsolePrinter::new()).unwrap();
+
+        for (definition, tag) in [("simple1", "test:v1"), ("simple3", "test:v2"), ("simple6", "test:v3")] {
+            super::test_helpers::build_image(
+                &mut image_manager,
+                Path::new(&format!("testdata/definitions/{}.labarfile", definition)),
+                ImageTag::from_str(tag).unwrap()
+            ).unwrap();
+        }
+
+        // The most recent tags are only protected, the others are only removed if too old
+        let policy = RetentionPolicy { keep_recent: Some(1), ..Default::default() };
+        let plan = image_manager.purge(&policy, true).unwrap();
+        assert!(plan.images.is_empty());
+
+        let policy = RetentionPolicy { keep_recent: Some(1), max_age: Some(Duration::from_secs(24 * 60 * 60)), ..Default::default() };
+        let plan = image_manager.purge(&policy, true).unwrap();
+        assert!(plan.images.is_empty());
+
+        let policy = RetentionPolicy { keep_recent: Some(2), max_age: Some(Duration::ZERO), ..Default::default() };
+        let plan = image_manager.purge(&policy, true).unwrap();
+        assert_eq!(vec![ImageTag::from_str("test:v1").unwrap()], plan.images);
+    }
+}
+
+#[test]
+fn test_last_used() {
+    use std::str::FromStr;
+
+    use crate::image_manager::ConsolePrinter;
+
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+
+    {
+        let config = ImageManagerConfig::with_base_folder(tmp_folder.owned());
+
+        let mut image_manager = ImageManager::new(config, ConsolePrinter::new()).unwrap();
+
+        super::test_helpers::build_image(
+            &mut image_manager,
+            Path::new("testdata/definitions/simple1.labarfile"),
+            ImageTag::from_str("first").unwrap()
+        ).unwrap();
+
+        super::test_helpers::build_image(
+            &mut image_manager,
+            Path::new("testdata/definitions/simple6.labarfile"),
+            ImageTag::from_str("second").unwrap()
+        ).unwrap();
+
+        let first = image_manager.resolve_image(&ImageTag::from_str("first").unwrap()).unwrap();
+        let second = image_manager.resolve_image(&ImageTag::from_str("second").unwrap()).unwrap();
+        assert!(first.last_used.is_some());
+        assert!(first.last_used < second.last_used);
+
+        assert!(image_manager.get_file(&Reference::from_str("first").unwrap(), "file1.txt").unwrap().is_some());
+        let first = image_manager.resolve_image(&ImageTag::from_str("first").unwrap()).unwrap();
+        assert!(first.last_used > second.last_used);
+
+        let policy = RetentionPolicy { max_store_size: Some(first.size), ..Default::default() };
+        let plan = image_manager.purge(&policy, true).unwrap();
+        assert_eq!(vec![ImageTag::from_str("second").unwrap()], plan.images);
+    }
+}
+
+#[test]
+fn test_failed_usage_tracking() {
+    use std::str::FromStr;
+
+    use crate::image_manager::ConsolePrinter;
+
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+
+    {
+        let config = ImageManagerConfig::with_base_folder(tmp_folder.owned());
+
+        let mut image_manager = ImageManager::new(config, ConsolePrinter::new()).unwrap();
+
+        // Like a busy database, all writes of the usage fail
+        image_manager.state_manager.pooled_session().unwrap().connection.execute_batch(
+            r#"
+            CREATE TRIGGER fail_layer_usage BEFORE INSERT ON layer_usage BEGIN SELECT RAISE(FAIL, 'database is locked'); END;
+            "#
+        ).unwrap();
+
+        let result = super::test_helpers::build_image(
+            &mut image_manager,
+            Path::new("testdata/definitions/simple1.labarfile"),
+            ImageTag::from_str("test").unwrap()
+        );
+        assert!(result.is_ok(), "{}", result.unwrap_err());
+
+        let result = image_manager.get_file(&Reference::from_str("test").unwrap(), "file1.txt");
+        assert!(matches!(result, Ok(Some(_))));
+
+        let image = image_manager.resolve_image(&ImageTag::from_str("test").unwrap()).unwrap();
+        assert_eq!(None, image.last_used);
+    }
+}
+
+#[test]
+fn test_pin_image() {
+    use std::str::FromStr;
+
+    use crate::image_manager::ConsolePrinter;
+
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+
+    {
+        let config = ImageManagerConfig::with_base_folder(tmp_folder.owned());
+
+        let mut image_manager = ImageManager::new(config, ConsolePrinter::new()).unwrap();
+
+        super::test_helpers::build_image(
+            &mut image_manager,
+            Path::new("testdata/definitions/simple1.labarfile"),
+            ImageTag::from_str("test").unwrap()
+        ).unwrap();
+
+        let tag = ImageTag::from_str("test").unwrap();
+        image_manager.pin_image(&tag).unwrap();
+        assert!(image_manager.resolve_image(&tag).unwrap().pinned);
+        assert!(image_manager.pin_image(&ImageTag::from_str("other").unwrap()).is_err());
+
+        let result = image_manager.remove_image(&tag, false);
+        assert!(matches!(result, Err(ImageManagerError::ImagePinned { .. })));
+
+        let policy = RetentionPolicy { max_store_size: Some(DataSize(0)), ..Default::default() };
+        assert!(image_manager.purge(&policy, false).unwrap().images.is_empty());
+        assert_eq!(1, image_manager.list_images(None).unwrap().len());
+
+        image_manager.remove_image(&tag, true).unwrap();
+        assert_eq!(0, image_manager.list_images(None).unwrap().len());
+        assert!(!image_manager.is_image_pinned(&tag).unwrap());
+    }
+}
+
+#[test]
+fn test_pin_image_replace() {
+    use std::str::FromStr;
+
+    use crate::image_manager::ConsolePrinter;
+
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+
+    {
+        let config = ImageManagerConfig::with_base_folder(tmp_folder.owned());
+
+        let mut image_manager = ImageManager::new(config, ConsolePrinter::new()).unwrap();
+
+        let tag = ImageTag::from_str("test").unwrap();
+        let image = super::test_helpers::build_image(
+            &mut image_manager,
+            Path::new("testdata/definitions/simple1.labarfile"),
+            tag.clone()
+        ).unwrap().image;
+        image_manager.pin_image(&tag).unwrap();
+
+        let other_image = super::test_helpers::build_image(
+            &mut image_manager,
+            Path::new("testdata/definitions/simple6.labarfile"),
+            ImageTag::from_str("other").unwrap()
+        ).unwrap().image;
+
+        let result = image_manager.tag_image(&other_image.hash.clone().to_ref(), &tag, false);
+        assert!(matches!(result, Err(ImageManagerError::ImagePinned { .. })));
+
+        let result = super::test_helpers::build_image(
+            &mut image_manager,
+            Path::new("testdata/definitions/simple6.labarfile"),
+            tag.clone()
+        );
+        assert_eq!(Some(ImageManagerError::ImagePinned { tag: tag.clone() }.to_string()), result.err());
+        assert_eq!(image.hash, image_manager.get_image(&tag).unwrap().hash);
+
+        // The same image can always be tagged again
+        assert!(image_manager.tag_image(&image.hash.clone().to_ref(), &tag, false).is_ok());
+
+        assert!(image_manager.tag_image(&other_image.hash.clone().to_ref(), &tag, true).is_ok());
+        assert_eq!(other_image.hash, image_manager.get_image(&tag).unwrap().hash);
+        assert!(image_manager.is_image_pinned(&tag).unwrap());
+    }
+}
+
+#[test]
+fn test_image_id_prefix() {
+    use std::str::FromStr;
+
+    use crate::image_manager::ConsolePrinter;
+
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+
+    {
+        let config = ImageManagerConfig::with_base_folder(tmp_folder.owned());
+
+        let mut image_manager = ImageManager::new(config, ConsolePrinter::new()).unwrap();
+
+        let image = super::test_helpers::build_image(
+            &mut image_manager,
+            Path::new("testdata/definitions/simple1.labarfile"),
+            ImageTag::from_str("test").unwrap()
+        ).unwrap().image;
+
+        let reference = Reference::from_str(image.hash.short()).unwrap();
+        assert_eq!(image.hash, image_manager.get_layer(&reference).unwrap().hash);
+        assert_eq!(vec![image.tag.clone()], image_manager.get_image_tags(&reference).unwrap());
+
+        for hash in ["aaaaaaaa11111111111111111111111111111111111111111111111111111111", "aaaaaaaa22222222222222222222222222222222222222222222222222222222"] {
+            image_manager.insert_layer(Layer::new(None, ImageId::from_str(hash).unwrap(), Vec::new(), DataSize(0))).unwrap();
+        }
+
+        assert!(image_manager.get_layer(&Reference::from_str("aaaaaaaa1").unwrap()).is_ok());
+
+        let result = image_manager.get_layer(&Reference::from_str("aaaaaaaa").unwrap());
+        match result {
+            Err(ImageManagerError::AmbiguousReference { candidates, .. }) => assert_eq!(2, candidates.len()),
+            _ => panic!("Expected ambiguous reference")
+        }
+
+        let result = image_manager.get_layer(&Reference::from_str("bbbbbbbb").unwrap());
+        assert!(matches!(result, Err(ImageManagerError::ReferenceNotFound { .. })));
+    }
+}
@@ -1,5 +1,8 @@
 use std::path::{Path, PathBuf};
 use serde::{Deserialize, Serialize};
+
+use crate::helpers::DataSize;
+use crate::reference::ImageTag;
 use zip::result::ZipError;
 
 mod printing;
@@ -29,6 +32,15 @@ pub enum ImageManagerError {
     SelfReferential,
     InvalidUnpack,
     InvalidImageImport,
+    InvalidBackup,
+    InvalidRebase,
+    NotBasedOn { reference: Reference, base: ImageId },
+    QuotaExceeded { quota: Quota, size: DataSize, limit: DataSize },
+    PathNotInImage { reference: Reference, path: String },
+    TemplateVariableNotFound { path: String, variable: String },
+    StateVersionNotSupported { version: u32, supported: u32 },
+    ImagePinned { tag: ImageTag },
+    AmbiguousReference { prefix: String, candidates: Vec<ImageId> },
     ZIPError(ZipError),
     Sql(rusqlite::Error),
     Serialization(serde_json::Error),
@@ -123,6 +135,38 @@ impl std::fmt::Display for ImageManagerError {
             ImageManagerError::InvalidImageImport => {
                 write!(f, "Invalid image to import")
             }
+            ImageManagerError::InvalidBackup => {
+                write!(f, "Invalid backup file")
+            }
+            ImageManagerError::InvalidRebase => {
+                write!(f, "Invalid rebase")
+            }
+            ImageManagerError::NotBasedOn { reference, base } => {
+                write!(f, "The image {} is not based on {}", reference, base)
+            }
+            ImageManagerError::QuotaExceeded { quota, size, limit } => {
+                write!(f, "The {} quota of {} would be exceeded ({})", quota, limit, size)
+            }
+            ImageManagerError::PathNotInImage { reference, path } => {
+                write!(f, "The path '{}' does not exist in the image {}", path, reference)
+            }
+            ImageManagerError::TemplateVariableNotFound { path, variable } => {
+                write!(f, "The variable '{}' used by the template {} is not defined", variable, path)
+            }
+            ImageManagerError::StateVersionNotSupported { version, supported } => {
+                write!(f, "The state database has version {} but this version of labar only supports up to version {}", version, supported)
+            }
+            ImageManagerError::ImagePinned { tag } => {
+                write!(f, "The image {} is pinned", tag)
+            }
+            ImageManagerError::AmbiguousReference { prefix, candidates } => {
+                write!(
+                    f,
+                    "The image id prefix {} is ambiguous, it matches: {}",
+                    prefix,
+                    candidates.iter().map(|candidate| candidate.to_string()).collect::<Vec<_>>().join(", ")
+                )
+            }
             ImageManagerError::Sql(err) => {
                 write!(f, "SQL: {}", err)
             }
@@ -147,17 +191,29 @@ pub struct ImageManagerConfig {
     pub accept_self_signed: bool,
     pub max_wait_for_upstream_pull: f64,
     pub upstream_pull_check: f64,
-    pub storage_mode: StorageMode
+    pub storage_mode: StorageMode,
+    #[serde(default = "default_tag_latest")]
+    pub tag_latest: bool,
+    #[serde(default)]
+    pub max_layer_size: Option<DataSize>,
+    #[serde(default)]
+    pub max_image_size: Option<DataSize>,
+    #[serde(default)]
+    pub max_store_size: Option<DataSize>
 }
 
 impl ImageManagerConfig {
     pub fn new() -> ImageManagerConfig {
         ImageManagerConfig {
-            base_folder: dirs::home_dir().unwrap().join(".labar"),
+            base_folder: ImageManagerConfig::default_base_folder(),
             accept_self_signed: true,
             max_wait_for_upstream_pull: 5.0 * 60.0,
             upstream_pull_check: 1.0,
-            storage_mode: StorageMode::AlwaysUncompressed
+            storage_mode: StorageMode::AlwaysUncompressed,
+            tag_latest: default_tag_latest(),
+            max_layer_size: None,
+            max_image_size: None,
+            max_store_size: None
         }
     }
 
@@ -167,10 +223,22 @@ impl ImageManagerConfig {
         config
     }
 
+    /// The folder given by the LABAR_HOME environment variable, or ~/.labar if not set.
+    pub fn default_base_folder() -> PathBuf {
+        std::env::var_os("LABAR_HOME")
+            .filter(|home| !home.is_empty())
+            .map(PathBuf::from)
+            .unwrap_or_else(|| dirs::home_dir().unwrap().join(".labar"))
+    }
+
     pub fn base_folder(&self) -> &Path {
         &self.base_folder
     }
 
+    pub fn set_base_folder(&mut self, base_folder: PathBuf) {
+        self.base_folder = base_folder;
+    }
+
     pub fn layers_base_folder(&self) -> PathBuf {
         self.base_folder().join("layers")
     }
@@ -178,6 +246,23 @@ impl ImageManagerConfig {
     pub fn get_layer_folder(&self, hash: &ImageId) -> PathBuf {
         self.layers_base_folder().join(&Path::new(&hash.to_string()))
     }
+
+    pub fn check_quota(&self, quota: Quota, size: DataSize) -> ImageManagerResult<()> {
+        let limit = match quota {
+            Quota::LayerSize => self.max_layer_size,
+            Quota::ImageSize => self.max_image_size,
+            Quota::StoreSize => self.max_store_size
+        };
+
+        match limit {
+            Some(limit) if size > limit => Err(ImageManagerError::QuotaExceeded { quota, size, limit }),
+            _ => Ok(())
+        }
+    }
+}
+
+fn default_tag_latest() -> bool {
+    true
 }
 
 impl Default for ImageManagerConfig {
@@ -186,6 +271,23 @@ impl Default for ImageManagerConfig {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Quota {
+    LayerSize,
+    ImageSize,
+    StoreSize
+}
+
+impl std::fmt::Display for Quota {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Quota::LayerSize => write!(f, "layer size"),
+            Quota::ImageSize => write!(f, "image size"),
+            Quota::StoreSize => write!(f, "store size")
+        }
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub enum StorageMode {
     AlwaysUncompressed,
@@ -197,10 +299,13 @@ pub enum StorageMode {
 pub use manager::ImageManager;
 pub use crate::image_parser::ImageParseError;
 pub use crate::reference::{ImageId, Reference};
-pub use crate::image_manager::manager::{ListContentEntry, PullRequest};
-pub use printing::{ConsolePrinter, EmptyPrinter, Printer, PrinterRef};
+pub use crate::image_manager::manager::{FileQuery, ListContentEntry, PullRequest};
+pub use printing::{BuildEvent, ConsolePrinter, EmptyPrinter, JsonLinesPrinter, Printer, PrinterRef};
 pub use details::registry::RegistryError;
-pub use details::build::BuildRequest;
+pub use details::build::{BuildRequest, BuildResult, BuildStep, provenance_labels, reproducible_build_time};
+pub use details::rewrite::{RebaseRequest, SquashRequest};
+pub use details::retention::{PurgePlan, RetentionPolicy};
+pub use details::fsck::FsckIssue;
 pub use details::unpack::{UnpackFile, UnpackRequest};
-pub use details::state::{PooledStateSession, SqlResult, StateManager, StateSession};
+pub use details::state::{PooledStateSession, SqlResult, StateManager, StateMigration, StateSession, STATE_VERSION};
 pub use details::storage::{ArcImageStorage, ImageStorage, ImageStorageError, ImageStorageResult};
\ No newline at end of file
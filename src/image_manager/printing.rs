@@ -3,10 +3,39 @@ use std::sync::Arc;
 
 use crossterm::{cursor, terminal, ExecutableCommand};
 use crossterm::terminal::ClearType;
+use serde::Serialize;
+
+use crate::helpers::DataSize;
+use crate::image::Image;
+use crate::reference::ImageId;
 
 pub trait Printer {
     fn println(&self, line: &str);
     fn refresh_latest_line(&self, line: &str);
+
+    /// Structured events for machine-readable output, ignored by default.
+    fn event(&self, _event: &BuildEvent) {
+
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum BuildEvent {
+    StepStarted { step: usize, num_steps: usize, input_line: String },
+    StepFinished { step: usize, num_steps: usize, #[serde(flatten)] result: StepResult },
+    BuildFinished { image: Image, built_layers: usize, duration_seconds: f64 },
+    BuildFailed { message: String }
+}
+
+/// The result of a finished step, as part of the build events.
+#[derive(Debug, Clone, Serialize)]
+pub struct StepResult {
+    pub input_line: String,
+    pub hash: ImageId,
+    pub cached: bool,
+    pub bytes_copied: DataSize,
+    pub duration_seconds: f64
 }
 
 pub type PrinterRef = Arc<dyn Printer + Send + Sync>;
@@ -52,4 +81,29 @@ impl Printer for EmptyPrinter {
     fn refresh_latest_line(&self, _line: &str) {
 
     }
-}
\ No newline at end of file
+}
+
+/// Prints the structured events as JSON lines, suppressing the regular output.
+pub struct JsonLinesPrinter {
+
+}
+
+impl JsonLinesPrinter {
+    pub fn new() -> Arc<JsonLinesPrinter> {
+        Arc::new(JsonLinesPrinter { })
+    }
+}
+
+impl Printer for JsonLinesPrinter {
+    fn println(&self, _line: &str) {
+
+    }
+
+    fn refresh_latest_line(&self, _line: &str) {
+
+    }
+
+    fn event(&self, event: &BuildEvent) {
+        println!("{}", serde_json::to_string(event).unwrap());
+    }
+}
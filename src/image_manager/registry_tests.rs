@@ -8,7 +8,7 @@ use tokio::time::Instant;
 
 use crate::assert_file_content_eq;
 use crate::helpers::DataSize;
-use crate::image_manager::{ConsolePrinter, EmptyPrinter, ImageManager, ImageManagerConfig, PullRequest, Reference, StorageMode, UnpackRequest};
+use crate::image_manager::{ConsolePrinter, EmptyPrinter, ImageManager, ImageManagerConfig, ImageManagerError, PullRequest, Quota, Reference, StorageMode, UnpackRequest};
 use crate::image_manager::details::registry::RegistryManager;
 use crate::reference::ImageTag;
 use crate::registry::auth::AccessRight;
@@ -85,6 +85,71 @@ async fn test_pull() {
     }
 }
 
+#[tokio::test]
+async fn test_pull_quota() {
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+    let tmp_registry_folder = crate::test_helpers::TempFolder::new();
+
+    let address: SocketAddr = generate_registry_address().parse().unwrap();
+
+    let image_tag = ImageTag::with_registry(&address.to_string(), "test", "latest");
+
+    // Build image inside registry
+    {
+        let config = ImageManagerConfig::with_base_folder(tmp_registry_folder.owned());
+        let mut image_manager = ImageManager::new(config, ConsolePrinter::new()).unwrap();
+
+        super::test_helpers::build_image(
+            &mut image_manager,
+            Path::new("testdata/definitions/simple5.labarfile"),
+            image_tag.clone()
+        ).unwrap();
+    }
+
+    tokio::spawn(crate::registry::run(create_registry_config(address, &tmp_registry_folder)));
+
+    // Wait until registry starts
+    if !registry_is_reachable(&address.to_string(), 1.0).await {
+        panic!("Registry is not reachable");
+    }
+
+    let pull_with_config = |config: ImageManagerConfig| {
+        let image_tag = image_tag.clone();
+        async move {
+            let mut image_manager = ImageManager::new(config, ConsolePrinter::new()).unwrap();
+            image_manager.login(&address.to_string(), "guest", "guest").await.unwrap();
+            let pull_result = image_manager.pull(PullRequest::from_tag(&image_tag)).await;
+            (pull_result, image_manager.list_images(None).unwrap().len())
+        }
+    };
+
+    let mut config = ImageManagerConfig::with_base_folder(tmp_folder.owned());
+    config.max_image_size = Some(DataSize(2000));
+    let (pull_result, num_images) = pull_with_config(config).await;
+    assert!(matches!(pull_result, Err(ImageManagerError::QuotaExceeded { quota: Quota::ImageSize, size: DataSize(3001), limit: DataSize(2000) })));
+    assert_eq!(0, num_images);
+
+    let mut config = ImageManagerConfig::with_base_folder(tmp_folder.owned());
+    config.max_layer_size = Some(DataSize(1000));
+    let (pull_result, num_images) = pull_with_config(config).await;
+    assert!(matches!(pull_result, Err(ImageManagerError::QuotaExceeded { quota: Quota::LayerSize, .. })));
+    assert_eq!(0, num_images);
+
+    // No layers are pulled if all of them do not fit in the store
+    let mut config = ImageManagerConfig::with_base_folder(tmp_folder.owned());
+    config.max_store_size = Some(DataSize(2500));
+    let (pull_result, num_images) = pull_with_config(config.clone()).await;
+    assert!(matches!(pull_result, Err(ImageManagerError::QuotaExceeded { quota: Quota::StoreSize, size: DataSize(3001), limit: DataSize(2500) })));
+    assert_eq!(0, num_images);
+    assert_eq!(0, std::fs::read_dir(config.layers_base_folder()).map(|entries| entries.count()).unwrap_or(0));
+
+    let mut config = ImageManagerConfig::with_base_folder(tmp_folder.owned());
+    config.max_store_size = Some(DataSize(4000));
+    let (pull_result, num_images) = pull_with_config(config).await;
+    assert!(pull_result.is_ok(), "{}", pull_result.unwrap_err());
+    assert_eq!(1, num_images);
+}
+
 #[tokio::test]
 async fn test_push_pull() {
     let tmp_folder = crate::test_helpers::TempFolder::new();
@@ -129,7 +194,7 @@ async fn test_push_pull() {
         assert_eq!(&image_tag, &remote_images[0].image.tag);
 
         // Remove in order to pull
-        assert!(image_manager.remove_image(&image.tag).is_ok());
+        assert!(image_manager.remove_image(&image.tag, false).is_ok());
 
         // Pull
         let pull_result = image_manager.pull(PullRequest::from_tag(&image_tag)).await;
@@ -205,7 +270,7 @@ async fn test_push_pull_default_registry() {
         assert_eq!(&image_tag.clone().set_registry_opt(Some(default_registry)), &remote_images[0].image.tag);
 
         // Remove in order to pull
-        assert!(image_manager.remove_image(&image.tag).is_ok());
+        assert!(image_manager.remove_image(&image.tag, false).is_ok());
 
         // Pull
         let pull_result = image_manager.pull(PullRequest {
@@ -214,6 +279,7 @@ async fn test_push_pull_default_registry() {
             new_tag: None,
             retry: None,
             verbose_output: false,
+            force: false
         }).await;
         assert!(pull_result.is_ok(), "{}", pull_result.unwrap_err());
         let pull_image = pull_result.unwrap();
@@ -285,8 +351,8 @@ async fn test_push_pull_with_ref() {
         assert_eq!(&image_tag, &remote_images[0].image.tag);
 
         // Remove in order to pull
-        assert!(image_manager.remove_image(&image.tag).is_ok());
-        assert!(image_manager.remove_image(&image_referred.tag).is_ok());
+        assert!(image_manager.remove_image(&image.tag, false).is_ok());
+        assert!(image_manager.remove_image(&image_referred.tag, false).is_ok());
 
         // Pull
         let pull_result = image_manager.pull(PullRequest::from_tag(&image_tag)).await;
@@ -352,7 +418,7 @@ async fn test_push_pull_compressed() {
         assert_eq!(1, remote_images.unwrap().len());
 
         // Remove in order to pull
-        assert!(image_manager.remove_image(&image.tag).is_ok());
+        assert!(image_manager.remove_image(&image.tag, false).is_ok());
 
         // Pull
         let pull_result = image_manager.pull(PullRequest::from_tag(&image_tag)).await;
@@ -420,7 +486,7 @@ async fn test_push_pull_compressed_storage_mode() {
         assert_eq!(1, remote_images.unwrap().len());
 
         // Remove in order to pull
-        assert!(image_manager.remove_image(&image.tag).is_ok());
+        assert!(image_manager.remove_image(&image.tag, false).is_ok());
 
         // Pull
         let pull_result = image_manager.pull(PullRequest::from_tag(&image_tag)).await;
@@ -487,7 +553,7 @@ async fn test_push_pull_compressed_decompress_on_download() {
         assert_eq!(1, remote_images.unwrap().len());
 
         // Remove in order to pull
-        assert!(image_manager.remove_image(&image.tag).is_ok());
+        assert!(image_manager.remove_image(&image.tag, false).is_ok());
 
         // Pull
         let pull_result = image_manager.pull(PullRequest::from_tag(&image_tag)).await;
@@ -560,7 +626,7 @@ async fn test_push_pull_external_storage() {
         assert_eq!(&image_tag, &remote_images[0].image.tag);
 
         // Remove in order to pull
-        assert!(image_manager.remove_image(&image.tag).is_ok());
+        assert!(image_manager.remove_image(&image.tag, false).is_ok());
 
         // Pull
         let pull_result = image_manager.pull(PullRequest::from_tag(&image_tag)).await;
@@ -915,6 +981,118 @@ async fn test_remove() {
     }
 }
 
+#[tokio::test]
+async fn test_remove_pinned() {
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+    let tmp_registry_folder = crate::test_helpers::TempFolder::new();
+
+    let address: SocketAddr = generate_registry_address().parse().unwrap();
+    tokio::spawn(crate::registry::run(create_registry_config(address, &tmp_registry_folder)));
+
+    // Wait until registry starts
+    if !registry_is_reachable(&address.to_string(), 1.0).await {
+        panic!("Registry is not reachable");
+    }
+
+    {
+        let config = ImageManagerConfig::with_base_folder(tmp_folder.owned());
+        let mut image_manager = ImageManager::new(config, ConsolePrinter::new()).unwrap();
+
+        // Login
+        let login_result = image_manager.login(&address.to_string(), "guest", "guest").await;
+        assert!(login_result.is_ok(), "{}", login_result.unwrap_err());
+
+        let image_tag = ImageTag::with_registry(&address.to_string(), "test", "latest");
+
+        // Build & push
+        let image = super::test_helpers::build_image(
+            &mut image_manager,
+            Path::new("testdata/definitions/simple4.labarfile"),
+            image_tag.clone()
+        ).unwrap().image;
+
+        let push_result = image_manager.push(&image.tag, None).await;
+        assert!(push_result.is_ok(), "{}", push_result.unwrap_err());
+
+        // Pin in registry
+        let registry_config = create_registry_config(address, &tmp_registry_folder);
+        let mut registry_image_manager = ImageManager::new(registry_config.image_manager_config(), ConsolePrinter::new()).unwrap();
+        registry_image_manager.pin_image(&image_tag).unwrap();
+
+        // Remove and overwrite are rejected
+        assert!(image_manager.remove_image_in_registry(&image.tag, None).await.is_err());
+
+        super::test_helpers::build_image(
+            &mut image_manager,
+            Path::new("testdata/definitions/simple1.labarfile"),
+            image_tag.clone()
+        ).unwrap();
+        assert!(image_manager.push(&image_tag, None).await.is_err());
+        assert_eq!(image.hash, image_manager.resolve_image_in_registry(&address.to_string(), &image_tag).await.unwrap().image.hash);
+
+        // Remove after unpinning
+        registry_image_manager.unpin_image(&image_tag).unwrap();
+        assert!(image_manager.remove_image_in_registry(&image.tag, None).await.is_ok());
+    }
+}
+
+#[tokio::test]
+async fn test_pull_pinned() {
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+    let tmp_registry_folder = crate::test_helpers::TempFolder::new();
+
+    let address: SocketAddr = generate_registry_address().parse().unwrap();
+
+    let image_tag = ImageTag::with_registry(&address.to_string(), "test", "latest");
+
+    // Build image inside registry
+    let remote_image = {
+        let config = ImageManagerConfig::with_base_folder(tmp_registry_folder.owned());
+        let mut image_manager = ImageManager::new(config, ConsolePrinter::new()).unwrap();
+
+        super::test_helpers::build_image(
+            &mut image_manager,
+            Path::new("testdata/definitions/simple4.labarfile"),
+            image_tag.clone()
+        ).unwrap().image
+    };
+
+    tokio::spawn(crate::registry::run(create_registry_config(address, &tmp_registry_folder)));
+
+    // Wait until registry starts
+    if !registry_is_reachable(&address.to_string(), 1.0).await {
+        panic!("Registry is not reachable");
+    }
+
+    {
+        let config = ImageManagerConfig::with_base_folder(tmp_folder.owned());
+        let mut image_manager = ImageManager::new(config, ConsolePrinter::new()).unwrap();
+        image_manager.login(&address.to_string(), "guest", "guest").await.unwrap();
+
+        let local_tag = ImageTag::from_str("local").unwrap();
+        let local_image = super::test_helpers::build_image(
+            &mut image_manager,
+            Path::new("testdata/definitions/simple1.labarfile"),
+            local_tag.clone()
+        ).unwrap().image;
+        image_manager.pin_image(&local_tag).unwrap();
+
+        // Pulling onto a pinned tag is rejected
+        let mut pull_request = PullRequest::from_tag(&image_tag);
+        pull_request.new_tag = Some(local_tag.clone());
+        let pull_result = image_manager.pull(pull_request).await;
+        assert!(matches!(pull_result, Err(ImageManagerError::ImagePinned { .. })));
+        assert_eq!(local_image.hash, image_manager.get_image(&local_tag).unwrap().hash);
+
+        let mut pull_request = PullRequest::from_tag(&image_tag);
+        pull_request.new_tag = Some(local_tag.clone());
+        pull_request.force = true;
+        let pull_result = image_manager.pull(pull_request).await;
+        assert!(pull_result.is_ok(), "{}", pull_result.unwrap_err());
+        assert_eq!(remote_image.hash, image_manager.get_image(&local_tag).unwrap().hash);
+    }
+}
+
 #[tokio::test]
 async fn test_remove_external_storage() {
     let tmp_folder = crate::test_helpers::TempFolder::new();
@@ -971,6 +1149,78 @@ async fn test_remove_external_storage() {
     }
 }
 
+#[tokio::test]
+async fn test_backup_restore() {
+    use crate::registry::auth::{AddUserResult, Password, SqliteAuthProvider};
+
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+    let tmp_registry_folder = crate::test_helpers::TempFolder::new();
+    let tmp_restored_registry_folder = crate::test_helpers::TempFolder::new();
+    let backup_file = tmp_folder.owned().join("backup.zip");
+
+    let address: SocketAddr = generate_registry_address().parse().unwrap();
+    let image_tag = ImageTag::with_registry(&address.to_string(), "test", "latest");
+
+    // Build image and add user inside registry, then back it up
+    {
+        let registry_config = create_registry_config(address, &tmp_registry_folder);
+        let mut image_manager = ImageManager::new(registry_config.image_manager_config(), ConsolePrinter::new()).unwrap();
+
+        super::test_helpers::build_image(
+            &mut image_manager,
+            Path::new("testdata/definitions/simple4.labarfile"),
+            image_tag.clone()
+        ).unwrap();
+
+        let auth_provider = SqliteAuthProvider::from_registry_config(&registry_config).unwrap();
+        assert!(matches!(
+            auth_provider.add_user("backup".to_owned(), Password::from_plain_text("backup"), vec![AccessRight::List, AccessRight::Download], false),
+            AddUserResult::Added
+        ));
+
+        tmp_folder.create().unwrap();
+        let backup_result = image_manager.backup(&backup_file, true);
+        assert!(backup_result.is_ok(), "{}", backup_result.unwrap_err());
+    }
+
+    // Restore into another registry
+    let registry_config = create_registry_config(address, &tmp_restored_registry_folder);
+    {
+        let mut image_manager = ImageManager::new(registry_config.image_manager_config(), ConsolePrinter::new()).unwrap();
+        let restore_result = image_manager.restore(&backup_file);
+        assert!(restore_result.is_ok(), "{}", restore_result.unwrap_err());
+    }
+
+    tokio::spawn(crate::registry::run(registry_config));
+
+    // Wait until registry starts
+    if !registry_is_reachable(&address.to_string(), 1.0).await {
+        panic!("Registry is not reachable");
+    }
+
+    {
+        let config = ImageManagerConfig::with_base_folder(tmp_folder.owned().join("store"));
+        let mut image_manager = ImageManager::new(config, ConsolePrinter::new()).unwrap();
+
+        let login_result = image_manager.login(&address.to_string(), "backup", "backup").await;
+        assert!(login_result.is_ok(), "{}", login_result.unwrap_err());
+
+        let pull_result = image_manager.pull(PullRequest::from_tag(&image_tag)).await;
+        assert!(pull_result.is_ok(), "{}", pull_result.unwrap_err());
+
+        let unpack_folder = tmp_folder.owned().join("unpack");
+        let unpack_result = image_manager.unpack(UnpackRequest {
+            reference: image_tag.clone().to_ref(),
+            unpack_folder: unpack_folder.clone(),
+            replace: false,
+            dry_run: false,
+            variables: Default::default()
+        });
+        assert!(unpack_result.is_ok(), "{}", unpack_result.unwrap_err());
+        assert_file_content_eq!(Path::new("testdata/rawdata/file1.txt"), unpack_folder.join("file1.txt"));
+    }
+}
+
 fn create_registry_config(address: SocketAddr, tmp_registry_folder: &Path) -> RegistryConfig {
     RegistryConfig {
         data_path: tmp_registry_folder.to_path_buf(),
@@ -1007,4 +1257,58 @@ async fn registry_is_reachable(registry: &str, max_wait_time: f64) -> bool {
     }
 
     false
-}
\ No newline at end of file
+}
+
+#[tokio::test]
+async fn test_fsck_repair_from_registry() {
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+    let tmp_registry_folder = crate::test_helpers::TempFolder::new();
+
+    let address: SocketAddr = generate_registry_address().parse().unwrap();
+
+    let image_tag = ImageTag::with_registry(&address.to_string(), "test", "latest");
+
+    // Build image inside registry
+    {
+        let config = ImageManagerConfig::with_base_folder(tmp_registry_folder.owned());
+        let mut image_manager = ImageManager::new(config, ConsolePrinter::new()).unwrap();
+
+        super::test_helpers::build_image(
+            &mut image_manager,
+            Path::new("testdata/definitions/simple4.labarfile"),
+            image_tag.clone()
+        ).unwrap();
+    }
+
+    tokio::spawn(crate::registry::run(create_registry_config(address, &tmp_registry_folder)));
+
+    // Wait until registry starts
+    if !registry_is_reachable(&address.to_string(), 1.0).await {
+        panic!("Registry is not reachable");
+    }
+
+    {
+        let config = ImageManagerConfig::with_base_folder(tmp_folder.owned());
+        let mut image_manager = ImageManager::new(config, ConsolePrinter::new()).unwrap();
+
+        let login_result = image_manager.login(&address.to_string(), "guest", "guest").await;
+        assert!(login_result.is_ok(), "{}", login_result.unwrap_err());
+
+        let pull_result = image_manager.pull(PullRequest::from_tag(&image_tag)).await;
+        assert!(pull_result.is_ok(), "{}", pull_result.unwrap_err());
+
+        let reference = image_tag.clone().to_ref();
+        let file1 = image_manager.get_file(&reference, "file1.txt").unwrap().unwrap();
+        std::fs::write(&file1.path, "corrupt").unwrap();
+        let file2 = image_manager.get_file(&reference, "file2.txt").unwrap().unwrap();
+        std::fs::remove_file(&file2.path).unwrap();
+
+        let issues = image_manager.fsck().unwrap();
+        assert_eq!(2, issues.len());
+
+        let remaining_issues = image_manager.repair_fsck_issues(issues, Some(&address.to_string())).await.unwrap();
+        assert_eq!(0, remaining_issues.len());
+        assert_eq!(0, image_manager.fsck().unwrap().len());
+        assert_eq!(None, image_manager.check(&reference).unwrap());
+    }
+}
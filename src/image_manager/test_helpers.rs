@@ -21,7 +21,12 @@ pub fn build_image(image_manager: &mut ImageManager,
         tag: image_tag,
         force: false,
         verbose_output: false,
-        print: true
+        print: true,
+        build_time: None,
+        labels: Vec::new(),
+        additional_tags: Vec::new(),
+        tag_latest: None,
+        stage_tags: Vec::new()
     }).map_err(|err| err.to_string())
 }
 
@@ -42,7 +47,12 @@ pub fn build_image2(session: &mut StateSession,
             tag: image_tag,
             force,
             verbose_output: false,
-            print: true
+            print: true,
+            build_time: None,
+            labels: Vec::new(),
+            additional_tags: Vec::new(),
+            tag_latest: None,
+            stage_tags: Vec::new()
         }
     ).map_err(|err| err.to_string())
 }
\ No newline at end of file
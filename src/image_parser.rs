@@ -28,6 +28,13 @@ pub enum ImageParseError {
     IO(std::io::Error),
     StripPrefix(StripPrefixError),
     ExpectedKeyValue(String),
+    ReservedLabel(String),
+    OutsideStage,
+    StageNotFound(String),
+    DuplicateStage(String),
+    CyclicStages(String),
+    FinalStageReferenced { stage: String, final_stage: String },
+    EmptyStage(String),
     Other(String),
 }
 
@@ -61,16 +68,27 @@ impl Display for ImageParseError {
             ImageParseError::IsAbsolutePath(path) => write!(f, "The path '{}' is absolute", path),
             ImageParseError::StripPrefix(error) => write!(f, "Failed to strip prefix due to: {}", error),
             ImageParseError::ExpectedKeyValue(argument) => write!(f, "Expected key=value but got: {}", argument),
+            ImageParseError::ReservedLabel(key) => write!(f, "The label '{}' uses the reserved '{}' namespace", key, RESERVED_LABEL_PREFIX),
+            ImageParseError::OutsideStage => write!(f, "All operations must be within a STAGE in a multi-stage definition"),
+            ImageParseError::StageNotFound(name) => write!(f, "The stage '{}' is not defined", name),
+            ImageParseError::DuplicateStage(name) => write!(f, "The stage '{}' is defined more than once", name),
+            ImageParseError::CyclicStages(name) => write!(f, "The stage '{}' depends on itself", name),
+            ImageParseError::FinalStageReferenced { stage, final_stage } => write!(f, "The stage '{}' refers to the final stage '{}', which is built after all other stages", stage, final_stage),
+            ImageParseError::EmptyStage(name) => write!(f, "The stage '{}' is empty", name),
             ImageParseError::IO(error) => write!(f, "IO error: {}", error),
             ImageParseError::Other(error) => write!(f, "{}", error),
         }
     }
 }
 
+/// Labels in this namespace are reserved for labels created by labar itself.
+pub const RESERVED_LABEL_PREFIX: &str = "labar.";
+
 pub struct ImageParser<'a> {
     context: &'a ImageParserContext,
 
     image_definition: ImageDefinition,
+    stages: Vec<ImageDefinition>,
 
     variable_regex: Vec<Regex>,
     label_regex: Regex,
@@ -86,6 +104,7 @@ impl<'a> ImageParser<'a> {
             context,
 
             image_definition: ImageDefinition::new(None, Vec::new()),
+            stages: Vec::new(),
 
             variable_regex: vec![
                 Regex::new("\\$([A-Za-z0-9_]+)").unwrap(),
@@ -105,7 +124,21 @@ impl<'a> ImageParser<'a> {
         }
         self.finish()?;
 
-        Ok(self.image_definition)
+        // The last stage is the image being built
+        let mut image_definition = self.image_definition;
+        image_definition.stages = self.stages;
+
+        if image_definition.name.is_some() {
+            for stage in std::iter::once(&image_definition).chain(image_definition.stages.iter()) {
+                if stage.base_image.is_none() && stage.layers.is_empty() {
+                    return Err(ImageParseError::EmptyStage(stage.name.clone().unwrap_or_default()));
+                }
+            }
+        }
+
+        image_definition.stage_build_order(&image_definition.stages.iter().filter_map(|stage| stage.name.as_deref()).collect::<Vec<_>>())?;
+
+        Ok(image_definition)
     }
 
     fn parse_line(&mut self, line: &str) -> ImageParseResult<()> {
@@ -120,6 +153,12 @@ impl<'a> ImageParser<'a> {
             let command = parts[0].as_str();
             let num_arguments = parts.len() - 1;
 
+            if command == "STAGE" {
+                self.parse_stage(&parts, num_arguments)?;
+                self.is_first_line = true;
+                return Ok(());
+            }
+
             match command {
                 "FROM" => {
                     self.parse_from(&mut parts, num_arguments)?;
@@ -142,6 +181,12 @@ impl<'a> ImageParser<'a> {
                 "LABEL" => {
                     self.parse_label(line, &mut parts)?;
                 }
+                "UNPACK" => {
+                    self.parse_unpack(line, &parts, num_arguments)?;
+                }
+                "TEMPLATE" => {
+                    self.parse_template(line, &mut parts, num_arguments)?;
+                }
                 _ => {
                     return Err(ImageParseError::UndefinedCommand(command.to_owned()));
                 }
@@ -176,6 +221,10 @@ impl<'a> ImageParser<'a> {
             for capture in self.label_regex.captures_iter(argument) {
                 let key = capture.get(1).unwrap().as_str();
                 let value = capture.get(2).unwrap().as_str();
+                if key.starts_with(RESERVED_LABEL_PREFIX) {
+                    return Err(ImageParseError::ReservedLabel(key.to_owned()));
+                }
+
                 key_values.insert(key.to_owned(), value.to_owned());
             }
         }
@@ -194,6 +243,33 @@ impl<'a> ImageParser<'a> {
         }
     }
 
+    fn parse_stage(&mut self, parts: &[String], num_arguments: usize) -> ImageParseResult<()> {
+        if num_arguments != 1 {
+            return Err(ImageParseError::ExpectedArguments { expected: 1, actual: num_arguments });
+        }
+
+        if self.within_layer {
+            return Err(ImageParseError::SubLayerNotEnded);
+        }
+
+        let name = parts[1].to_owned();
+        if self.image_definition.name.as_ref() == Some(&name) || self.stages.iter().any(|stage| stage.name.as_ref() == Some(&name)) {
+            return Err(ImageParseError::DuplicateStage(name));
+        }
+
+        let mut stage = ImageDefinition::new(None, Vec::new());
+        stage.name = Some(name);
+
+        let previous_stage = std::mem::replace(&mut self.image_definition, stage);
+        if previous_stage.name.is_some() {
+            self.stages.push(previous_stage);
+        } else if previous_stage.base_image.is_some() || !previous_stage.layers.is_empty() {
+            return Err(ImageParseError::OutsideStage);
+        }
+
+        Ok(())
+    }
+
     fn parse_begin_layer(&mut self, line: &str, parts: &mut Vec<String>, num_arguments: usize) -> ImageParseResult<()> {
         if num_arguments != 1 {
             return Err(ImageParseError::ExpectedSubcommand)
@@ -239,6 +315,48 @@ impl<'a> ImageParser<'a> {
         Ok(())
     }
 
+    fn parse_unpack(&mut self, line: &str, parts: &[String], num_arguments: usize) -> ImageParseResult<()> {
+        if num_arguments != 2 {
+            return Err(ImageParseError::ExpectedArguments { expected: 2, actual: num_arguments });
+        }
+
+        self.add_operation(
+            line,
+            LayerOperationDefinition::Archive {
+                path: parts[2].to_owned(),
+                source_path: parts[1].to_owned()
+            }
+        );
+
+        Ok(())
+    }
+
+    fn parse_template(&mut self, line: &str, parts: &mut Vec<String>, num_arguments: usize) -> ImageParseResult<()> {
+        if num_arguments < 2 {
+            return Err(ImageParseError::ExpectedArguments { expected: 2, actual: num_arguments });
+        }
+
+        let arguments = extract_arguments(parts);
+        if parts.len() < 3 {
+            return Err(ImageParseError::ExpectedArguments { expected: 2, actual: parts.len() - 1 });
+        }
+
+        let writable = matches!(arguments.get("writable").map(|x| x.as_str()), Some("yes" | "true"));
+
+        self.add_operation(
+            line,
+            LayerOperationDefinition::File {
+                path: parts[2].to_owned(),
+                source_path: parts[1].to_owned(),
+                link_type: LinkType::Hard,
+                writable,
+                template: true
+            }
+        );
+
+        Ok(())
+    }
+
     fn parse_copy(&mut self, line: &str, mut parts: &mut Vec<String>, num_arguments: usize) -> ImageParseResult<()> {
         if num_arguments < 2 {
             return Err(ImageParseError::ExpectedArguments { expected: 2, actual: num_arguments });
@@ -260,15 +378,28 @@ impl<'a> ImageParser<'a> {
             _ => false
         };
 
-        self.add_operation(
-            line,
-            LayerOperationDefinition::File {
-                path: destination,
-                source_path: source,
-                link_type,
-                writable
+        let operation = match arguments.get("from") {
+            Some(reference) => {
+                LayerOperationDefinition::ImageFile {
+                    reference: Reference::from_str(reference).map_err(ImageParseError::InvalidImageReference)?,
+                    path: destination,
+                    source_path: source,
+                    link_type,
+                    writable
+                }
             }
-        );
+            None => {
+                LayerOperationDefinition::File {
+                    path: destination,
+                    source_path: source,
+                    link_type,
+                    writable,
+                    template: false
+                }
+            }
+        };
+
+        self.add_operation(line, operation);
 
         Ok(())
     }
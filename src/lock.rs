@@ -1,159 +1,157 @@
-use std::path::PathBuf;
-use std::io::{Write, Read};
+use std::fmt::{Display, Formatter};
+use std::fs::{File, TryLockError};
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 
-use sysinfo::{Pid, ProcessStatus, System};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use sysinfo::{Pid, ProcessesToUpdate, System};
 
-pub struct FileLock {
-    path: PathBuf,
-    has_lock: bool,
-    system: System
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    Shared,
+    Exclusive
 }
 
-impl FileLock {
-    pub fn new(path: PathBuf) -> FileLock {
-        let mut file_lock = FileLock {
-            path,
-            has_lock: false,
-            system: System::new_all()
-        };
-
-        file_lock.lock();
-        file_lock
-    }
-
-    pub async fn new_async(path: PathBuf) -> FileLock {
-        let mut file_lock = FileLock {
-            path,
-            has_lock: false,
-            system: System::new_all()
-        };
+#[derive(Debug)]
+pub enum LockError {
+    Timeout { path: PathBuf, pid: Option<u32> },
+    IO(std::io::Error)
+}
 
-        file_lock.lock_async().await;
-        file_lock
+impl From<std::io::Error> for LockError {
+    fn from(error: std::io::Error) -> Self {
+        LockError::IO(error)
     }
+}
 
-    fn lock(&mut self) {
-        let mut first_time = true;
-        loop {
-            if self.path.exists() {
-                if first_time {
-                    println!("Waiting for lock...");
-                    first_time = false;
-                }
-
-                if let Ok(mut lock_file) = std::fs::File::open(&self.path) {
-                    // We can remove the lock if the process holding the lock is dead
-                    let mut content = String::new();
-                    if let Ok(_) = lock_file.read_to_string(&mut content) {
-                        if let Ok(pid) = u32::from_str(&content) {
-                            if self.is_dead(pid) {
-                                #[allow(unused_must_use)] {
-                                    std::fs::remove_file(&self.path);
-                                }
-                            }
-                        }
-                    }
-                }
-
-                std::thread::sleep(std::time::Duration::from_millis(50));
-            } else {
-                #[allow(unused_must_use)] {
-                    std::fs::create_dir_all(self.path.parent().unwrap());
-                }
-
-                let lock_file = std::fs::OpenOptions::new()
-                    .write(true)
-                    .create_new(true)
-                    .open(&self.path);
-
-                if let Ok(mut lock_file) = lock_file {
-                    // We use the PID to indicate if the process handling the lock is alive
-                    let result = lock_file.write_all(std::process::id().to_string().as_bytes());
-
-                    if result.is_ok() {
-                        self.has_lock = true;
-                        break;
-                    } else {
-                        // Remove the lock file as we failed to write our PID.
-                        #[allow(unused_must_use)] {
-                            std::fs::remove_file(&self.path);
-                        }
-                    }
-                }
+impl Display for LockError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LockError::Timeout { path, pid: Some(pid) } => {
+                write!(f, "Timed out waiting for the lock {} held by process {}", path.display(), pid)
+            }
+            LockError::Timeout { path, pid: None } => {
+                write!(f, "Timed out waiting for the lock {}", path.display())
+            }
+            LockError::IO(err) => {
+                write!(f, "Failed to lock: {}", err)
             }
         }
     }
+}
+
+/// A lock backed by an OS file lock, which is released by the OS if the holding process dies.
+/// Exclusive holders write their PID to the lock file so that waiting processes can tell who holds the lock.
+pub struct FileLock {
+    file: File,
+    mode: LockMode
+}
 
-    async fn lock_async(&mut self) {
+impl FileLock {
+    pub fn new(path: PathBuf, mode: LockMode, timeout: Option<Duration>) -> Result<FileLock, LockError> {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        let start_time = Instant::now();
         let mut first_time = true;
         loop {
-            if self.path.exists() {
-                if first_time {
-                    println!("Waiting for lock...");
-                    first_time = false;
+            let result = match mode {
+                LockMode::Shared => file.try_lock_shared(),
+                LockMode::Exclusive => file.try_lock()
+            };
+
+            match result {
+                Ok(()) => {
+                    break;
                 }
-
-                if let Ok(mut lock_file) = tokio::fs::File::open(&self.path).await {
-                    // We can remove the lock if the process holding the lock is dead
-                    let mut content = String::new();
-                    if let Ok(_) = lock_file.read_to_string(&mut content).await {
-                        if let Ok(pid) = u32::from_str(&content) {
-                            if self.is_dead(pid) {
-                                #[allow(unused_must_use)] {
-                                    tokio::fs::remove_file(&self.path).await;
-                                }
-                            }
+                Err(TryLockError::WouldBlock) => {
+                    let pid = FileLock::holder_pid(&path);
+                    if first_time {
+                        match pid {
+                            Some(pid) => println!("Waiting for lock held by process {}...", pid),
+                            None => println!("Waiting for lock...")
                         }
-                    }
-                }
 
-                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
-            } else {
-                #[allow(unused_must_use)] {
-                    tokio::fs::create_dir_all(self.path.parent().unwrap()).await;
-                }
+                        first_time = false;
+                    }
 
-                let lock_file = tokio::fs::OpenOptions::new()
-                    .write(true)
-                    .create_new(true)
-                    .open(&self.path)
-                    .await;
-
-                if let Ok(mut lock_file) = lock_file {
-                    // We use the PID to indicate if the process handling the lock is alive
-                    let result = lock_file.write_all(std::process::id().to_string().as_bytes()).await;
-
-                    if result.is_ok() {
-                        self.has_lock = true;
-                        break;
-                    } else {
-                        // Remove the lock file as we failed to write our PID.
-                        #[allow(unused_must_use)] {
-                            tokio::fs::remove_file(&self.path).await;
+                    if let Some(timeout) = timeout {
+                        if start_time.elapsed() >= timeout {
+                            return Err(LockError::Timeout { path, pid });
                         }
                     }
+
+                    std::thread::sleep(Duration::from_millis(50));
+                }
+                Err(TryLockError::Error(err)) => {
+                    return Err(err.into());
                 }
             }
         }
-    }
 
-    fn is_dead(&self, pid: u32) -> bool {
-         if let Some(process) = self.system.process(Pid::from_u32(pid)) {
-            process.status() == ProcessStatus::Run
-        } else {
-            true
+        if mode == LockMode::Exclusive {
+            file.set_len(0)?;
+            (&file).write_all(std::process::id().to_string().as_bytes())?;
         }
+
+        Ok(FileLock { file, mode })
+    }
+
+    fn holder_pid(path: &Path) -> Option<u32> {
+        let content = std::fs::read_to_string(path).ok()?;
+        let pid = u32::from_str(content.trim()).ok()?;
+
+        // The PID is only cleared on a clean release, so it might belong to a process that has died
+        let mut system = System::new();
+        system.refresh_processes(ProcessesToUpdate::Some(&[Pid::from_u32(pid)]), true);
+        system.process(Pid::from_u32(pid)).map(|_| pid)
     }
 }
 
 impl Drop for FileLock {
     fn drop(&mut self) {
-        if self.has_lock {
+        if self.mode == LockMode::Exclusive {
             #[allow(unused_must_use)] {
-                std::fs::remove_file(&self.path);
+                self.file.set_len(0);
             }
         }
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_shared_locks() {
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+    std::fs::create_dir_all(tmp_folder.owned()).unwrap();
+    let path = tmp_folder.owned().join("lock");
+
+    let _lock1 = FileLock::new(path.clone(), LockMode::Shared, Some(Duration::from_millis(100))).unwrap();
+    let _lock2 = FileLock::new(path.clone(), LockMode::Shared, Some(Duration::from_millis(100))).unwrap();
+
+    let result = FileLock::new(path.clone(), LockMode::Exclusive, Some(Duration::from_millis(100)));
+    assert!(matches!(result, Err(LockError::Timeout { pid: None, .. })));
+}
+
+#[test]
+fn test_exclusive_lock() {
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+    std::fs::create_dir_all(tmp_folder.owned()).unwrap();
+    let path = tmp_folder.owned().join("lock");
+
+    {
+        let _lock = FileLock::new(path.clone(), LockMode::Exclusive, None).unwrap();
+
+        let result = FileLock::new(path.clone(), LockMode::Shared, Some(Duration::from_millis(100)));
+        match result {
+            Err(LockError::Timeout { pid, .. }) => assert_eq!(Some(std::process::id()), pid),
+            _ => panic!("Expected timeout")
+        }
+    }
+
+    let _lock = FileLock::new(path.clone(), LockMode::Shared, Some(Duration::from_millis(100))).unwrap();
+    assert_eq!("", std::fs::read_to_string(&path).unwrap());
+}
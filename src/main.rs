@@ -1,5 +1,7 @@
+use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::time::{Duration, Instant};
 use chrono::{DateTime, Local};
 use flate2::read::GzDecoder;
@@ -17,26 +19,63 @@ pub mod image_manager;
 pub mod registry;
 pub mod reference;
 pub mod content;
+pub mod archive;
+pub mod watch;
 
 #[cfg(test)]
 pub mod test_helpers;
 
-use crate::helpers::{edit_key_value, TablePrinter};
+use crate::helpers::{edit_key_value, DataSize, TablePrinter};
 use crate::image::ImageMetadata;
-use crate::image_definition::{ImageDefinition};
-use crate::lock::FileLock;
-use crate::image_manager::{PrinterRef, BuildRequest, ConsolePrinter, ImageManager, ImageManagerConfig, ImageManagerError, ImageManagerResult, RegistryError, UnpackRequest, PullRequest, UnpackFile, ListContentEntry};
-use crate::image_parser::ImageParserContext;
+use crate::image_definition::{ImageDefinition, LayerGrouping};
+use crate::lock::{FileLock, LockMode};
+use crate::image_manager::{PrinterRef, BuildEvent, EmptyPrinter, JsonLinesPrinter, BuildRequest, provenance_labels, reproducible_build_time, ConsolePrinter, ImageManager, ImageManagerConfig, ImageManagerError, ImageManagerResult, RegistryError, UnpackRequest, PullRequest, UnpackFile, ListContentEntry, FileQuery, SquashRequest, RebaseRequest, RetentionPolicy};
+use crate::image_parser::{ImageParserContext, RESERVED_LABEL_PREFIX};
 use crate::reference::{ImageTag, Reference};
 use crate::registry::auth::{AccessRight, AddUserResult, Password, SqliteAuthProvider};
 use crate::registry::config::{RegistryConfig};
 
 #[derive(Debug, StructOpt)]
 #[structopt(name="labar", about="Layer Based Archive")]
+struct MainInput {
+    #[structopt(long, global=true, help="The folder of the store to use, overriding LABAR_HOME and the config file")]
+    store: Option<PathBuf>,
+    #[structopt(long, global=true, help="The store profile in the config file to use")]
+    profile: Option<String>,
+    #[structopt(long, global=true, help="The maximum number of seconds to wait for the store lock")]
+    lock_timeout: Option<f64>,
+    #[structopt(subcommand)]
+    command: CommandLineInput
+}
+
+#[derive(Debug, StructOpt)]
 enum CommandLineInput {
     #[structopt(about="Shows storage use on the system")]
     SystemUsage {
 
+    },
+    #[structopt(about="Migrates the state database to the version used by this version of labar")]
+    SystemMigrate {
+
+    },
+    #[structopt(about="Backs up the state database, optionally including the layer files")]
+    SystemBackup {
+        #[structopt(name="file", help="The backup file to create")]
+        file: PathBuf,
+        #[structopt(long, help="Includes the layer files in the backup")]
+        include_layers: bool
+    },
+    #[structopt(about="Restores the state database and the layer files from a backup")]
+    SystemRestore {
+        #[structopt(name="file", help="The backup file to restore")]
+        file: PathBuf
+    },
+    #[structopt(about="Checks the consistency between the state database and the stored layers")]
+    SystemFsck {
+        #[structopt(long, help="Repairs the found issues")]
+        repair: bool,
+        #[structopt(long, help="The registry to re-download missing or corrupt layers from. If not specified, the default registry is used")]
+        registry: Option<String>
     },
     #[structopt(about="Builds an image")]
     Build {
@@ -48,21 +87,60 @@ enum CommandLineInput {
         context: Option<PathBuf>,
         #[structopt(long, help="The build arguments on format key=value")]
         arguments: Vec<String>,
-        #[structopt(long, help="Forces a build, ignoring previously cached layers")]
+        #[structopt(long, help="Forces a build, ignoring previously cached layers and replacing pinned tags")]
         force: bool,
         #[structopt(long, short, help="Prints more verbose output when building the image")]
         verbose_output: bool,
+        #[structopt(long, help="Uses fixed creation times (SOURCE_DATE_EPOCH or the Unix epoch) to make the build reproducible")]
+        reproducible: bool,
+        #[structopt(long, help="Rebuilds the image into a temporary store and verifies that the result is identical")]
+        verify_reproducible: bool,
+        #[structopt(long="label", help="Adds a label on format key=value to the image")]
+        labels: Vec<String>,
+        #[structopt(long, help="Records the inputs of the build as labels in the reserved labar.* namespace")]
+        provenance: bool,
+        #[structopt(long, help="Watches the labarfile and the copied files, rebuilding the image when they change")]
+        watch: bool,
+        #[structopt(long, help="Unpacks the image to the given directory after each build in watch mode")]
+        unpack_to: Option<PathBuf>,
+        #[structopt(long, default_value="text", help="The output format: text, json (the build result) or json-lines (streaming build events)")]
+        output: OutputFormat,
+        #[structopt(long="tag", help="Additional tags of the image")]
+        additional_tags: Vec<ImageTag>,
+        #[structopt(long, help="Does not also tag the image as latest")]
+        no_latest: bool,
+        #[structopt(long, help="The stage to build as the image, defaults to the last stage")]
+        target: Option<String>,
+        #[structopt(long="tag-stage", help="Tags another stage on format stage=tag")]
+        stage_tags: Vec<String>,
     },
     #[structopt(about="Builds an image from a directory, automatically creating the operations")]
     BuildFromDirectory {
         #[structopt(name="directory", help="The directory to build from")]
         directory: PathBuf,
+        #[structopt(long, default_value="top-level", help="How files are grouped into layers: top-level, single, depth=N, size=SIZE (e.g. 64MB) or manifest=FILE")]
+        grouping: LayerGrouping,
+        #[structopt(name="tag", help="The tag of the image")]
+        tag: ImageTag,
+        #[structopt(long, help="Forces a build, ignoring previously cached layers")]
+        force: bool,
+        #[structopt(long, short, help="Prints more verbose output when building the image")]
+        verbose_output: bool,
+        #[structopt(long, help="Uses fixed creation times (SOURCE_DATE_EPOCH or the Unix epoch) to make the build reproducible")]
+        reproducible: bool,
+    },
+    #[structopt(about="Builds an image from a zip or tar archive, automatically creating the operations")]
+    BuildFromArchive {
+        #[structopt(name="file", help="The archive to build from (.zip, .tar, .tar.gz or .tgz)")]
+        file: PathBuf,
         #[structopt(name="tag", help="The tag of the image")]
         tag: ImageTag,
         #[structopt(long, help="Forces a build, ignoring previously cached layers")]
         force: bool,
         #[structopt(long, short, help="Prints more verbose output when building the image")]
         verbose_output: bool,
+        #[structopt(long, help="Uses fixed creation times (SOURCE_DATE_EPOCH or the Unix epoch) to make the build reproducible")]
+        reproducible: bool,
     },
     #[structopt(about="Merges two images into a new one", alias="merge")]
     MergeImage {
@@ -73,10 +151,42 @@ enum CommandLineInput {
         #[structopt(name="tag", help="The tag of the image")]
         tag: ImageTag
     },
+    #[structopt(about="Squashes an image into a single layer")]
+    Squash {
+        #[structopt(name="reference", help="The reference of the image to squash")]
+        reference: Reference,
+        #[structopt(name="tag", help="The tag of the squashed image")]
+        tag: ImageTag,
+        #[structopt(long, help="Only squash the given number of top layers")]
+        layers: Option<usize>
+    },
+    #[structopt(about="Rebases an image onto a new base image")]
+    Rebase {
+        #[structopt(name="reference", help="The reference of the image to rebase")]
+        reference: Reference,
+        #[structopt(long, help="The current base image")]
+        from: Reference,
+        #[structopt(long, help="The new base image")]
+        onto: Reference,
+        #[structopt(name="tag", help="The tag of the rebased image")]
+        tag: ImageTag
+    },
     #[structopt(about="Removes an image")]
     RemoveImage {
         #[structopt(name="tags", help="The tag(s) of the image(s) to remove")]
-        tags: Vec<ImageTag>
+        tags: Vec<ImageTag>,
+        #[structopt(long, help="Removes the image even if pinned")]
+        force: bool
+    },
+    #[structopt(about="Pins an image, which prevents it from being removed")]
+    Pin {
+        #[structopt(name="tag", help="The image to pin")]
+        tag: ImageTag
+    },
+    #[structopt(about="Unpins an image")]
+    Unpin {
+        #[structopt(name="tag", help="The image to unpin")]
+        tag: ImageTag
     },
     #[structopt(about="Tags an image")]
     #[structopt(name="tag")]
@@ -84,14 +194,18 @@ enum CommandLineInput {
         #[structopt(name="reference", help="The source image")]
         reference: Reference,
         #[structopt(name="tag", help="The new tag for the image")]
-        tag: ImageTag
+        tag: ImageTag,
+        #[structopt(long, help="Replaces the tag even if pinned")]
+        force: bool
     },
     #[structopt(about="Lists the available images")]
     ListImages {
         #[structopt(long, help="Only show images matching the given regex (for image tag)")]
         filter: Option<Regex>,
         #[structopt(long, short, help="Only show image IDs")]
-        quiet: bool
+        quiet: bool,
+        #[structopt(long, help="Show short image IDs, which can be used as references")]
+        short_ids: bool
     },
     #[structopt(about="Lists the content of an image")]
     ListContent {
@@ -100,6 +214,13 @@ enum CommandLineInput {
         #[structopt(long, short, help="The maximum depth to show")]
         max_depth: Option<usize>
     },
+    #[structopt(about="Finds the images and layers containing the given files")]
+    Find {
+        #[structopt(name="pattern", help="The glob pattern of the path to find, e.g. models/*/weights.bin", required_unless="hash")]
+        pattern: Option<String>,
+        #[structopt(long, help="Finds the files with the given content hash instead", conflicts_with="pattern")]
+        hash: Option<String>
+    },
     #[structopt(about="Checks the integrity of an image")]
     CheckImage {
         #[structopt(name="reference", help="The reference to list for")]
@@ -156,7 +277,9 @@ enum CommandLineInput {
         #[structopt(long, help="Replaces the existing unpacking")]
         replace: bool,
         #[structopt(long, help="Simulates what an unpacking would do")]
-        dry_run: bool
+        dry_run: bool,
+        #[structopt(long="variable", help="A variable on format key=value used when rendering templates")]
+        variables: Vec<String>
     },
     #[structopt(about="Unpacks from a definition file")]
     UnpackFile {
@@ -172,6 +295,17 @@ enum CommandLineInput {
         #[structopt(long, help="Force removes an unpacking, not guaranteeing that all files are removed, but entry removed")]
         force: bool
     },
+    #[structopt(about="Commits the changes of an unpacking as a new image")]
+    Commit {
+        #[structopt(name="path", help="The path of the unpacking")]
+        path: String,
+        #[structopt(name="tag", help="The tag of the new image")]
+        tag: ImageTag,
+        #[structopt(long, short, help="Prints more verbose output when building the image")]
+        verbose_output: bool,
+        #[structopt(long, help="Uses a fixed creation time (SOURCE_DATE_EPOCH or the Unix epoch) to make the commit reproducible")]
+        reproducible: bool,
+    },
     #[structopt(about="Extracts an image to an archive file")]
     Extract {
         #[structopt(name="reference", help="The image to extract")]
@@ -194,7 +328,21 @@ enum CommandLineInput {
     #[structopt(about="Removes layers not used")]
     Purge {
         #[structopt(long, name="clean_old_images", help="Removes unused images that are older than X number of days")]
-        clean_old_images: Option<u64>
+        clean_old_images: Option<u64>,
+        #[structopt(long, help="Removes images that have not been used in X number of days")]
+        clean_unused_images: Option<u64>,
+        #[structopt(long, help="Never removes the N most recent tags of each repository")]
+        keep_recent: Option<usize>,
+        #[structopt(long, help="Never removes images with tags matching the regex")]
+        keep_tags: Option<Regex>,
+        #[structopt(long, help="Never removes images with the label, given as KEY or KEY=VALUE")]
+        protect_label: Option<String>,
+        #[structopt(long, help="Removes the least recently used images until the store fits in the given size, e.g. 10GB")]
+        max_store_size: Option<DataSize>,
+        #[structopt(long, help="Also removes pinned images")]
+        force: bool,
+        #[structopt(long, help="Only shows what would be removed")]
+        dry_run: bool
     },
     #[structopt(about="Login into a remote registry")]
     Login {
@@ -215,6 +363,8 @@ enum CommandLineInput {
         retry: Option<usize>,
         #[structopt(long, short, help="Prints more verbose output when downloading the image")]
         verbose_output: bool,
+        #[structopt(long, help="Replaces the local tag even if pinned")]
+        force: bool
     },
     #[structopt(about="Pushes a local image to a remote registry")]
     Push {
@@ -255,8 +405,40 @@ enum RegistryCommandLineInput {
         #[structopt(name="config_file", help="The toml configuration file of the registry")]
         config_file: PathBuf,
         #[structopt(name="tag", help="The tag of the image to remove")]
+        tag: ImageTag,
+        #[structopt(long, help="Removes the image even if pinned")]
+        force: bool
+    },
+    #[structopt(about="Pins an image in the registry, which prevents it from being overwritten or removed")]
+    Pin {
+        #[structopt(name="config_file", help="The toml configuration file of the registry")]
+        config_file: PathBuf,
+        #[structopt(name="tag", help="The tag of the image to pin")]
+        tag: ImageTag
+    },
+    #[structopt(about="Unpins an image in the registry")]
+    Unpin {
+        #[structopt(name="config_file", help="The toml configuration file of the registry")]
+        config_file: PathBuf,
+        #[structopt(name="tag", help="The tag of the image to unpin")]
         tag: ImageTag
     },
+    #[structopt(about="Backs up the state of the registry, optionally including the layer files")]
+    Backup {
+        #[structopt(name="config_file", help="The toml configuration file of the registry")]
+        config_file: PathBuf,
+        #[structopt(name="file", help="The backup file to create")]
+        file: PathBuf,
+        #[structopt(long, help="Includes the layer files in the backup")]
+        include_layers: bool
+    },
+    #[structopt(about="Restores the state and the layer files of the registry from a backup")]
+    Restore {
+        #[structopt(name="config_file", help="The toml configuration file of the registry")]
+        config_file: PathBuf,
+        #[structopt(name="file", help="The backup file to restore")]
+        file: PathBuf
+    },
     #[structopt(about="Adds a new user to the registry")]
     AddUser {
         #[structopt(name="config_file", help="The toml configuration file of the registry")]
@@ -279,11 +461,32 @@ enum RegistryCommandLineInput {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    Text,
+    Json,
+    JsonLines
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            "json-lines" => Ok(OutputFormat::JsonLines),
+            _ => Err(format!("Invalid output format '{}', expected text, json or json-lines", text))
+        }
+    }
+}
+
 async fn main_run(file_config: FileConfig, command_line_input: CommandLineInput) -> Result<(), String> {
     let printer = ConsolePrinter::new();
 
     match command_line_input {
         CommandLineInput::SystemUsage { } => {
+            let _read_lock = create_read_lock(&file_config)?;
             let image_manager = create_image_manager(&file_config, printer.clone());
             let system_usage = image_manager.system_usage().map_err(|err| format!("{}", err))?;
 
@@ -293,69 +496,315 @@ async fn main_run(file_config: FileConfig, command_line_input: CommandLineInput)
             println!("State storage size: {}", system_usage.state_storage_size);
             println!("File storage size: {}", system_usage.file_storage_size);
         }
-        CommandLineInput::Build { file, tag, context, arguments, force, verbose_output } => {
-            let _write_lock = create_write_lock(&file_config);
+        CommandLineInput::SystemFsck { repair, registry } => {
+            let _lock = if repair { create_write_lock(&file_config)? } else { create_read_lock(&file_config)? };
             let mut image_manager = create_image_manager(&file_config, printer.clone());
 
+            let issues = image_manager.fsck().map_err(|err| format!("{}", err))?;
+            for issue in &issues {
+                println!("{}", issue);
+            }
+
+            if issues.is_empty() {
+                println!("No issues found.");
+                return Ok(());
+            }
+
+            if !repair {
+                return Err(format!("Found {} issues, use --repair to repair them.", issues.len()));
+            }
+
+            let registry = registry.as_deref().or(file_config.default_registry());
+            let remaining_issues = transform_registry_result(image_manager.repair_fsck_issues(issues, registry).await)?;
+            if !remaining_issues.is_empty() {
+                for issue in &remaining_issues {
+                    println!("Not repaired: {}", issue);
+                }
+
+                return Err(format!("Failed to repair {} issues.", remaining_issues.len()));
+            }
+
+            println!("All issues repaired.");
+        }
+        CommandLineInput::SystemBackup { file, include_layers } => {
+            // The state is snapshotted consistently without a lock, but the layer files must not change during the backup
+            let _read_lock = if include_layers { Some(create_read_lock(&file_config)?) } else { None };
+            let image_manager = create_image_manager(&file_config, printer.clone());
+            image_manager.backup(&file, include_layers).map_err(|err| format!("{}", err))?;
+        }
+        CommandLineInput::SystemRestore { file } => {
+            let _write_lock = create_write_lock(&file_config)?;
+            let _unpack_lock = create_unpack_lock(&file_config)?;
+            let mut image_manager = create_image_manager(&file_config, printer.clone());
+            image_manager.restore(&file).map_err(|err| format!("{}", err))?;
+        }
+        CommandLineInput::SystemMigrate { } => {
+            let _write_lock = create_write_lock(&file_config)?;
+            let migration = ImageManager::migrate_state(&file_config.image_manager).map_err(|err| format!("{}", err))?;
+            if migration.from_version == migration.to_version {
+                println!("The state database is already at version {}.", migration.to_version);
+            } else {
+                println!("Migrated the state database from version {} to {}.", migration.from_version, migration.to_version);
+            }
+        }
+        CommandLineInput::Build { file, tag, context, arguments, force, verbose_output, reproducible, verify_reproducible, labels, provenance, watch, unpack_to, output, additional_tags, no_latest, target, stage_tags } => {
             let mut image_parser_context = ImageParserContext::new();
+            let mut build_arguments = Vec::new();
             for argument in arguments {
                 let parts = argument.split("=").collect::<Vec<_>>();
                 if parts.len() == 2 {
                     image_parser_context.add_variable(parts[0], parts[1]);
+                    build_arguments.push((parts[0].to_owned(), parts[1].to_owned()));
+                }
+            }
+
+            let mut labels = labels.iter().map(|label| parse_label(label)).collect::<Result<Vec<_>, _>>()?;
+            let stage_tags = stage_tags.iter().map(|stage_tag| parse_stage_tag(stage_tag)).collect::<Result<Vec<_>, _>>()?;
+            let build_context = context.unwrap_or_else(|| std::env::current_dir().unwrap());
+            if provenance {
+                labels.extend(
+                    provenance_labels(Path::new(&file), &build_context, &build_arguments).map_err(|err| format!("{}", err))?
+                );
+            }
+
+            let build_printer: PrinterRef = match output {
+                OutputFormat::Text => printer.clone(),
+                OutputFormat::Json => EmptyPrinter::new(),
+                OutputFormat::JsonLines => JsonLinesPrinter::new()
+            };
+
+            let build = || -> Result<ImageTag, String> {
+                let _write_lock = create_write_lock(&file_config)?;
+                let mut image_manager = create_image_manager(&file_config, build_printer.clone());
+
+                if output == OutputFormat::Text {
+                    println!("Building image {}...", tag);
+                }
+                let start_time = Instant::now();
+                let mut image_definition = ImageDefinition::parse_file(
+                    Path::new(&file),
+                    &image_parser_context
+                ).map_err(|err| format!("Failed parsing build definition: {}", err))?;
+                if let Some(target) = target.as_ref() {
+                    image_definition = image_definition.with_target(target).map_err(|err| format!("{}", err))?;
+                }
+
+                let request = BuildRequest {
+                    build_context: build_context.clone(),
+                    image_definition,
+                    tag: tag.clone(),
+                    force,
+                    verbose_output,
+                    print: true,
+                    build_time: reproducible_build_time(reproducible || verify_reproducible).map_err(|err| format!("{}", err))?,
+                    labels: labels.clone(),
+                    additional_tags: additional_tags.clone(),
+                    tag_latest: if no_latest { Some(false) } else { None },
+                    stage_tags: stage_tags.clone()
+                };
+
+                let build_result = image_manager.build_image(request.clone()).map_err(|err| format!("{}", err))?;
+                let image = build_result.image.clone();
+                let image_size = image_manager.image_size(&Reference::ImageTag(image.tag.clone())).map_err(|err| format!("{}", err))?;
+                if output == OutputFormat::Text {
+                    println!("Built image {} ({}) of size {:.2} in {:.2} seconds.", image.tag, image.hash, image_size, start_time.elapsed().as_secs_f64());
+                    for additional_tag in &additional_tags {
+                        println!("Tagged image as {}.", additional_tag);
+                    }
+                    for (stage, stage_tag) in &stage_tags {
+                        println!("Tagged stage {} as {}.", stage, stage_tag);
+                    }
+                }
+
+                let mut mismatched_layers = None;
+                if verify_reproducible {
+                    if output == OutputFormat::Text {
+                        println!("Verifying that the build is reproducible...");
+                    }
+
+                    mismatched_layers = Some(image_manager.verify_reproducible_build(request).map_err(|err| format!("{}", err))?);
+                }
+
+                match output {
+                    OutputFormat::Text => {}
+                    OutputFormat::Json => {
+                        let mut json_result = serde_json::to_value(&build_result).map_err(|err| format!("{}", err))?;
+                        json_result["size"] = serde_json::json!(image_size);
+                        json_result["duration_seconds"] = serde_json::json!(start_time.elapsed().as_secs_f64());
+                        if let Some(mismatched_layers) = mismatched_layers.as_ref() {
+                            json_result["mismatched_layers"] = serde_json::json!(mismatched_layers);
+                        }
+
+                        println!("{}", serde_json::to_string_pretty(&json_result).unwrap());
+                    }
+                    OutputFormat::JsonLines => {
+                        if let Some(mismatched_layers) = mismatched_layers.as_ref() {
+                            println!("{}", serde_json::json!({ "event": "reproducibility_verified", "mismatched_layers": mismatched_layers }));
+                        }
+                    }
+                }
+
+                if let Some(mismatched_layers) = mismatched_layers {
+                    if !mismatched_layers.is_empty() {
+                        if output == OutputFormat::Text {
+                            for hash in mismatched_layers {
+                                println!("\t* Layer {} is not reproducible.", hash);
+                            }
+                        }
+
+                        return Err("The build is not reproducible.".to_owned());
+                    }
+
+                    if output == OutputFormat::Text {
+                        println!("The build is reproducible.");
+                    }
                 }
+
+                Ok(image.tag)
+            };
+
+            // Errors are also reported as events, so that the output only consists of JSON lines. Returns the error if it still needs to be printed.
+            let report_error = |err: String| {
+                if output == OutputFormat::JsonLines {
+                    build_printer.event(&BuildEvent::BuildFailed { message: err });
+                    None
+                } else {
+                    Some(err)
+                }
+            };
+
+            if !watch {
+                if let Err(err) = build() {
+                    match report_error(err) {
+                        Some(err) => return Err(err),
+                        None => std::process::exit(1)
+                    }
+                }
+
+                return Ok(());
             }
 
+            let get_watched_paths = || {
+                ImageDefinition::parse_file(Path::new(&file), &image_parser_context)
+                    .map(|image_definition| watch::watched_paths(Path::new(&file), &image_definition, &build_context))
+                    .unwrap_or_else(|_| vec![PathBuf::from(&file)])
+            };
+
+            let mut file_watcher = watch::FileWatcher::new(get_watched_paths());
+            loop {
+                match build() {
+                    Ok(image_tag) => {
+                        if let Some(unpack_to) = unpack_to.as_ref() {
+                            let unpack = || -> Result<(), String> {
+                                let _read_lock = create_read_lock(&file_config)?;
+                                let _unpack_lock = create_unpack_lock(&file_config)?;
+                                let mut image_manager = create_image_manager(&file_config, printer.clone());
+
+                                image_manager.unpack(UnpackRequest {
+                                    reference: image_tag.to_ref(),
+                                    unpack_folder: unpack_to.clone(),
+                                    replace: true,
+                                    dry_run: false,
+                                    variables: HashMap::new()
+                                }).map_err(|err| format!("{}", err))
+                            };
+
+                            if let Err(err) = unpack() {
+                                println!("Failed to unpack due to: {}", err);
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        if let Some(err) = report_error(err) {
+                            println!("{}", err);
+                        }
+                    }
+                }
+
+                if output == OutputFormat::Text {
+                    println!("Watching for changes...");
+                }
+                file_watcher.wait_for_change(Duration::from_millis(250), Duration::from_millis(500)).await;
+                file_watcher.set_paths(get_watched_paths());
+            }
+        }
+        CommandLineInput::BuildFromDirectory { directory, grouping, tag, force, verbose_output, reproducible } => {
+            let _write_lock = create_write_lock(&file_config)?;
+            let mut image_manager = create_image_manager(&file_config, printer.clone());
+
             println!("Building image {}...", tag);
             let start_time = Instant::now();
-            let image_definition = ImageDefinition::parse_file(
-                Path::new(&file),
-                &image_parser_context
-            ).map_err(|err| format!("Failed parsing build definition: {}", err))?;
-
-            let request = BuildRequest {
-                build_context: context.unwrap_or_else(|| std::env::current_dir().unwrap()),
-                image_definition,
+
+            let image = image_manager.build_image_from_directory(
+                &directory,
+                &grouping,
                 tag,
                 force,
                 verbose_output,
-                print: true
-            };
-
-            let image = image_manager.build_image(request).map_err(|err| format!("{}", err))?.image;
+                reproducible_build_time(reproducible).map_err(|err| format!("{}", err))?
+            ).map_err(|err| format!("{}", err))?.image;
             let image_size = image_manager.image_size(&Reference::ImageTag(image.tag.clone())).map_err(|err| format!("{}", err))?;
             println!("Built image {} ({}) of size {:.2} in {:.2} seconds.", image.tag, image.hash, image_size, start_time.elapsed().as_secs_f64());
         }
-        CommandLineInput::BuildFromDirectory { directory, tag, force, verbose_output } => {
-            let _write_lock = create_write_lock(&file_config);
+        CommandLineInput::BuildFromArchive { file, tag, force, verbose_output, reproducible } => {
+            let _write_lock = create_write_lock(&file_config)?;
             let mut image_manager = create_image_manager(&file_config, printer.clone());
 
             println!("Building image {}...", tag);
             let start_time = Instant::now();
 
-            let image = image_manager.build_image_from_directory(
-                &directory,
+            let image = image_manager.build_image_from_archive(
+                &file,
                 tag,
                 force,
-                verbose_output
+                verbose_output,
+                reproducible_build_time(reproducible).map_err(|err| format!("{}", err))?
             ).map_err(|err| format!("{}", err))?.image;
             let image_size = image_manager.image_size(&Reference::ImageTag(image.tag.clone())).map_err(|err| format!("{}", err))?;
             println!("Built image {} ({}) of size {:.2} in {:.2} seconds.", image.tag, image.hash, image_size, start_time.elapsed().as_secs_f64());
         }
         CommandLineInput::MergeImage { first, second, tag } => {
-            let _write_lock = create_write_lock(&file_config);
+            let _write_lock = create_write_lock(&file_config)?;
             let mut image_manager = create_image_manager(&file_config, printer.clone());
 
             let image = image_manager.merge_image(&first, &second, tag).map_err(|err| format!("{}", err))?.image;
             println!("Merged {}, {} into {} ({}).", first, second, image.tag, image.hash);
         }
-        CommandLineInput::RemoveImage { tags } => {
-            let _write_lock = create_write_lock(&file_config);
-            let _unpack_lock = create_unpack_lock(&file_config);
+        CommandLineInput::Squash { reference, tag, layers } => {
+            let _write_lock = create_write_lock(&file_config)?;
+            let mut image_manager = create_image_manager(&file_config, printer.clone());
+
+            let result = image_manager.squash_image(
+                SquashRequest {
+                    reference: reference.clone(),
+                    tag,
+                    layers
+                }
+            ).map_err(|err| format!("{}", err))?;
+            println!("Squashed {} layers of {} into {} ({}).", result.squashed_layers, reference, result.image.tag, result.image.hash);
+        }
+        CommandLineInput::Rebase { reference, from, onto, tag } => {
+            let _write_lock = create_write_lock(&file_config)?;
+            let mut image_manager = create_image_manager(&file_config, printer.clone());
+
+            let result = image_manager.rebase_image(
+                RebaseRequest {
+                    reference: reference.clone(),
+                    from,
+                    onto: onto.clone(),
+                    tag
+                }
+            ).map_err(|err| format!("{}", err))?;
+            println!("Rebased {} onto {} as {} ({}).", reference, onto, result.image.tag, result.image.hash);
+        }
+        CommandLineInput::RemoveImage { tags, force } => {
+            let _write_lock = create_write_lock(&file_config)?;
+            let _unpack_lock = create_unpack_lock(&file_config)?;
             let mut image_manager = create_image_manager(&file_config, printer.clone());
 
             let mut failed = false;
             for tag in tags {
-                if let Err(err) = image_manager.remove_image(&tag) {
+                if let Err(err) = image_manager.remove_image(&tag, force) {
                     println!("{}", err);
                     failed = true;
                 }
@@ -365,26 +814,46 @@ async fn main_run(file_config: FileConfig, command_line_input: CommandLineInput)
                 return Err(String::new());
             }
         }
-        CommandLineInput::TagImage { reference, tag } => {
-            let _write_lock = create_write_lock(&file_config);
+        CommandLineInput::Pin { tag } => {
+            let _write_lock = create_write_lock(&file_config)?;
+            let mut image_manager = create_image_manager(&file_config, printer.clone());
+
+            image_manager.pin_image(&tag).map_err(|err| format!("{}", err))?;
+            println!("Pinned {}.", tag);
+        }
+        CommandLineInput::Unpin { tag } => {
+            let _write_lock = create_write_lock(&file_config)?;
             let mut image_manager = create_image_manager(&file_config, printer.clone());
 
-            let image = image_manager.tag_image(&reference, &tag).map_err(|err| format!("{}", err))?;
+            image_manager.unpin_image(&tag).map_err(|err| format!("{}", err))?;
+            println!("Unpinned {}.", tag);
+        }
+        CommandLineInput::TagImage { reference, tag, force } => {
+            let _write_lock = create_write_lock(&file_config)?;
+            let mut image_manager = create_image_manager(&file_config, printer.clone());
+
+            let image = image_manager.tag_image(&reference, &tag, force).map_err(|err| format!("{}", err))?;
             println!("Tagged {} ({}) as {}", reference, image.hash, image.tag);
         },
-        CommandLineInput::ListImages { filter, quiet } => {
+        CommandLineInput::ListImages { filter, quiet, short_ids } => {
+            let _read_lock = create_read_lock(&file_config)?;
             let image_manager = create_image_manager(&file_config, printer.clone());
 
             let images = image_manager.list_images(filter.as_ref()).map_err(|err| format!("{}", err))?;
             if !quiet {
-                print_images(&images);
+                print_images(&images, short_ids);
             } else {
                 for image in images {
-                    println!("{}", image.image.hash);
+                    if short_ids {
+                        println!("{}", image.image.hash.short());
+                    } else {
+                        println!("{}", image.image.hash);
+                    }
                 }
             }
         }
         CommandLineInput::ListContent { reference, max_depth } => {
+            let _read_lock = create_read_lock(&file_config)?;
             let image_manager = create_image_manager(&file_config, printer.clone());
 
             let content = image_manager.list_content(&reference, max_depth).map_err(|err| format!("{}", err))?;
@@ -399,7 +868,42 @@ async fn main_run(file_config: FileConfig, command_line_input: CommandLineInput)
                 }
             }
         }
+        CommandLineInput::Find { pattern, hash } => {
+            let _read_lock = create_read_lock(&file_config)?;
+            let image_manager = create_image_manager(&file_config, printer.clone());
+
+            let query = match (pattern, hash) {
+                (_, Some(hash)) => FileQuery::ContentHash(hash),
+                (Some(pattern), None) => FileQuery::Path(pattern),
+                (None, None) => return Err("Expected a path pattern or a content hash".to_owned())
+            };
+
+            let found_files = image_manager.find_files(&query).map_err(|err| format!("{}", err))?;
+
+            let mut table_printer = TablePrinter::new(
+                vec![
+                    "PATH".to_owned(),
+                    "LAYER".to_owned(),
+                    "CONTENT HASH".to_owned(),
+                    "SIZE".to_owned(),
+                    "IMAGES".to_owned()
+                ]
+            );
+
+            for found_file in found_files {
+                table_printer.add_row(vec![
+                    found_file.file.path,
+                    found_file.file.layer.to_string(),
+                    found_file.file.content_hash,
+                    found_file.file.size.to_string(),
+                    found_file.images.iter().map(|tag| tag.to_string()).collect::<Vec<_>>().join(", ")
+                ]);
+            }
+
+            table_printer.print();
+        }
         CommandLineInput::CheckImage { reference } => {
+            let _read_lock = create_read_lock(&file_config)?;
             let image_manager = create_image_manager(&file_config, printer.clone());
 
             if let Some(failed) = image_manager.check(&reference).map_err(|err| format!("{}", err))? {
@@ -407,6 +911,7 @@ async fn main_run(file_config: FileConfig, command_line_input: CommandLineInput)
             }
         }
         CommandLineInput::PrintContent { reference, file } => {
+            let _read_lock = create_read_lock(&file_config)?;
             let image_manager = create_image_manager(&file_config, printer.clone());
 
             match image_manager.get_file(&reference, &file).map_err(|err| format!("{}", err))? {
@@ -424,6 +929,7 @@ async fn main_run(file_config: FileConfig, command_line_input: CommandLineInput)
             }
         }
         CommandLineInput::CopyFile { reference, file, destination } => {
+            let _read_lock = create_read_lock(&file_config)?;
             let image_manager = create_image_manager(&file_config, printer.clone());
 
             match image_manager.get_file(&reference, &file).map_err(|err| format!("{}", err))? {
@@ -442,13 +948,15 @@ async fn main_run(file_config: FileConfig, command_line_input: CommandLineInput)
             }
         }
         CommandLineInput::Inspect { reference } => {
+            let _read_lock = create_read_lock(&file_config)?;
             let image_manager = create_image_manager(&file_config, printer.clone());
 
             let inspect_result = image_manager.inspect(&reference).map_err(|err| format!("{}", err))?;
 
             println!("Image id: {}", inspect_result.top_layer.hash);
             println!("Tags: {}", inspect_result.image_tags.iter().map(|tag| tag.to_string()).collect::<Vec<_>>().join(", "));
-            println!("Created: {}", inspect_result.top_layer.created.format(DATE_FORMAT));
+            println!("Pinned: {}", inspect_result.pinned_tags.iter().map(|tag| tag.to_string()).collect::<Vec<_>>().join(", "));
+            println!("Created: {}", inspect_result.top_layer.created.with_timezone(&Local).format(DATE_FORMAT));
             println!("Size: {}", inspect_result.size);
             println!("Labels: {}", inspect_result.labels.iter().map(|(key, value)| format!("{}={}", key, value)).collect::<Vec<_>>().join(", "));
             println!();
@@ -465,7 +973,7 @@ async fn main_run(file_config: FileConfig, command_line_input: CommandLineInput)
             for layer in inspect_result.layers {
                 table_printer.add_row(vec![
                     layer.hash.to_string(),
-                    layer.created.format(DATE_FORMAT).to_string(),
+                    layer.created.with_timezone(&Local).format(DATE_FORMAT).to_string(),
                     layer.size.to_string(),
                 ]);
             }
@@ -473,6 +981,7 @@ async fn main_run(file_config: FileConfig, command_line_input: CommandLineInput)
             table_printer.print();
         }
         CommandLineInput::GetLabel { reference, key } => {
+            let _read_lock = create_read_lock(&file_config)?;
             let image_manager = create_image_manager(&file_config, printer.clone());
 
             let labels = image_manager.get_labels(&reference).map_err(|err| format!("{}", err))?;
@@ -484,6 +993,7 @@ async fn main_run(file_config: FileConfig, command_line_input: CommandLineInput)
             }
         }
         CommandLineInput::DiffImage { reference, comparison } => {
+            let _read_lock = create_read_lock(&file_config)?;
             let image_manager = create_image_manager(&file_config, printer.clone());
             let result = image_manager.diff(&reference, &comparison).map_err(|err| format!("{}", err))?;
 
@@ -508,6 +1018,7 @@ async fn main_run(file_config: FileConfig, command_line_input: CommandLineInput)
             }
         }
         CommandLineInput::ListUnpackings { filter, quiet } => {
+            let _read_lock = create_read_lock(&file_config)?;
             let image_manager = create_image_manager(&file_config, printer.clone());
 
             let unpackings = image_manager.list_unpackings(filter.as_ref()).map_err(|err| format!("{}", err))?;
@@ -546,28 +1057,57 @@ async fn main_run(file_config: FileConfig, command_line_input: CommandLineInput)
                 }
             }
         }
-        CommandLineInput::Unpack { reference, destination, replace, dry_run } => {
-            let _unpack_lock = create_unpack_lock(&file_config);
+        CommandLineInput::Unpack { reference, destination, replace, dry_run, variables } => {
+            let _read_lock = create_read_lock(&file_config)?;
+            let _unpack_lock = create_unpack_lock(&file_config)?;
             let mut image_manager = create_image_manager(&file_config, printer.clone());
 
+            let variables = variables
+                .iter()
+                .map(|variable| {
+                    variable.split_once("=")
+                        .map(|(key, value)| (key.to_owned(), value.to_owned()))
+                        .ok_or_else(|| format!("Expected key=value but got: {}", variable))
+                })
+                .collect::<Result<HashMap<_, _>, _>>()?;
+
             let request = UnpackRequest {
                 reference,
                 unpack_folder: Path::new(&destination).to_path_buf(),
                 replace,
                 dry_run,
+                variables
             };
 
             image_manager.unpack(request).map_err(|err| format!("{}", err))?;
         },
         CommandLineInput::UnpackFile { file, dry_run } => {
-            let _unpack_lock = create_unpack_lock(&file_config);
+            let _read_lock = create_read_lock(&file_config)?;
+            let _unpack_lock = create_unpack_lock(&file_config)?;
             let mut image_manager = create_image_manager(&file_config, printer.clone());
 
             let unpack_file = UnpackFile::parse_file(Path::new(&file), dry_run).map_err(|err| format!("Failed parsing unpack definition: {}", err))?;
             image_manager.unpack_file(unpack_file).map_err(|err| format!("{}", err))?;
         },
+        CommandLineInput::Commit { path, tag, verbose_output, reproducible } => {
+            let _write_lock = create_write_lock(&file_config)?;
+            let _unpack_lock = create_unpack_lock(&file_config)?;
+            let mut image_manager = create_image_manager(&file_config, printer.clone());
+
+            let build_time = reproducible_build_time(reproducible).map_err(|err| format!("{}", err))?;
+            let result = image_manager.commit(Path::new(&path), tag, verbose_output, build_time).map_err(|err| format!("{}", err))?;
+            println!(
+                "Committed {} ({} added, {} changed) as {} ({}).",
+                path,
+                result.diff.added_files.len(),
+                result.diff.changed_files.len(),
+                result.image.tag,
+                result.image.hash
+            );
+        }
         CommandLineInput::RemoveUnpacking { paths, force } => {
-            let _unpack_lock = create_unpack_lock(&file_config);
+            let _read_lock = create_read_lock(&file_config)?;
+            let _unpack_lock = create_unpack_lock(&file_config)?;
             let mut image_manager = create_image_manager(&file_config, printer.clone());
 
             let mut failed = false;
@@ -583,29 +1123,43 @@ async fn main_run(file_config: FileConfig, command_line_input: CommandLineInput)
             }
         }
         CommandLineInput::Extract { reference, archive } => {
+            let _read_lock = create_read_lock(&file_config)?;
             let image_manager = create_image_manager(&file_config, printer.clone());
             image_manager.extract(&reference, Path::new(&archive)).map_err(|err| format!("{}", err))?;
         }
         CommandLineInput::ExportImage { tag, path } => {
-            let _write_lock = create_write_lock(&file_config);
+            let _write_lock = create_write_lock(&file_config)?;
             let image_manager = create_image_manager(&file_config, printer.clone());
             image_manager.export_image(&tag, Path::new(&path)).map_err(|err| format!("{}", err))?;
         }
         CommandLineInput::ImportImage { path } => {
-            let _write_lock = create_write_lock(&file_config);
+            let _write_lock = create_write_lock(&file_config)?;
             let image_manager = create_image_manager(&file_config, printer.clone());
             image_manager.import_image(Path::new(&path)).map_err(|err| format!("{}", err))?;
         }
-        CommandLineInput::Purge { clean_old_images } => {
-            let _write_lock = create_write_lock(&file_config);
+        CommandLineInput::Purge { clean_old_images, clean_unused_images, keep_recent, keep_tags, protect_label, max_store_size, force, dry_run } => {
+            let _write_lock = create_write_lock(&file_config)?;
             let mut image_manager = create_image_manager(&file_config, printer.clone());
 
-            if let Some(clean_old_images) = clean_old_images {
-                let duration = Duration::from_secs(clean_old_images * 24 * 60 * 60);
-                image_manager.clean_old_images(duration).map_err(|err| format!("{}", err))?;
-            }
+            let policy = RetentionPolicy {
+                max_age: clean_old_images.map(|clean_old_images| Duration::from_secs(clean_old_images * 24 * 60 * 60)),
+                max_unused: clean_unused_images.map(|clean_unused_images| Duration::from_secs(clean_unused_images * 24 * 60 * 60)),
+                keep_recent,
+                keep_tags,
+                protect_label,
+                max_store_size,
+                include_pinned: force
+            };
 
-            image_manager.garbage_collect().map_err(|err| format!("{}", err))?;
+            let plan = image_manager.purge(&policy, dry_run).map_err(|err| format!("{}", err))?;
+            if dry_run {
+                println!(
+                    "Would remove {} images and {} layers, reclaiming {}.",
+                    plan.images.len(),
+                    plan.layers.len(),
+                    plan.reclaimed_size
+                );
+            }
         },
         CommandLineInput::Login { registry, username, password } => {
             let mut image_manager = create_image_manager(&file_config, printer.clone());
@@ -613,12 +1167,12 @@ async fn main_run(file_config: FileConfig, command_line_input: CommandLineInput)
             println!("Logged into registry {} as {}.", registry, username);
         }
         CommandLineInput::Push { tag } => {
-            let _write_lock = create_write_lock(&file_config);
+            let _write_lock = create_write_lock(&file_config)?;
             let image_manager = create_image_manager(&file_config, printer.clone());
             transform_registry_result(image_manager.push(&tag, file_config.default_registry()).await)?;
         },
-        CommandLineInput::Pull { tag, new_tag, retry, verbose_output } => {
-            let _write_lock = create_write_lock(&file_config);
+        CommandLineInput::Pull { tag, new_tag, retry, verbose_output, force } => {
+            let _write_lock = create_write_lock(&file_config)?;
             let mut image_manager = create_image_manager(&file_config, printer.clone());
             transform_registry_result(image_manager.pull(
                 PullRequest {
@@ -626,7 +1180,8 @@ async fn main_run(file_config: FileConfig, command_line_input: CommandLineInput)
                     default_registry: file_config.default_registry(),
                     new_tag,
                     retry,
-                    verbose_output
+                    verbose_output,
+                    force
                 }
             ).await)?;
         },
@@ -634,7 +1189,7 @@ async fn main_run(file_config: FileConfig, command_line_input: CommandLineInput)
             let image_manager = create_image_manager(&file_config, printer.clone());
 
             let images = transform_registry_result(image_manager.list_images_in_registry(&registry).await)?;
-            print_images(&images);
+            print_images(&images, false);
         }
         CommandLineInput::RemoveImageRegistry { tag } => {
             let image_manager = create_image_manager(&file_config, printer.clone());
@@ -647,11 +1202,40 @@ async fn main_run(file_config: FileConfig, command_line_input: CommandLineInput)
                     let registry_config = RegistryConfig::load_from_file(&config_file)?;
                     registry::run(registry_config).await.map_err(|err| format!("{}", err))?;
                 }
-                RegistryCommandLineInput::RemoveImage { config_file, tag } => {
+                RegistryCommandLineInput::RemoveImage { config_file, tag, force } => {
+                    let registry_config = RegistryConfig::load_from_file(&config_file)?;
+
+                    let mut image_manager = ImageManager::new(registry_config.image_manager_config(), printer.clone()).unwrap();
+                    image_manager.remove_image(&tag, force).map_err(|err| format!("{}", err))?;
+                }
+                RegistryCommandLineInput::Pin { config_file, tag } => {
+                    let registry_config = RegistryConfig::load_from_file(&config_file)?;
+
+                    let mut image_manager = ImageManager::new(registry_config.image_manager_config(), printer.clone()).unwrap();
+                    image_manager.pin_image(&tag).map_err(|err| format!("{}", err))?;
+                    println!("Pinned {}.", tag);
+                }
+                RegistryCommandLineInput::Unpin { config_file, tag } => {
+                    let registry_config = RegistryConfig::load_from_file(&config_file)?;
+
+                    let mut image_manager = ImageManager::new(registry_config.image_manager_config(), printer.clone()).unwrap();
+                    image_manager.unpin_image(&tag).map_err(|err| format!("{}", err))?;
+                    println!("Unpinned {}.", tag);
+                }
+                RegistryCommandLineInput::Backup { config_file, file, include_layers } => {
+                    let registry_config = RegistryConfig::load_from_file(&config_file)?;
+                    if include_layers && registry_config.has_external_storage() {
+                        return Err("The layer files of the registry are in external storage and must be backed up separately.".to_owned());
+                    }
+
+                    let image_manager = ImageManager::new(registry_config.image_manager_config(), printer.clone()).unwrap();
+                    image_manager.backup(&file, include_layers).map_err(|err| format!("{}", err))?;
+                }
+                RegistryCommandLineInput::Restore { config_file, file } => {
                     let registry_config = RegistryConfig::load_from_file(&config_file)?;
 
                     let mut image_manager = ImageManager::new(registry_config.image_manager_config(), printer.clone()).unwrap();
-                    image_manager.remove_image(&tag).map_err(|err| format!("{}", err))?;
+                    image_manager.restore(&file).map_err(|err| format!("{}", err))?;
                 }
                 RegistryCommandLineInput::AddUser { config_file, username, password, access_rights, update } => {
                     let registry_config = RegistryConfig::load_from_file(&config_file)?;
@@ -685,11 +1269,12 @@ async fn main_run(file_config: FileConfig, command_line_input: CommandLineInput)
         CommandLineInput::Config { edit } => {
             fn print_config(file_config: &FileConfig) {
                 println!("default_registry: {}", file_config.default_registry.as_ref().map(|x| x.as_str()).unwrap_or("N/A"));
+                println!("base_folder: {}", file_config.image_manager.base_folder().display());
                 println!("accept_self_signed: {}", file_config.image_manager.accept_self_signed);
             }
 
             if let Some(edit) = edit {
-                let mut new_file_config = file_config.clone();
+                let mut new_file_config = FileConfig::from_default().unwrap_or_default();
                 let (key, value) = edit_key_value(&edit)?;
                 let value_str = value.unwrap_or("");
                 match key {
@@ -724,10 +1309,18 @@ async fn main() -> Result<(), String> {
         return Ok(());
     }
 
-    let file_config = FileConfig::from_default().unwrap_or(FileConfig::default());
-    let command_line_input = CommandLineInput::from_args();
+    let main_input = MainInput::from_args();
+    let mut file_config = FileConfig::from_default().unwrap_or(FileConfig::default());
+    if main_input.lock_timeout.is_some() {
+        file_config.lock_timeout = main_input.lock_timeout;
+    }
+
+    let result = match file_config.select_store(main_input.store, main_input.profile.as_deref()) {
+        Ok(file_config) => main_run(file_config, main_input.command).await,
+        Err(err) => Err(err)
+    };
 
-    if let Err(err) = main_run(file_config, command_line_input).await {
+    if let Err(err) = result {
         if !err.is_empty() {
             println!("{}", err);
         }
@@ -739,20 +1332,56 @@ async fn main() -> Result<(), String> {
 }
 
 fn create_image_manager(file_config: &FileConfig, printer: PrinterRef) -> ImageManager {
-    ImageManager::new(
-        file_config.image_manager.clone(),
-        printer.clone()
-    ).unwrap_or_else(|_|
-        ImageManager::new(ImageManagerConfig::new(), printer.clone()).unwrap()
-    )
+    match ImageManager::new(file_config.image_manager.clone(), printer.clone()) {
+        Ok(image_manager) => image_manager,
+        Err(err) => {
+            println!("Failed to open the store at {}: {}", file_config.image_manager.base_folder().display(), err);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn parse_label(label: &str) -> Result<(String, String), String> {
+    let (key, value) = label.split_once("=").ok_or_else(|| format!("Expected key=value but got: {}", label))?;
+    if key.starts_with(RESERVED_LABEL_PREFIX) {
+        return Err(format!("The label '{}' uses the reserved '{}' namespace", key, RESERVED_LABEL_PREFIX));
+    }
+
+    Ok((key.to_owned(), value.to_owned()))
+}
+
+fn parse_stage_tag(stage_tag: &str) -> Result<(String, ImageTag), String> {
+    let (stage, tag) = stage_tag.split_once("=").ok_or_else(|| format!("Expected stage=tag but got: {}", stage_tag))?;
+    Ok((stage.to_owned(), ImageTag::from_str(tag)?))
+}
+
+fn create_write_lock(file_config: &FileConfig) -> Result<FileLock, String> {
+    create_lock(file_config, "write_lock", LockMode::Exclusive)
+}
+
+fn create_read_lock(file_config: &FileConfig) -> Result<FileLock, String> {
+    create_lock(file_config, "write_lock", LockMode::Shared)
 }
 
-fn create_write_lock(_file_config: &FileConfig) -> FileLock {
-    FileLock::new(ImageManagerConfig::new().base_folder().join("write_lock"))
+fn create_unpack_lock(file_config: &FileConfig) -> Result<FileLock, String> {
+    create_lock(file_config, "unpack_lock", LockMode::Exclusive)
 }
 
-fn create_unpack_lock(_file_config: &FileConfig) -> FileLock {
-    FileLock::new(ImageManagerConfig::new().base_folder().join("unpack_lock"))
+fn create_lock(file_config: &FileConfig, name: &str, mode: LockMode) -> Result<FileLock, String> {
+    let base_folder = file_config.image_manager.base_folder();
+    if !base_folder.exists() {
+        std::fs::create_dir_all(base_folder).map_err(|err| format!("{}", err))?;
+    }
+
+    let lock_timeout = file_config.lock_timeout
+        .map(|lock_timeout| Duration::try_from_secs_f64(lock_timeout).map_err(|err| format!("Invalid lock timeout {}: {}", lock_timeout, err)))
+        .transpose()?;
+
+    FileLock::new(
+        base_folder.join(name),
+        mode,
+        lock_timeout
+    ).map_err(|err| format!("{}", err))
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -760,7 +1389,19 @@ fn create_unpack_lock(_file_config: &FileConfig) -> FileLock {
 pub struct FileConfig {
     default_registry: Option<String>,
     #[serde(default)]
-    image_manager: ImageManagerConfig
+    image_manager: ImageManagerConfig,
+    #[serde(default)]
+    lock_timeout: Option<f64>,
+    #[serde(default, skip_serializing_if="BTreeMap::is_empty")]
+    profiles: BTreeMap<String, StoreProfile>
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StoreProfile {
+    base_folder: PathBuf,
+    #[serde(default)]
+    default_registry: Option<String>
 }
 
 impl FileConfig {
@@ -775,7 +1416,27 @@ impl FileConfig {
     }
 
     pub fn default_path() -> PathBuf {
-        dirs::home_dir().unwrap().join(".labar").join("config.toml")
+        ImageManagerConfig::default_base_folder().join("config.toml")
+    }
+
+    /// Selects the store to use, where an explicit store folder takes precedence over a profile.
+    pub fn select_store(mut self, store: Option<PathBuf>, profile: Option<&str>) -> Result<FileConfig, String> {
+        if let Some(profile) = profile {
+            let store_profile = self.profiles.get(profile)
+                .ok_or_else(|| format!("The profile '{}' is not defined in the config file", profile))?
+                .clone();
+
+            self.image_manager.set_base_folder(store_profile.base_folder);
+            if store_profile.default_registry.is_some() {
+                self.default_registry = store_profile.default_registry;
+            }
+        }
+
+        if let Some(store) = store {
+            self.image_manager.set_base_folder(store);
+        }
+
+        Ok(self)
     }
 
     pub fn from_file(path: &Path) -> Result<FileConfig, String> {
@@ -794,7 +1455,9 @@ impl Default for FileConfig {
     fn default() -> Self {
         FileConfig {
             default_registry: None,
-            image_manager: ImageManagerConfig::new()
+            image_manager: ImageManagerConfig::new(),
+            lock_timeout: None,
+            profiles: BTreeMap::new()
         }
     }
 }
@@ -820,7 +1483,7 @@ fn generate_completions() -> bool {
     if std::env::args().skip(1).next() == Some("generate-completions".to_owned()) {
         let output_dir = "completions";
         std::fs::create_dir_all(output_dir).unwrap();
-        CommandLineInput::clap().gen_completions("labar", Shell::Bash, output_dir);
+        MainInput::clap().gen_completions("labar", Shell::Bash, output_dir);
         true
     } else {
         false
@@ -829,14 +1492,16 @@ fn generate_completions() -> bool {
 
 const DATE_FORMAT: &str = "%Y-%m-%d %T";
 
-fn print_images(images: &Vec<ImageMetadata>) {
+fn print_images(images: &Vec<ImageMetadata>, short_ids: bool) {
     let mut table_printer = TablePrinter::new(
         vec![
             "REPOSITORY".to_owned(),
             "TAG".to_owned(),
             "IMAGE ID".to_owned(),
             "CREATED".to_owned(),
-            "SIZE".to_owned()
+            "LAST USED".to_owned(),
+            "SIZE".to_owned(),
+            "PINNED".to_owned()
         ]
     );
 
@@ -846,11 +1511,42 @@ fn print_images(images: &Vec<ImageMetadata>) {
         table_printer.add_row(vec![
             metadata.image.tag.full_repository(),
             metadata.image.tag.tag().to_owned(),
-            metadata.image.hash.to_string(),
+            if short_ids { metadata.image.hash.short().to_owned() } else { metadata.image.hash.to_string() },
             created.format(DATE_FORMAT).to_string(),
-            metadata.size.to_string()
+            metadata.last_used.map(|last_used| last_used.format(DATE_FORMAT).to_string()).unwrap_or_else(|| "-".to_owned()),
+            metadata.size.to_string(),
+            if metadata.pinned { "yes".to_owned() } else { String::new() }
         ]);
     }
 
     table_printer.print();
 }
+
+#[test]
+fn test_select_store() {
+    let file_config: FileConfig = toml::from_str(
+        r#"
+        default_registry = "localhost:3000"
+
+        [profiles.ci]
+        base_folder = "/tmp/labar-ci"
+        default_registry = "ci:3000"
+
+        [profiles.shared]
+        base_folder = "/srv/labar"
+        "#
+    ).unwrap();
+
+    let selected = file_config.clone().select_store(None, Some("ci")).unwrap();
+    assert_eq!(Path::new("/tmp/labar-ci"), selected.image_manager.base_folder());
+    assert_eq!(Some("ci:3000"), selected.default_registry());
+
+    let selected = file_config.clone().select_store(None, Some("shared")).unwrap();
+    assert_eq!(Path::new("/srv/labar"), selected.image_manager.base_folder());
+    assert_eq!(Some("localhost:3000"), selected.default_registry());
+
+    let selected = file_config.clone().select_store(Some(PathBuf::from("/tmp/store")), Some("ci")).unwrap();
+    assert_eq!(Path::new("/tmp/store"), selected.image_manager.base_folder());
+
+    assert!(file_config.select_store(None, Some("missing")).is_err());
+}
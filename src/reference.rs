@@ -1,21 +1,34 @@
 use std::fmt::{Display, Formatter};
 use std::str::FromStr;
+use std::sync::LazyLock;
 use regex::Regex;
 use rusqlite::ToSql;
 use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde::de::{Error, Visitor};
 
+/// The repository used for referring to other stages within a multi-stage definition, e.g. `stage:base`.
+/// It is reserved, so that image tags never collide with stages.
+pub const STAGE_REPOSITORY: &str = "stage";
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum Reference {
     ImageTag(ImageTag),
-    ImageId(ImageId)
+    ImageId(ImageId),
+    /// Another stage of a multi-stage definition, only valid while building.
+    Stage(String)
 }
 
 impl FromStr for Reference {
     type Err = String;
 
     fn from_str(text: &str) -> Result<Self, Self::Err> {
+        if let Some(name) = text.strip_prefix(STAGE_REPOSITORY).and_then(|name| name.strip_prefix(':')) {
+            if !name.is_empty() {
+                return Ok(Reference::Stage(name.to_owned()));
+            }
+        }
+
         if let Ok(image_id) = ImageId::from_str(text) {
             return Ok(Reference::ImageId(image_id));
         }
@@ -32,7 +45,8 @@ impl Display for Reference {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             Reference::ImageTag(tag) => write!(f, "{}", tag),
-            Reference::ImageId(id) => write!(f, "{}", id)
+            Reference::ImageId(id) => write!(f, "{}", id),
+            Reference::Stage(name) => write!(f, "{}:{}", STAGE_REPOSITORY, name)
         }
     }
 }
@@ -89,10 +103,19 @@ impl FromSql for ImageId {
     }
 }
 
+pub const MIN_IMAGE_ID_PREFIX_LENGTH: usize = 8;
+pub const SHORT_IMAGE_ID_LENGTH: usize = 12;
+
+static IMAGE_ID_PREFIX_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new("^[0-9a-f]+$").unwrap());
+
 impl ImageId {
     pub fn to_ref(self) -> Reference {
         Reference::ImageId(self)
     }
+
+    pub fn short(&self) -> &str {
+        &self.0[..SHORT_IMAGE_ID_LENGTH.min(self.0.len())]
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
@@ -139,6 +162,21 @@ impl ImageTag {
         &self.tag
     }
 
+    /// A short image id is parsed as a tag, so returns the prefix if this tag could be one.
+    pub fn image_id_prefix(&self) -> Option<&str> {
+        let is_prefix = self.registry.is_none()
+            && self.tag == "latest"
+            && self.repository.len() >= MIN_IMAGE_ID_PREFIX_LENGTH
+            && self.repository.len() < 64
+            && IMAGE_ID_PREFIX_REGEX.is_match(&self.repository);
+
+        if is_prefix {
+            Some(&self.repository)
+        } else {
+            None
+        }
+    }
+
     pub fn set_registry(mut self, registry: &str) -> Self {
         self.registry = Some(registry.to_owned());
         self
@@ -181,6 +219,10 @@ impl FromStr for ImageTag {
         let repository = capture.get(3).map(|x| x.as_str().to_string()).ok_or_else(|| "Expected image tag")?;
         let tag = capture.get(5).map(|x| x.as_str().to_string()).unwrap_or_else(|| "latest".to_owned());
 
+        if registry.is_none() && repository == STAGE_REPOSITORY {
+            return Err(format!("The repository '{}' is reserved for referring to stages", STAGE_REPOSITORY));
+        }
+
         Ok(
             ImageTag {
                 registry,
@@ -258,6 +300,14 @@ fn test_reference2() {
     );
 }
 
+#[test]
+fn test_reference_stage() {
+    assert_eq!(Some(Reference::Stage("base".to_owned())), Reference::from_str("stage:base").ok());
+    assert_eq!("stage:base", Reference::Stage("base".to_owned()).to_string());
+    assert_eq!(None, ImageTag::from_str("stage:base").ok());
+    assert_eq!(Some(ImageTag::with_registry("localhost:3000", "stage", "base")), ImageTag::from_str("localhost:3000/stage:base").ok());
+}
+
 #[test]
 fn test_image_id_parse1() {
     assert_eq!(
@@ -378,4 +428,18 @@ fn test_image_tag_deserialize2() {
     let deserialized: ImageTag = serde_json::from_str(&content).unwrap();
 
     assert_eq!(image_tag, deserialized);
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_image_id_prefix1() {
+    let reference = Reference::from_str("679447d45a6c").unwrap();
+    assert_eq!(Some("679447d45a6c"), reference.image_tag().unwrap().image_id_prefix());
+}
+
+#[test]
+fn test_image_id_prefix2() {
+    assert_eq!(None, ImageTag::from_str("679447d").unwrap().image_id_prefix());
+    assert_eq!(None, ImageTag::from_str("679447d45a6c:v1").unwrap().image_id_prefix());
+    assert_eq!(None, ImageTag::from_str("localhost:3000/679447d45a6c").unwrap().image_id_prefix());
+    assert_eq!(None, ImageTag::from_str("679447g45a6c").unwrap().image_id_prefix());
+}
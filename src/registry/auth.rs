@@ -20,7 +20,7 @@ use chrono::{TimeDelta, Utc};
 use rusqlite::OptionalExtension;
 use rusqlite::types::FromSqlError;
 
-use crate::image_manager::{SqlResult, StateManager, StateSession};
+use crate::image_manager::{ImageManagerResult, SqlResult, StateManager, StateSession};
 use crate::registry::model::{AppError, AppResult};
 use crate::registry::RegistryConfig;
 
@@ -184,7 +184,7 @@ pub struct SqliteAuthProvider {
 }
 
 impl SqliteAuthProvider {
-    pub fn new(base_folder: &Path, initial_users: UsersSpec) -> SqlResult<SqliteAuthProvider> {
+    pub fn new(base_folder: &Path, initial_users: UsersSpec) -> ImageManagerResult<SqliteAuthProvider> {
         let provider = SqliteAuthProvider {
             state_manager: StateManager::new(base_folder)?
         };
@@ -200,7 +200,7 @@ impl SqliteAuthProvider {
         Ok(provider)
     }
 
-    pub fn from_registry_config(registry_config: &RegistryConfig) -> SqlResult<SqliteAuthProvider> {
+    pub fn from_registry_config(registry_config: &RegistryConfig) -> ImageManagerResult<SqliteAuthProvider> {
         SqliteAuthProvider::new(&registry_config.data_path, registry_config.initial_users.clone())
     }
 
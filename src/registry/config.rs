@@ -48,6 +48,10 @@ impl RegistryConfig {
         config
     }
 
+    pub fn has_external_storage(&self) -> bool {
+        self.s3_storage.is_some() || self.in_memory_storage.is_some()
+    }
+
     pub fn can_pull_through_upstream(&self) -> bool {
         match self.upstream.as_ref() {
             Some(upstream) => upstream.pull_through,
@@ -180,7 +180,7 @@ async fn set_image(State(state): State<Arc<AppState>>,
     let spec: ImageSpec = helpers::decode_json(request).await?;
 
     let mut image_manager = state.pooled_image_manager(&token);
-    image_manager.tag_image(&Reference::ImageId(spec.hash.clone()), &spec.tag)?;
+    image_manager.tag_image(&Reference::ImageId(spec.hash.clone()), &spec.tag, false)?;
 
     info!("Uploaded image: {} ({})", spec.tag, spec.hash);
     Ok(())
@@ -219,7 +219,7 @@ async fn remove_image(State(state): State<Arc<AppState>>,
     let token = check_access_right(&request, &state.sign_key, AccessRight::Delete)?;
 
     let mut image_manager = state.pooled_image_manager(&token);
-    let removed_layers = image_manager.remove_image_async(&tag).await?;
+    let removed_layers = image_manager.remove_image_async(&tag, false).await?;
     let num_deleted_layers = removed_layers.len();
 
     state.clear_layer_cache().await;
@@ -43,6 +43,12 @@ impl IntoResponse for AppError {
                             Json(json!(AppErrorResponse { error: format!("{}", err) }))
                         ).into_response()
                     }
+                    err @ ImageManagerError::ImagePinned { .. } => {
+                        (
+                            StatusCode::CONFLICT,
+                            Json(json!(AppErrorResponse { error: format!("{}", err) }))
+                        ).into_response()
+                    }
                     err => {
                         (
                             StatusCode::BAD_REQUEST,
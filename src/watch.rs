@@ -0,0 +1,146 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::image_definition::{ImageDefinition, LayerOperationDefinition};
+
+type Snapshot = BTreeMap<PathBuf, Option<(SystemTime, u64)>>;
+
+/// Watches a set of files and directories for changes by polling their metadata.
+pub struct FileWatcher {
+    paths: Vec<PathBuf>,
+    snapshot: Snapshot
+}
+
+impl FileWatcher {
+    pub fn new(paths: Vec<PathBuf>) -> FileWatcher {
+        let snapshot = create_snapshot(&paths);
+
+        FileWatcher {
+            paths,
+            snapshot
+        }
+    }
+
+    pub fn set_paths(&mut self, paths: Vec<PathBuf>) {
+        self.snapshot = create_snapshot(&paths);
+        self.paths = paths;
+    }
+
+    pub fn has_changed(&mut self) -> bool {
+        let snapshot = create_snapshot(&self.paths);
+        if snapshot != self.snapshot {
+            self.snapshot = snapshot;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Waits until a change has been detected and no further changes have been made within the debounce time.
+    pub async fn wait_for_change(&mut self, poll_interval: Duration, debounce: Duration) {
+        while !self.has_changed() {
+            tokio::time::sleep(poll_interval).await;
+        }
+
+        loop {
+            tokio::time::sleep(debounce).await;
+            if !self.has_changed() {
+                break;
+            }
+        }
+    }
+}
+
+/// Returns the paths that a build depends on: the definition file and the sources of all COPY and UNPACK operations.
+pub fn watched_paths(definition_file: &Path, image_definition: &ImageDefinition, build_context: &Path) -> Vec<PathBuf> {
+    let mut paths = vec![definition_file.to_owned()];
+
+    for layer in std::iter::once(image_definition).chain(image_definition.stages.iter()).flat_map(|definition| definition.layers.iter()) {
+        for operation in &layer.operations {
+            match operation {
+                LayerOperationDefinition::File { source_path, .. } | LayerOperationDefinition::Archive { source_path, .. } => {
+                    paths.push(build_context.join(source_path));
+                }
+                LayerOperationDefinition::Image { .. } => {}
+                LayerOperationDefinition::ImageFile { .. } => {}
+                LayerOperationDefinition::ImageAlias { .. } => {}
+                LayerOperationDefinition::Directory { .. } => {}
+                LayerOperationDefinition::Label { .. } => {}
+            }
+        }
+    }
+
+    paths
+}
+
+fn create_snapshot(paths: &[PathBuf]) -> Snapshot {
+    let mut snapshot = BTreeMap::new();
+
+    let mut stack = paths.to_vec();
+    while let Some(current) = stack.pop() {
+        let metadata = match std::fs::metadata(&current) {
+            Ok(metadata) => metadata,
+            Err(_) => {
+                snapshot.insert(current, None);
+                continue;
+            }
+        };
+
+        if metadata.is_dir() {
+            if let Ok(read_dir) = std::fs::read_dir(&current) {
+                for entry in read_dir.flatten() {
+                    stack.push(entry.path());
+                }
+            }
+        }
+
+        let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+        snapshot.insert(current, Some((modified, metadata.len())));
+    }
+
+    snapshot
+}
+
+#[test]
+fn test_file_watcher() {
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+    std::fs::create_dir_all(tmp_folder.join("data")).unwrap();
+    std::fs::write(tmp_folder.join("data/file1.txt"), "Hello").unwrap();
+
+    let mut watcher = FileWatcher::new(vec![tmp_folder.join("data")]);
+    assert!(!watcher.has_changed());
+
+    std::fs::write(tmp_folder.join("data/file2.txt"), "World").unwrap();
+    assert!(watcher.has_changed());
+    assert!(!watcher.has_changed());
+
+    std::fs::write(tmp_folder.join("data/file1.txt"), "Hello, World").unwrap();
+    assert!(watcher.has_changed());
+
+    std::fs::remove_file(tmp_folder.join("data/file2.txt")).unwrap();
+    assert!(watcher.has_changed());
+}
+
+#[tokio::test]
+async fn test_file_watcher_wait_for_change() {
+    let tmp_folder = crate::test_helpers::TempFolder::new();
+    std::fs::create_dir_all(tmp_folder.join("data")).unwrap();
+    std::fs::write(tmp_folder.join("data/file1.txt"), "Hello").unwrap();
+
+    let mut watcher = FileWatcher::new(vec![tmp_folder.join("data")]);
+
+    // Waiting does not block the runtime, so the change can be made on the same thread
+    let file_path = tmp_folder.join("data/file1.txt");
+    let writer = tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        std::fs::write(file_path, "Hello, World").unwrap();
+    });
+
+    let result = tokio::time::timeout(
+        Duration::from_secs(10),
+        watcher.wait_for_change(Duration::from_millis(10), Duration::from_millis(10))
+    ).await;
+    assert!(result.is_ok());
+    writer.await.unwrap();
+}